@@ -1,28 +1,146 @@
-use crate::blockchain::Blockchain;
+use crate::blockchain::{self, Blockchain, InsertResult};
 use crate::generator::generator::TransactionGenerator;
-use crate::miner::Handle as MinerHandle;
-use crate::network::message::Message;
+use crate::metrics::{self, Gauges};
+use crate::miner::{self, Handle as MinerHandle, OperatingState};
+use crate::network::message::{InvItem, Message, PROTOCOL_VERSION};
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::types::hash::Hashable;
+use crate::shutdown;
+use crate::types::block::{Block, Header as BlockHeader};
+use crate::types::hash::{Hashable, H256};
 use crate::types::mempool::{self, Mempool};
-use crate::types::state::State;
-use serde::Serialize;
+use crate::types::merkle::MerkleTree;
+use crate::types::state::{AccountAddress, StateError};
+use crate::types::transaction::SignedTransaction;
+use serde::{Deserialize, Serialize};
 
-use log::info;
+use crossbeam::channel::TrySendError;
+use log::{debug, info, warn};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{clone, thread};
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+/// How long `/blockchain/subscribe` waits for a new block unless the request says otherwise
+const SUBSCRIBE_TIMEOUT_SECS: u64 = 30;
+/// Most blocks in a page of `/blockchain/longest-chain-tx-details`, whatever `limit` says
+const MAX_DETAILS_PAGE: usize = 100;
+/// Most transactions in a page of `/account/transactions`, whatever `limit` says
+const MAX_HISTORY_PAGE: usize = 100;
+/// Blocks listed by `/explorer/recent` unless the request says otherwise
+const DEFAULT_RECENT_BLOCKS: usize = 10;
+/// Most blocks listed by `/explorer/recent`, whatever `count` says
+const MAX_RECENT_BLOCKS: usize = 100;
+/// Most clients whose request rate is tracked before those that are not limited are forgotten
+const MAX_RATE_LIMITED_CLIENTS: usize = 1024;
+/// Number of threads serving requests unless configured otherwise
+pub const DEFAULT_WORKERS: usize = 8;
+/// Most requests waiting for a worker, more are turned away until the queue drains
+const MAX_QUEUED_REQUESTS: usize = 1024;
+
 pub struct Server {
-    handle: HTTPServer,
+    handle: Arc<HTTPServer>,
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    generator: TransactionGenerator, // cloned by every /tx-generator/start
+    rate_limiter: Option<RateLimiter>,
+    admin_token: Option<String>,
+    debug_api: bool,
+}
+
+/// Returned by `Server::start` to stop the server
+pub struct Handle {
+    http: Arc<HTTPServer>,
+    dispatcher: thread::JoinHandle<()>,
+}
+
+impl Handle {
+    /// Stop taking requests, the listening socket is closed soon after. Requests already handed
+    /// to the workers are still answered.
+    pub fn stop(self) {
+        self.http.unblock();
+        if self.dispatcher.join().is_err() {
+            warn!("API dispatcher panicked");
+        }
+        // dropping the last reference to the server closes the socket
+        info!("API server stopped");
+    }
+}
+
+/// Settings of the API server besides the node it serves
+#[derive(Clone)]
+pub struct Options {
+    /// Requests per second allowed from each client IP, unlimited if None
+    pub rate_limit: Option<u32>,
+    /// Bearer token a client must present to use the `/admin` endpoints, disabled if None
+    pub admin_token: Option<String>,
+    /// Number of threads serving requests, at least one. A `/blockchain/subscribe` holds its
+    /// thread until a block arrives or it times out.
+    pub workers: usize,
+    /// Whether the `/debug` endpoints that change the chain, breaking consensus, are served
+    pub debug_api: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            rate_limit: None,
+            admin_token: None,
+            workers: DEFAULT_WORKERS,
+            debug_api: false,
+        }
+    }
+}
+
+/// A request and the handler that answers it, run by one of the API workers
+type Job = (
+    tiny_http::Request,
+    Box<dyn FnOnce(tiny_http::Request) + Send>,
+);
+
+/// A token bucket per client IP. A bucket holds up to `rate` requests and refills at `rate`
+/// requests per second, so a client may burst a second's worth of requests at once.
+struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<IpAddr, (f64, Instant)>, // tokens left and when they were last counted
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token from the client's bucket at `now`, returning false if it is empty
+    fn allow(&mut self, client: IpAddr, now: Instant) -> bool {
+        let rate = self.rate;
+        let refill = |tokens: f64, last: Instant| {
+            (tokens + rate * now.saturating_duration_since(last).as_secs_f64()).min(rate)
+        };
+        if self.buckets.len() >= MAX_RATE_LIMITED_CLIENTS {
+            // a full bucket is the same as none
+            self.buckets
+                .retain(|_, (tokens, last)| refill(*tokens, *last) < rate);
+        }
+        let (tokens, last) = self.buckets.entry(client).or_insert((rate, now));
+        *tokens = refill(*tokens, *last);
+        *last = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
 }
 
 #[derive(Serialize)]
@@ -31,15 +149,224 @@ struct ApiResponse {
     message: String,
 }
 
+/// Everything a light client needs to check that a transaction is in a block with
+/// `merkle::verify`
+#[derive(Serialize)]
+struct TransactionProof {
+    block: String,
+    transaction: String,
+    merkle_root: String,
+    index: usize,
+    leaf_count: usize,
+    proof: Vec<String>,
+}
+
+/// A transaction decoded field by field
+#[derive(Serialize)]
+struct TransactionDetails {
+    hash: String,
+    sender: String,
+    receiver: String,
+    value: i64,
+    fee: u64,
+    nonce: u64,
+}
+
+impl From<&SignedTransaction> for TransactionDetails {
+    fn from(tx: &SignedTransaction) -> Self {
+        Self {
+            hash: tx.hash().to_string(),
+            sender: tx.get_sender().clone(),
+            receiver: tx.get_receiver().clone(),
+            value: tx.get_value(),
+            fee: tx.get_fee(),
+            nonce: tx.get_nonce(),
+        }
+    }
+}
+
+/// Fees of the ready transactions in the mempool at the 25th, 50th and 90th percentiles (null
+/// if there are none), and the fee estimated to get a transaction into the next block
+#[derive(Serialize)]
+struct FeeRates {
+    p25: Option<u64>,
+    p50: Option<u64>,
+    p90: Option<u64>,
+    next_block: u64,
+}
+
+/// The nonce of an account in the tip state and the one its next transaction should take, past
+/// those of its transactions pending in the mempool
+#[derive(Serialize)]
+struct AccountNonce {
+    address: String,
+    state_nonce: u64,
+    next_nonce: u64,
+}
+
+/// A transaction of an account's history and the block of the longest chain holding it
+#[derive(Serialize)]
+struct SentTransaction {
+    block: String,
+    height: u32,
+    transaction: TransactionDetails,
+}
+
+/// The transactions of a block of the longest chain, in block order
+#[derive(Serialize)]
+struct BlockTransactions {
+    block: String,
+    height: usize,
+    transactions: Vec<TransactionDetails>,
+}
+
+/// A block of the longest chain as an explorer lists it. `miner` is the receiver of its coinbase,
+/// null for genesis, and `timestamp` is in milliseconds since the Unix epoch.
+#[derive(Serialize)]
+struct RecentBlock {
+    hash: String,
+    height: usize,
+    timestamp: u64,
+    transaction_count: usize,
+    miner: Option<String>,
+}
+
+/// A block for an external miner to complete. The header is hashed as its bincode encoding:
+/// `parent`, `nonce` (u32, little endian), `difficulty`, `timestamp` (u128, little endian),
+/// `merkle_root` and `state_root`, the hashes as 32 raw bytes each. The block is found when that
+/// hash is at most `difficulty`, and is then posted back with its nonce to `/miner/submit-block`.
+/// `nonce` is a random seed to start the search from, and `height` is informational only.
+#[derive(Serialize, Deserialize)]
+struct BlockTemplate {
+    #[serde(default)]
+    height: u32,
+    parent: String,
+    difficulty: String,
+    merkle_root: String,
+    state_root: String,
+    timestamp: u128,
+    nonce: u32,
+    transactions: Vec<SignedTransaction>,
+}
+
+impl BlockTemplate {
+    fn new(block: &Block, height: u32) -> Self {
+        BlockTemplate {
+            height,
+            parent: block.get_parent().to_string(),
+            difficulty: block.get_difficulty().to_string(),
+            merkle_root: block.get_merkle_root().to_string(),
+            state_root: block.get_state_root().to_string(),
+            timestamp: block.get_timestamp(),
+            nonce: block.get_nonce(),
+            transactions: block.get_transactions().clone(),
+        }
+    }
+
+    /// The block this template describes, with the header fields as given
+    fn into_block(self) -> Result<Block, String> {
+        let header = BlockHeader::from_fields(
+            parse_hash(&self.parent)?,
+            self.nonce,
+            parse_hash(&self.difficulty)?,
+            self.timestamp,
+            parse_hash(&self.merkle_root)?,
+            parse_hash(&self.state_root)?,
+        );
+        Ok(Block::from_parts(header, self.transactions))
+    }
+}
+
+/// Where a transaction is: `confirmed` in a block of the longest chain, `unconfirmed` in the
+/// mempool, or `unknown`. Confirmations are the tip height minus the height of the block.
+#[derive(Serialize)]
+struct TransactionStatus {
+    status: &'static str,
+    block: Option<String>,
+    height: Option<u32>,
+    confirmations: u32,
+}
+
+/// Status of the node in a single response. `mining` is `paused`, `running` or `shut down`, and
+/// `lambda` is the interval between blocks, in microseconds, while running.
+#[derive(Serialize)]
+struct NodeInfo {
+    protocol_version: u32,
+    address: String,
+    genesis: String,
+    tip: String,
+    best_height: u32,
+    peer_count: usize,
+    mempool_size: usize,
+    block_count: usize,
+    transaction_count: usize,
+    mining: &'static str,
+    lambda: Option<u64>,
+}
+
+/// A block no known block builds on
+#[derive(Serialize)]
+struct ForkTip {
+    hash: String,
+    height: u32,
+    work: String, // cumulative work, in decimal as it may not fit in a JSON number
+}
+
+/// The proof-of-work target of the tip, which the next block inherits, and the number of hashes
+/// expected to find a block below it. Difficulty doesn't retarget, so there is no hashrate to
+/// estimate from recent blocks.
+#[derive(Serialize)]
+struct Difficulty {
+    tip: String,
+    height: u32,
+    target: String,
+    work: String, // in decimal as it may not fit in a JSON number
+}
+
+/// Whether a transaction would be accepted against the tip state. On failure `reason` is one of
+/// `malformed`, `bad_signature`, `sender_mismatch`, `unknown_sender`, `bad_nonce`,
+/// `insufficient_funds`, `dust` or `coinbase`, and `message` explains it.
+#[derive(Serialize)]
+struct Validation {
+    valid: bool,
+    reason: Option<&'static str>,
+    message: Option<String>,
+}
+
+/// A transaction added to the mempool and how many peers its hash was announced to
+#[derive(Serialize)]
+struct Submission {
+    hash: String,
+    peers: usize,
+}
+
+/// Delays between the header timestamps of recently inserted blocks and their arrival, oldest
+/// first, with their mean (null before any block arrives)
+#[derive(Serialize)]
+struct PropagationReport {
+    average_delay_ms: Option<f64>,
+    recent: Vec<PropagationSample>,
+}
+
+#[derive(Serialize)]
+struct PropagationSample {
+    block: String,
+    delay_ms: u64,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
+        respond_result!($req, $success, $message, 200)
+    }};
+    ( $req:expr, $success:expr, $message:expr, $status:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
         let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
+            .with_header(content_type)
+            .with_status_code($status);
         $req.respond(resp).unwrap();
     }};
 }
@@ -59,22 +386,57 @@ impl Server {
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
-    ) {
-        let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
+        generator: &TransactionGenerator,
+        options: &Options,
+    ) -> Handle {
+        let handle = Arc::new(HTTPServer::http(&addr).unwrap());
+        let http = Arc::clone(&handle);
+        let mut server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
+            generator: generator.clone(),
+            rate_limiter: options.rate_limit.map(RateLimiter::new),
+            admin_token: options.admin_token.clone(),
+            debug_api: options.debug_api,
         };
-        thread::spawn(move || {
+        let (queue, jobs) = crossbeam::channel::bounded::<Job>(MAX_QUEUED_REQUESTS);
+        for i in 0..options.workers.max(1) {
+            let jobs = jobs.clone();
+            thread::Builder::new()
+                .name(format!("api-{}-{}", addr.port(), i))
+                .spawn(move || {
+                    for (req, handle) in jobs.iter() {
+                        // a client hanging up before its response panics the handler, the
+                        // worker lives on to serve the next request
+                        let handled = panic::catch_unwind(AssertUnwindSafe(|| handle(req)));
+                        if handled.is_err() {
+                            warn!("API request handler panicked");
+                        }
+                    }
+                })
+                .unwrap();
+        }
+        let dispatcher = thread::spawn(move || {
             for req in server.handle.incoming_requests() {
+                if let Some(rate_limiter) = &mut server.rate_limiter {
+                    let client = req.remote_addr().ip();
+                    if !rate_limiter.allow(client, Instant::now()) {
+                        debug!("Throttling API requests from {}", client);
+                        respond_result!(req, false, "too many requests", 429);
+                        continue;
+                    }
+                }
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let mempool = Arc::clone(&server.mempool);
-                thread::spawn(move || {
+                let tx_generator = server.generator.clone();
+                let admin_token = server.admin_token.clone();
+                let debug_api = server.debug_api;
+                let handle = move |mut req: tiny_http::Request| {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
                     let url = match base_url.join(req.url()) {
@@ -109,6 +471,65 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/mine-one" => match miner.mine_one() {
+                            Some(hash) => respond_result!(req, true, hash),
+                            None => respond_result!(req, false, "miner is shut down", 503),
+                        },
+                        "/miner/block-template" => {
+                            let (block, height) = miner::block_template(
+                                &blockchain,
+                                &mempool,
+                                miner.address(),
+                                miner.max_block_size(),
+                            );
+                            respond_json!(req, BlockTemplate::new(&block, height));
+                        }
+                        "/miner/submit-block" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let block = match serde_json::from_str::<BlockTemplate>(&body)
+                                .map_err(|e| format!("error parsing block: {}", e))
+                                .and_then(BlockTemplate::into_block)
+                            {
+                                Ok(block) => block,
+                                Err(e) => {
+                                    respond_result!(req, false, e, 400);
+                                    return;
+                                }
+                            };
+                            // the same checks as a block from a peer, signatures outside the lock
+                            let params = blockchain.lock().unwrap().params().clone();
+                            if let Err(e) = blockchain::check_block(&block, &params) {
+                                respond_result!(req, false, e.to_string(), 422);
+                                return;
+                            }
+                            let state = {
+                                let mut blockchain = blockchain.lock().unwrap();
+                                if let Err(e) = blockchain.check_block_context(&block) {
+                                    respond_result!(req, false, e.to_string(), 422);
+                                    return;
+                                }
+                                if blockchain.insert(&block) == InsertResult::AlreadyKnown {
+                                    respond_result!(req, false, "block is already known", 422);
+                                    return;
+                                }
+                                blockchain.get_state().clone()
+                            };
+                            // against a copy, so the chain isn't locked for the whole mempool
+                            mempool.lock().unwrap().revalidate(&state);
+                            metrics::global().block_mined();
+                            let hash = block.hash();
+                            info!("Block {} submitted through the API", hash);
+                            network.broadcast(Message::Inv(vec![InvItem::Block(hash)]));
+                            respond_result!(req, true, hash);
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -130,8 +551,7 @@ impl Server {
                                     return;
                                 }
                             };
-                            let tx_generator = TransactionGenerator::new();
-                            tx_generator.start(theta, network, mempool);
+                            tx_generator.start(theta, network, mempool, blockchain);
                             respond_result!(req, true, "Transaction generator started");
                             // unimplemented!()
                             // respond_result!(req, false, "unimplemented!");
@@ -140,20 +560,136 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/node/info" => {
+                            let mempool_size = mempool.lock().unwrap().len();
+                            let (genesis, tip, block_count, transaction_count) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                (
+                                    blockchain.genesis_hash(),
+                                    blockchain.tip(),
+                                    blockchain.block_count(),
+                                    blockchain.total_transaction_count(),
+                                )
+                            };
+                            let (mining, lambda) = match miner.operating_state() {
+                                OperatingState::Paused => ("paused", None),
+                                OperatingState::Run(lambda) => ("running", Some(lambda)),
+                                OperatingState::ShutDown => ("shut down", None),
+                            };
+                            respond_json!(
+                                req,
+                                NodeInfo {
+                                    protocol_version: PROTOCOL_VERSION,
+                                    address: miner.address().to_string(),
+                                    genesis: genesis.to_string(),
+                                    tip: tip.to_string(),
+                                    best_height: network.best_height(),
+                                    peer_count: network.peers().len(),
+                                    mempool_size,
+                                    block_count,
+                                    transaction_count,
+                                    mining,
+                                    lambda,
+                                }
+                            );
+                        }
+                        "/debug/fork-info" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tips: Vec<ForkTip> = blockchain
+                                .leaves()
+                                .into_iter()
+                                .map(|hash| ForkTip {
+                                    hash: hash.to_string(),
+                                    height: blockchain.get_height(&hash).unwrap_or(0),
+                                    work: blockchain.get_work(&hash).unwrap_or(0).to_string(),
+                                })
+                                .collect();
+                            respond_json!(req, tips);
+                        }
+                        "/debug/set-tip" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            if !debug_api {
+                                respond_result!(req, false, "debug endpoints are disabled", 403);
+                                return;
+                            }
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let block_hash = match params.get("block").map(|v| parse_hash(v)) {
+                                Some(Ok(hash)) => hash,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let state = {
+                                let mut blockchain = blockchain.lock().unwrap();
+                                if let Err(e) = blockchain.force_tip(block_hash) {
+                                    respond_result!(req, false, e, 404);
+                                    return;
+                                }
+                                blockchain.get_state().clone()
+                            };
+                            warn!("Tip forced to {} through the debug API", block_hash);
+                            mempool.lock().unwrap().revalidate(&state);
+                            respond_result!(req, true, block_hash);
+                        }
+                        "/blockchain/difficulty" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = blockchain.tip();
+                            let target = blockchain
+                                .get_block(&tip)
+                                .map_or(blockchain.params().difficulty, |b| b.get_difficulty());
+                            respond_json!(
+                                req,
+                                Difficulty {
+                                    tip: tip.to_string(),
+                                    height: blockchain.best_height(),
+                                    target: target.to_string(),
+                                    work: blockchain::block_work(&target).to_string(),
+                                }
+                            );
+                        }
+                        "/network/peers" => {
+                            let peers: Vec<String> =
+                                network.peers().iter().map(|p| p.to_string()).collect();
+                            respond_json!(req, peers);
+                        }
                         "/blockchain/longest-chain" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (start, limit) = match parse_page(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
                             let blockchain = blockchain.lock().unwrap();
-                            let v = blockchain.all_blocks_in_longest_chain();
-                            let v_string: Vec<String> =
-                                v.into_iter().map(|h| h.to_string()).collect();
+                            let v = blockchain.blocks_in_longest_chain(start, limit);
+                            let v_string: Vec<String> = v.iter().map(|h| h.to_string()).collect();
                             respond_json!(req, v_string);
                         }
                         "/blockchain/longest-chain-tx" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (start, limit) = match parse_page(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
                             let blockchain = blockchain.lock().unwrap();
-                            let longest_chain_hashes = blockchain.all_blocks_in_longest_chain();
+                            let longest_chain_hashes =
+                                blockchain.blocks_in_longest_chain(start, limit);
                             let mut tx_hashes: Vec<Vec<String>> = Vec::new();
 
                             for block_hash in longest_chain_hashes {
-                                if let Some(block) = blockchain.get_block(&block_hash) {
+                                if let Some(block) = blockchain.get_block(block_hash) {
                                     let block_tx_hashes: Vec<String> = block
                                         .get_transactions()
                                         .iter()
@@ -170,6 +706,74 @@ impl Server {
                             // unimplemented!()
                             // respond_result!(req, false, "unimplemented!");
                         }
+                        "/blockchain/longest-chain-tx-details" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (start, limit) = match parse_page(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let page: Vec<BlockTransactions> = blockchain
+                                .blocks_in_longest_chain(start, limit.min(MAX_DETAILS_PAGE))
+                                .iter()
+                                .zip(start..)
+                                .filter_map(|(hash, height)| {
+                                    let block = blockchain.get_block(hash)?;
+                                    Some(BlockTransactions {
+                                        block: hash.to_string(),
+                                        height,
+                                        transactions: block
+                                            .get_transactions()
+                                            .iter()
+                                            .map(TransactionDetails::from)
+                                            .collect(),
+                                    })
+                                })
+                                .collect();
+                            respond_json!(req, page);
+                        }
+                        "/explorer/recent" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let count = match params.get("count").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => v.min(MAX_RECENT_BLOCKS),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing count: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => DEFAULT_RECENT_BLOCKS,
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let length = blockchain.best_height() as usize + 1;
+                            let start = length.saturating_sub(count);
+                            let recent: Vec<RecentBlock> = blockchain
+                                .blocks_in_longest_chain(start, count)
+                                .iter()
+                                .zip(start..length)
+                                .rev()
+                                .filter_map(|(hash, height)| {
+                                    let block = blockchain.get_block(hash)?;
+                                    let transactions = block.get_transactions();
+                                    Some(RecentBlock {
+                                        hash: hash.to_string(),
+                                        height,
+                                        timestamp: block.get_timestamp() as u64,
+                                        transaction_count: transactions.len(),
+                                        miner: transactions
+                                            .first()
+                                            .filter(|tx| tx.is_coinbase())
+                                            .map(|tx| tx.get_receiver().clone()),
+                                    })
+                                })
+                                .collect();
+                            respond_json!(req, recent);
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             let blockchain = blockchain.lock().unwrap();
                             let longest_chain_hashes = blockchain.all_blocks_in_longest_chain();
@@ -235,9 +839,477 @@ impl Server {
 
                                     respond_json!(req, accounts_str);
                                 }
+                                Err(e) => respond_result!(req, false, e, status_code(&e)),
+                            }
+                        }
+                        "/blockchain/subscribe" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let timeout = match params.get("timeout").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing timeout: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => SUBSCRIBE_TIMEOUT_SECS,
+                            };
+                            // long-poll: hold the request until the next tip is committed
+                            let tips = blockchain.lock().unwrap().subscribe_tip();
+                            match tips.recv_timeout(Duration::from_secs(timeout)) {
+                                Ok(tip) => respond_json!(req, tip.to_string()),
+                                Err(_) => respond_result!(req, false, "no new block"),
+                            }
+                        }
+                        "/blockchain/tx-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block_hash = match params.get("block").map(|v| parse_hash(v)) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing block hash");
+                                    return;
+                                }
+                            };
+                            let tx_hash = match params.get("tx").map(|v| parse_hash(v)) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing transaction hash");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let block = match blockchain.get_block(&block_hash) {
+                                Some(block) => block,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            let transactions = block.get_transactions();
+                            let index =
+                                match transactions.iter().position(|tx| tx.hash() == tx_hash) {
+                                    Some(index) => index,
+                                    None => {
+                                        respond_result!(req, false, "transaction not in block");
+                                        return;
+                                    }
+                                };
+                            let merkle_tree = MerkleTree::new(transactions);
+                            match merkle_tree.proof(index) {
+                                Ok(proof) => respond_json!(
+                                    req,
+                                    TransactionProof {
+                                        block: block_hash.to_string(),
+                                        transaction: tx_hash.to_string(),
+                                        // what the header commits to, checked when the block
+                                        // was accepted
+                                        merkle_root: block.get_merkle_root().to_string(),
+                                        index,
+                                        leaf_count: transactions.len(),
+                                        proof: proof.iter().map(|h| h.to_string()).collect(),
+                                    }
+                                ),
                                 Err(e) => respond_result!(req, false, e),
                             }
                         }
+                        "/mempool/size" => {
+                            let mempool = mempool.lock().unwrap();
+                            respond_json!(req, mempool.len());
+                        }
+                        "/mempool/feerate" => {
+                            let max_block_size = blockchain.lock().unwrap().params().max_block_size;
+                            let mempool = mempool.lock().unwrap();
+                            respond_json!(
+                                req,
+                                FeeRates {
+                                    p25: mempool.fee_percentile(25),
+                                    p50: mempool.fee_percentile(50),
+                                    p90: mempool.fee_percentile(90),
+                                    next_block: mempool.next_block_fee(max_block_size),
+                                }
+                            );
+                        }
+                        "/mempool/export" => {
+                            let transactions = mempool.lock().unwrap().export();
+                            respond_json!(req, transactions);
+                        }
+                        "/mempool/import" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let transactions: Vec<SignedTransaction> =
+                                match serde_json::from_str(&body) {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing transactions: {}", e),
+                                            400
+                                        );
+                                        return;
+                                    }
+                                };
+                            let total = transactions.len();
+                            let imported = mempool.lock().unwrap().import(transactions);
+                            respond_result!(
+                                req,
+                                true,
+                                format!("imported {} of {} transactions", imported, total)
+                            );
+                        }
+                        "/mempool/transactions" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let limit = match params.get("limit") {
+                                Some(v) => match v.parse::<usize>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing limit: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            let mempool = mempool.lock().unwrap();
+                            let hashes: Vec<String> = mempool
+                                .transaction_hashes()
+                                .into_iter()
+                                .take(limit.unwrap_or(usize::MAX))
+                                .map(|h| h.to_string())
+                                .collect();
+                            respond_json!(req, hashes);
+                        }
+                        "/admin/shutdown" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            let authorized = match &admin_token {
+                                Some(token) => has_bearer_token(&req, token),
+                                None => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        "admin endpoints are disabled",
+                                        403
+                                    );
+                                    return;
+                                }
+                            };
+                            if !authorized {
+                                respond_result!(req, false, "invalid admin token", 401);
+                                return;
+                            }
+                            info!("Shutdown requested through the API");
+                            respond_result!(req, true, "shutting down");
+                            // the main thread stops the miner and saves a snapshot before exiting
+                            shutdown::request();
+                        }
+                        "/metrics/propagation" => {
+                            let propagation = metrics::global().propagation();
+                            let recent = propagation
+                                .recent()
+                                .map(|(block, delay)| PropagationSample {
+                                    block: block.to_string(),
+                                    delay_ms: *delay,
+                                })
+                                .collect();
+                            respond_json!(
+                                req,
+                                PropagationReport {
+                                    average_delay_ms: propagation.average(),
+                                    recent,
+                                }
+                            );
+                        }
+                        "/metrics" => {
+                            let (chain_height, tip_timestamp, block_count, transaction_count) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let tip = blockchain.tip();
+                                let timestamp = blockchain
+                                    .get_block(&tip)
+                                    .map(|block| block.get_timestamp())
+                                    .unwrap_or(0);
+                                (
+                                    blockchain.get_height(&tip).unwrap_or(0),
+                                    timestamp,
+                                    blockchain.block_count(),
+                                    blockchain.total_transaction_count(),
+                                )
+                            };
+                            let now = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap()
+                                .as_millis();
+                            let gauges = Gauges {
+                                chain_height: chain_height as u64,
+                                tip_age_seconds: (now.saturating_sub(tip_timestamp) / 1000) as u64,
+                                mempool_size: mempool.lock().unwrap().len() as u64,
+                                peer_count: network.peers().len() as u64,
+                                block_count: block_count as u64,
+                                transaction_count: transaction_count as u64,
+                            };
+                            let content_type = "Content-Type: text/plain; version=0.0.4"
+                                .parse::<Header>()
+                                .unwrap();
+                            let resp = Response::from_string(metrics::global().render(&gauges))
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/transaction/status" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx_hash = match params.get("tx").map(|v| parse_hash(v)) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing transaction hash");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            let status = match blockchain.get_transaction_block(&tx_hash) {
+                                Some((block_hash, height)) => TransactionStatus {
+                                    status: "confirmed",
+                                    block: Some(block_hash.to_string()),
+                                    height: Some(height),
+                                    confirmations: blockchain
+                                        .get_height(&blockchain.tip())
+                                        .unwrap_or(height)
+                                        - height,
+                                },
+                                None => TransactionStatus {
+                                    status: if mempool.contains_transaction(&tx_hash) {
+                                        "unconfirmed"
+                                    } else {
+                                        "unknown"
+                                    },
+                                    block: None,
+                                    height: None,
+                                    confirmations: 0,
+                                },
+                            };
+                            respond_json!(req, status);
+                        }
+                        "/transaction/validate" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            // a dry run: only the tip state is read, nothing is added anywhere
+                            let verdict = match serde_json::from_str::<SignedTransaction>(&body) {
+                                Ok(tx) => match blockchain
+                                    .lock()
+                                    .unwrap()
+                                    .get_state()
+                                    .check_transaction(&tx)
+                                {
+                                    Ok(()) => Validation {
+                                        valid: true,
+                                        reason: None,
+                                        message: None,
+                                    },
+                                    Err(e) => Validation {
+                                        valid: false,
+                                        reason: Some(rejection_reason(&e)),
+                                        message: Some(e.to_string()),
+                                    },
+                                },
+                                Err(e) => Validation {
+                                    valid: false,
+                                    reason: Some("malformed"),
+                                    message: Some(format!("error parsing transaction: {}", e)),
+                                },
+                            };
+                            respond_json!(req, verdict);
+                        }
+                        "/transaction/submit" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "expected a POST request", 405);
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let tx = match serde_json::from_str::<SignedTransaction>(&body) {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing transaction: {}", e),
+                                        400
+                                    );
+                                    return;
+                                }
+                            };
+                            let hash = tx.hash();
+                            let accepted = {
+                                let checked = blockchain
+                                    .lock()
+                                    .unwrap()
+                                    .get_state()
+                                    .check_transaction(&tx);
+                                let mut mempool = mempool.lock().unwrap();
+                                match checked {
+                                    Ok(()) if mempool.add_transaction(tx) => Ok(()),
+                                    Ok(()) => Err("rejected by the mempool".to_string()),
+                                    Err(e) => Err(e.to_string()),
+                                }
+                            };
+                            if let Err(e) = accepted {
+                                respond_result!(req, false, format!("transaction {} {}", hash, e));
+                                return;
+                            }
+                            let peers =
+                                network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                            debug!(
+                                "Submitted transaction {} announced to {} peers",
+                                hash, peers
+                            );
+                            respond_json!(
+                                req,
+                                Submission {
+                                    hash: hash.to_string(),
+                                    peers,
+                                }
+                            );
+                        }
+                        "/wallet/balance-history" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => AccountAddress::new(v.to_string()),
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let history = blockchain.balance_series(&address);
+                            respond_json!(req, history);
+                        }
+                        "/account/history" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => AccountAddress::new(v.to_string()),
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let height = |name: &str| match params.get(name) {
+                                Some(v) => v
+                                    .parse::<u32>()
+                                    .map(Some)
+                                    .map_err(|e| format!("error parsing {}: {}", name, e)),
+                                None => Ok(None),
+                            };
+                            let (from, to) = match (height("from"), height("to")) {
+                                (Ok(from), Ok(to)) => (from, to),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let history = blockchain.balance_history(
+                                &address,
+                                from.unwrap_or(0),
+                                to.unwrap_or_else(|| blockchain.best_height()),
+                            );
+                            respond_json!(req, history);
+                        }
+                        "/account/next-nonce" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => AccountAddress::new(v.to_string()),
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let state_nonce = blockchain
+                                .lock()
+                                .unwrap()
+                                .get_state()
+                                .get_account(&address)
+                                .map(|account| account.get_nonce())
+                                .unwrap_or(0);
+                            let mempool = mempool.lock().unwrap();
+                            respond_json!(
+                                req,
+                                AccountNonce {
+                                    address: address.to_string(),
+                                    state_nonce,
+                                    next_nonce: mempool.next_nonce(&address, state_nonce),
+                                }
+                            );
+                        }
+                        "/account/transactions" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => AccountAddress::new(v.to_string()),
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let (start, limit) = match parse_page(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let page: Vec<SentTransaction> = blockchain
+                                .transactions_by_sender(&address)
+                                .iter()
+                                .skip(start)
+                                .take(limit.min(MAX_HISTORY_PAGE))
+                                .map(|(block_hash, tx)| SentTransaction {
+                                    block: block_hash.to_string(),
+                                    height: blockchain.get_height(block_hash).unwrap_or(0),
+                                    transaction: TransactionDetails::from(tx),
+                                })
+                                .collect();
+                            respond_json!(req, page);
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -253,9 +1325,1052 @@ impl Server {
                             req.respond(resp).unwrap();
                         }
                     }
-                });
+                };
+                if let Err(TrySendError::Full((req, _))) = queue.try_send((req, Box::new(handle))) {
+                    debug!("API request queue full, turning a request away");
+                    respond_result!(req, false, "server busy", 503);
+                }
             }
         });
         info!("API server listening at {}", &addr);
+        Handle { http, dispatcher }
+    }
+}
+
+/// Whether a request carries `token` in an `Authorization: Bearer` header, compared in constant
+/// time
+fn has_bearer_token(req: &tiny_http::Request, token: &str) -> bool {
+    req.headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .any(|given| {
+            ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok()
+        })
+}
+
+/// The HTTP status reporting a failure to compute a state: a missing block is not found, a
+/// transaction the state rejects is unprocessable and an overflowing balance is our own fault
+fn status_code(error: &StateError) -> u16 {
+    match error {
+        StateError::BlockNotFound(_) => 404,
+        StateError::BalanceOverflow(_) => 500,
+        StateError::Malformed(_)
+        | StateError::InvalidSignature
+        | StateError::SenderMismatch
+        | StateError::InsufficientFunds { .. }
+        | StateError::BadNonce { .. }
+        | StateError::UnknownSender(_)
+        | StateError::MisplacedCoinbase
+        | StateError::WrongReward(_)
+        | StateError::Dust { .. } => 422,
+    }
+}
+
+/// Short name of why the state rejects a transaction, reported by `/transaction/validate`
+fn rejection_reason(error: &StateError) -> &'static str {
+    match error {
+        StateError::Malformed(_) => "malformed",
+        StateError::InvalidSignature => "bad_signature",
+        StateError::SenderMismatch => "sender_mismatch",
+        StateError::UnknownSender(_) => "unknown_sender",
+        StateError::BadNonce { .. } => "bad_nonce",
+        StateError::InsufficientFunds { .. } => "insufficient_funds",
+        StateError::Dust { .. } => "dust",
+        StateError::MisplacedCoinbase | StateError::WrongReward(_) => "coinbase",
+        StateError::BlockNotFound(_) | StateError::BalanceOverflow(_) => "invalid",
+    }
+}
+
+/// Parse the optional `start` and `limit` of a page, which default to everything. `start` is a
+/// height for pages of the longest chain, and a position for pages of a list.
+fn parse_page(params: &HashMap<String, String>) -> Result<(usize, usize), String> {
+    let start = match params.get("start") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|e| format!("error parsing start: {}", e))?,
+        None => 0,
+    };
+    let limit = match params.get("limit") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|e| format!("error parsing limit: {}", e))?,
+        None => usize::MAX,
+    };
+    Ok((start, limit))
+}
+
+/// Parse a hash given as 64 hex characters
+fn parse_hash(hex_str: &str) -> Result<H256, String> {
+    let bytes = hex::decode(hex_str).map_err(|e| format!("error parsing hash: {}", e))?;
+    if bytes.len() != 32 {
+        return Err(format!(
+            "error parsing hash: expected 32 bytes, got {}",
+            bytes.len()
+        ));
+    }
+    let mut raw = [0u8; 32];
+    raw.copy_from_slice(&bytes);
+    Ok(raw.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusParams;
+    use crate::network::server::{Handle as NetworkServerHandle, TestReceiver};
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::key_pair;
+    use crate::types::merkle;
+    use crate::types::state::State;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// Start an API server on `port` over the given blockchain and an empty mempool, which is
+    /// returned
+    fn start_test_api(port: u16, blockchain: &Arc<Mutex<Blockchain>>) -> Arc<Mutex<Mempool>> {
+        start_test_api_with(port, blockchain, &Options::default()).0
+    }
+
+    /// Like `start_test_api`, with the given server options. Also returns the receiver of the
+    /// server's network broadcasts.
+    fn start_test_api_with(
+        port: u16,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        options: &Options,
+    ) -> (Arc<Mutex<Mempool>>, TestReceiver) {
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (_ctx, miner, _blocks) =
+            crate::miner::new(blockchain, &mempool, "node".to_string(), 20);
+        let (network, receiver) = NetworkServerHandle::new_for_test();
+        blockchain
+            .lock()
+            .unwrap()
+            .track_best_height(network.best_height_tracker());
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+        Server::start(
+            addr,
+            &miner,
+            &network,
+            blockchain,
+            &mempool,
+            &TransactionGenerator::default(),
+            options,
+        );
+        (mempool, receiver)
+    }
+
+    /// Send a GET request and return the body of the response
+    fn get_text(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.splitn(2, "\r\n\r\n").nth(1).unwrap().to_string()
+    }
+
+    /// Send a GET request and parse the JSON body of the response
+    fn get(port: u16, path: &str) -> serde_json::Value {
+        serde_json::from_str(&get_text(port, path)).unwrap()
+    }
+
+    /// Send a POST request and parse the JSON body of the response
+    fn post(port: u16, path: &str, body: &str) -> serde_json::Value {
+        post_with_headers(port, path, "", body).1
+    }
+
+    /// Send a POST request with extra header lines, each ending in CRLF, and return the status
+    /// code and the parsed JSON body of the response
+    fn post_with_headers(
+        port: u16,
+        path: &str,
+        headers: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = serde_json::from_str(response.splitn(2, "\r\n\r\n").nth(1).unwrap()).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn tx_proof_verifies() {
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let transactions: Vec<SignedTransaction> = (0..3)
+            .map(|nonce| {
+                let receiver = base64::encode(key_pair::random().public_key());
+                SignedTransaction::new(
+                    Transaction::new(ico_address.clone(), receiver, 1, 0, nonce),
+                    &ico,
+                )
+            })
+            .collect();
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block
+            .get_content_mut()
+            .add_transactions(transactions.clone());
+        block.set_merkle_root(MerkleTree::new(&transactions).root());
+        blockchain.insert(&block);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        start_test_api(17201, &blockchain);
+
+        let tx_hash = transactions[2].hash();
+        let response = get(
+            17201,
+            &format!("/blockchain/tx-proof?block={}&tx={}", block.hash(), tx_hash),
+        );
+        let proof: Vec<H256> = response["proof"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|h| parse_hash(h.as_str().unwrap()).unwrap())
+            .collect();
+        let root = parse_hash(response["merkle_root"].as_str().unwrap()).unwrap();
+        assert_eq!(root, block.get_merkle_root());
+        assert!(merkle::verify(
+            &root,
+            &tx_hash,
+            &proof,
+            response["index"].as_u64().unwrap() as usize,
+            response["leaf_count"].as_u64().unwrap() as usize,
+        ));
+
+        let response = get(
+            17201,
+            &format!(
+                "/blockchain/tx-proof?block={}&tx={}",
+                block.hash(),
+                block.hash()
+            ),
+        );
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn metrics_scraped() {
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&generate_random_block(&blockchain.tip()));
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = start_test_api(17202, &blockchain);
+        mempool
+            .lock()
+            .unwrap()
+            .add_transaction(SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap());
+        metrics::global().block_mined();
+
+        let text = get_text(17202, "/metrics");
+        let value = |name: &str| -> u64 {
+            text.lines()
+                .find(|line| line.starts_with(&format!("{} ", name)))
+                .unwrap_or_else(|| panic!("{} missing from {}", name, text))
+                .split(' ')
+                .nth(1)
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        assert!(text.contains("# TYPE bitcoin_chain_height gauge"));
+        assert_eq!(value("bitcoin_chain_height"), 1);
+        assert_eq!(value("bitcoin_mempool_size"), 1);
+        assert_eq!(value("bitcoin_blocks"), 2);
+        assert_eq!(value("bitcoin_block_transactions"), 0);
+        assert_eq!(value("bitcoin_peers"), 0);
+        assert!(value("bitcoin_blocks_mined_total") > 0);
+        assert!(value("bitcoin_transactions_processed_total") > 0);
+        value("bitcoin_tip_age_seconds");
+        value("bitcoin_blocks_received_total");
+    }
+
+    #[test]
+    fn propagation_delays_reported() {
+        start_test_api(17216, &Arc::new(Mutex::new(Blockchain::new())));
+        let block = generate_random_block(&H256::from([7; 32]));
+        let timestamp = block.get_timestamp();
+        metrics::global().block_propagated(block.hash(), timestamp, timestamp + 1_500);
+
+        let report = get(17216, "/metrics/propagation");
+        let recent = report["recent"].as_array().unwrap();
+        let sample = recent
+            .iter()
+            .find(|sample| sample["block"] == block.hash().to_string())
+            .unwrap();
+        assert_eq!(sample["delay_ms"], 1_500);
+        assert!(report["average_delay_ms"].as_f64().is_some());
+    }
+
+    #[test]
+    fn subscriber_notified_of_new_block() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        start_test_api(17203, &blockchain);
+        let subscriber = thread::spawn(|| get(17203, "/blockchain/subscribe?timeout=10"));
+        // give the request time to subscribe
+        thread::sleep(Duration::from_millis(500));
+        let block = generate_random_block(&blockchain.lock().unwrap().tip());
+        blockchain.lock().unwrap().insert(&block);
+        assert_eq!(subscriber.join().unwrap(), block.hash().to_string());
+
+        let response = get(17203, "/blockchain/subscribe?timeout=0");
+        assert_eq!(response["success"], false);
+    }
+
+    #[test]
+    fn transaction_status_counts_confirmations() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = start_test_api(17204, &blockchain);
+        let transaction = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        let path = format!("/transaction/status?tx={}", transaction.hash());
+        assert_eq!(get(17204, &path)["status"], "unknown");
+
+        mempool.lock().unwrap().add_transaction(transaction.clone());
+        assert_eq!(get(17204, &path)["status"], "unconfirmed");
+
+        let mut block = generate_random_block(&blockchain.lock().unwrap().tip());
+        block
+            .get_content_mut()
+            .add_transactions(vec![transaction.clone()]);
+        blockchain.lock().unwrap().insert(&block);
+        let response = get(17204, &path);
+        assert_eq!(response["status"], "confirmed");
+        assert_eq!(response["block"], block.hash().to_string());
+        assert_eq!(response["height"], 1);
+        assert_eq!(response["confirmations"], 0);
+
+        for confirmations in 1..3 {
+            let mut blockchain = blockchain.lock().unwrap();
+            let next = generate_random_block(&blockchain.tip());
+            blockchain.insert(&next);
+            drop(blockchain);
+            assert_eq!(get(17204, &path)["confirmations"], confirmations);
+        }
+    }
+
+    #[test]
+    fn longest_chain_paginated() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..50 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        let expected: Vec<String> = blockchain.all_blocks_in_longest_chain()[10..15]
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        start_test_api(17205, &blockchain);
+
+        let page = get(17205, "/blockchain/longest-chain?start=10&limit=5");
+        assert_eq!(page, serde_json::json!(expected));
+        let page = get(17205, "/blockchain/longest-chain-tx?start=10&limit=5");
+        assert_eq!(page.as_array().unwrap().len(), 5);
+        assert_eq!(
+            get(17205, "/blockchain/longest-chain")
+                .as_array()
+                .unwrap()
+                .len(),
+            51
+        );
+        assert_eq!(
+            get(17205, "/blockchain/longest-chain?start=49")
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            get(17205, "/blockchain/longest-chain?start=x")["success"],
+            false
+        );
+    }
+
+    #[test]
+    fn state_errors_map_to_status_codes() {
+        let address = AccountAddress::new("alice".to_string());
+        assert_eq!(
+            status_code(&StateError::BlockNotFound(H256::default())),
+            404
+        );
+        assert_eq!(status_code(&StateError::InvalidSignature), 422);
+        assert_eq!(
+            status_code(&StateError::BadNonce {
+                expected: 1,
+                got: 2
+            }),
+            422
+        );
+        assert_eq!(
+            status_code(&StateError::UnknownSender(address.clone())),
+            422
+        );
+        assert_eq!(status_code(&StateError::BalanceOverflow(address)), 500);
+    }
+
+    #[test]
+    fn node_info_summarizes_status() {
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&generate_random_block(&blockchain.tip()));
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = start_test_api(17206, &blockchain);
+        mempool
+            .lock()
+            .unwrap()
+            .add_transaction(SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap());
+        let (genesis, tip) = {
+            let blockchain = blockchain.lock().unwrap();
+            (blockchain.genesis_hash(), blockchain.tip())
+        };
+        assert_eq!(genesis, Block::get_genesis_block().hash());
+        let info = get(17206, "/node/info");
+        assert_eq!(info["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(info["address"], "node");
+        assert_eq!(info["genesis"], genesis.to_string());
+        assert_eq!(info["tip"], tip.to_string());
+        assert_eq!(info["best_height"], 1);
+        assert_eq!(info["peer_count"], 0);
+        assert_eq!(info["mempool_size"], 1);
+        assert_eq!(info["block_count"], 2);
+        assert_eq!(info["transaction_count"], 0);
+        assert_eq!(info["mining"], "paused");
+        assert!(info["lambda"].is_null());
+
+        let tip = blockchain.lock().unwrap().tip();
+        blockchain
+            .lock()
+            .unwrap()
+            .insert(&generate_random_block(&tip));
+        assert_eq!(get(17206, "/node/info")["best_height"], 2);
+    }
+
+    #[test]
+    fn longest_chain_transaction_details() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let tx = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        let mut block = generate_random_block(&blockchain.lock().unwrap().tip());
+        block.get_content_mut().add_transactions(vec![tx.clone()]);
+        blockchain.lock().unwrap().insert(&block);
+        for _ in 0..3 {
+            let tip = blockchain.lock().unwrap().tip();
+            blockchain
+                .lock()
+                .unwrap()
+                .insert(&generate_random_block(&tip));
+        }
+        start_test_api(17213, &blockchain);
+
+        let page = get(
+            17213,
+            "/blockchain/longest-chain-tx-details?start=1&limit=2",
+        );
+        assert_eq!(page.as_array().unwrap().len(), 2);
+        assert_eq!(page[0]["block"], block.hash().to_string());
+        assert_eq!(page[0]["height"], 1);
+        assert_eq!(
+            page[0]["transactions"],
+            serde_json::json!([{
+                "hash": tx.hash().to_string(),
+                "sender": tx.get_sender(),
+                "receiver": tx.get_receiver(),
+                "value": tx.get_value(),
+                "fee": 0,
+                "nonce": 0,
+            }])
+        );
+        assert_eq!(page[1]["height"], 2);
+        assert_eq!(page[1]["transactions"], serde_json::json!([]));
+
+        // genesis and every block after it, without a limit
+        let all = get(17213, "/blockchain/longest-chain-tx-details");
+        assert_eq!(all.as_array().unwrap().len(), 5);
+        assert_eq!(
+            get(17213, "/blockchain/longest-chain-tx-details?limit=x")["success"],
+            false
+        );
+    }
+
+    #[test]
+    fn recent_blocks_newest_first() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes = vec![blockchain.tip()];
+        for height in 1..=12 {
+            let mut block = generate_random_block(&blockchain.tip());
+            block
+                .get_content_mut()
+                .add_transactions(vec![SignedTransaction::coinbase(
+                    format!("miner{}", height),
+                    ConsensusParams::default().block_reward as i64,
+                    height,
+                )]);
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        start_test_api(17223, &Arc::new(Mutex::new(blockchain)));
+
+        let recent = get(17223, "/explorer/recent");
+        let recent = recent.as_array().unwrap();
+        assert_eq!(recent.len(), 10);
+        for (entry, height) in recent.iter().zip((3..=12).rev()) {
+            assert_eq!(entry["height"], height);
+            assert_eq!(entry["hash"], hashes[height].to_string());
+            assert_eq!(entry["transaction_count"], 1);
+            assert_eq!(entry["miner"], format!("miner{}", height));
+            assert!(entry["timestamp"].as_u64().unwrap() > 0);
+        }
+
+        // the count is capped by the chain, genesis has no miner
+        let all = get(17223, "/explorer/recent?count=500");
+        let all = all.as_array().unwrap();
+        assert_eq!(all.len(), 13);
+        assert_eq!(all[12]["height"], 0);
+        assert!(all[12]["miner"].is_null());
+        assert_eq!(get(17223, "/explorer/recent?count=2")[1]["height"], 11);
+        assert_eq!(get(17223, "/explorer/recent?count=x")["success"], false);
+    }
+
+    #[test]
+    fn bursts_beyond_rate_limit_throttled() {
+        let options = Options {
+            rate_limit: Some(1),
+            ..Options::default()
+        };
+        start_test_api_with(17214, &Arc::new(Mutex::new(Blockchain::new())), &options);
+        assert!(get(17214, "/node/info")["tip"].is_string());
+        for _ in 0..3 {
+            let throttled = get(17214, "/node/info");
+            assert_eq!(throttled["success"], false);
+            assert_eq!(throttled["message"], "too many requests");
+        }
+
+        // buckets refill over time and are kept per client
+        let mut limiter = RateLimiter::new(2);
+        let (alice, bob) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        assert!(limiter.allow(alice, start));
+        assert!(limiter.allow(alice, start));
+        assert!(!limiter.allow(alice, start));
+        assert!(limiter.allow(bob, start));
+        assert!(limiter.allow(alice, start + Duration::from_millis(500)));
+        assert!(!limiter.allow(alice, start + Duration::from_millis(500)));
+        // no more than a second's worth is saved up
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.allow(alice, later));
+        assert!(limiter.allow(alice, later));
+        assert!(!limiter.allow(alice, later));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn concurrent_requests_served_by_bounded_workers() {
+        let options = Options {
+            workers: 2,
+            ..Options::default()
+        };
+        start_test_api_with(17222, &Arc::new(Mutex::new(Blockchain::new())), &options);
+        // worker threads are named after the port they serve
+        let workers = || {
+            std::fs::read_dir("/proc/self/task")
+                .unwrap()
+                .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+                .filter(|name| name.starts_with("api-17222-"))
+                .count()
+        };
+
+        let clients: Vec<_> = (0..50)
+            .map(|_| thread::spawn(|| get(17222, "/blockchain/longest-chain")))
+            .collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap().as_array().unwrap().len(), 1);
+        }
+        assert_eq!(workers(), 2);
+    }
+
+    #[test]
+    fn shutdown_requires_admin_token() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        start_test_api(17217, &blockchain);
+        let options = Options {
+            admin_token: Some("secret".to_string()),
+            ..Options::default()
+        };
+        start_test_api_with(17218, &blockchain, &options);
+        let shutdown =
+            |port: u16, headers: &str| post_with_headers(port, "/admin/shutdown", headers, "");
+
+        assert_eq!(shutdown(17217, "Authorization: Bearer secret\r\n").0, 403);
+        assert_eq!(shutdown(17218, "").0, 401);
+        assert_eq!(shutdown(17218, "Authorization: Bearer wrong\r\n").0, 401);
+        assert_eq!(shutdown(17218, "Authorization: secret\r\n").0, 401);
+        assert!(!shutdown::requested());
+        assert_eq!(get(17218, "/admin/shutdown")["success"], false);
+
+        let (status, reply) = shutdown(17218, "Authorization: Bearer secret\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(reply["success"], true);
+        // requested right after the response went out
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !shutdown::requested() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(shutdown::requested());
+    }
+
+    #[test]
+    fn stopped_server_closes_its_socket() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (_ctx, miner, _blocks) =
+            crate::miner::new(&blockchain, &mempool, "node".to_string(), 20);
+        let (network, _receiver) = NetworkServerHandle::new_for_test();
+        let addr = "127.0.0.1:17227".parse().unwrap();
+        let handle = Server::start(
+            addr,
+            &miner,
+            &network,
+            &blockchain,
+            &mempool,
+            &TransactionGenerator::default(),
+            &Options::default(),
+        );
+        let chain = get(17227, "/blockchain/longest-chain");
+        assert_eq!(chain.as_array().unwrap().len(), 1);
+
+        // the socket is closed by the thread accepting connections, soon after
+        handle.stop();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_ok() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn difficulty_of_the_tip() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        start_test_api(17212, &blockchain);
+        let target = crate::config::DEFAULT_DIFFICULTY.into();
+        let work = blockchain::block_work(&target).to_string();
+        let expected = |tip: &H256, height: u32| {
+            serde_json::json!({
+                "tip": tip.to_string(),
+                "height": height,
+                "target": target.to_string(),
+                "work": work,
+            })
+        };
+        assert_eq!(
+            get(17212, "/blockchain/difficulty"),
+            expected(&genesis_hash, 0)
+        );
+
+        // without retargeting every block keeps the same target
+        let block = generate_random_block(&genesis_hash);
+        blockchain.lock().unwrap().insert(&block);
+        assert_eq!(
+            get(17212, "/blockchain/difficulty"),
+            expected(&block.hash(), 1)
+        );
+    }
+
+    #[test]
+    fn validate_transaction_dry_run() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = start_test_api(17207, &blockchain);
+        let ico = key_pair::ico();
+        let ico_address = key_pair::address(&ico);
+        let balance = blockchain
+            .lock()
+            .unwrap()
+            .get_state()
+            .get_balance(&AccountAddress::new(ico_address.clone()));
+        let signed = |sender: &str, value: i64, nonce: u64, key: &Ed25519KeyPair| {
+            let tx = Transaction::new(sender.to_string(), "bob".to_string(), value, 1, nonce);
+            serde_json::to_string(&SignedTransaction::new(tx, key)).unwrap()
+        };
+        let validate = |body: &str| post(17207, "/transaction/validate", body);
+
+        let verdict = validate(&signed(&ico_address, 5, 0, &ico));
+        assert_eq!(verdict["valid"], true);
+        assert!(verdict["reason"].is_null());
+
+        let reason = |body: &str| validate(body)["reason"].as_str().unwrap().to_string();
+        assert_eq!(
+            reason(&signed(&ico_address, balance as i64, 0, &ico)),
+            "insufficient_funds"
+        );
+        assert_eq!(reason(&signed(&ico_address, 5, 1, &ico)), "bad_nonce");
+        let stranger = key_pair::random();
+        assert_eq!(
+            reason(&signed(&ico_address, 5, 0, &stranger)),
+            "sender_mismatch"
+        );
+        assert_eq!(
+            reason(&signed(&key_pair::address(&stranger), 5, 0, &stranger)),
+            "unknown_sender"
+        );
+        let mut tampered: serde_json::Value =
+            serde_json::from_str(&signed(&ico_address, 5, 0, &ico)).unwrap();
+        tampered["transaction"]["value"] = 6.into();
+        assert_eq!(reason(&tampered.to_string()), "bad_signature");
+        tampered["signature"].as_array_mut().unwrap().pop();
+        assert_eq!(reason(&tampered.to_string()), "malformed");
+        assert_eq!(reason("not a transaction"), "malformed");
+
+        // nothing was kept
+        assert!(mempool.lock().unwrap().is_empty());
+        assert_eq!(blockchain.lock().unwrap().best_height(), 0);
+        assert_eq!(
+            get(17207, "/transaction/validate")["success"],
+            serde_json::Value::Bool(false)
+        );
+    }
+
+    #[test]
+    fn submitted_transaction_announced() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mempool, broadcasts) = start_test_api_with(17215, &blockchain, &Options::default());
+        let ico = key_pair::ico();
+        let ico_address = key_pair::address(&ico);
+        let signed = |nonce: u64| {
+            let tx = Transaction::new(ico_address.clone(), "bob".to_string(), 5, 1, nonce);
+            SignedTransaction::new(tx, &ico)
+        };
+        let submit = |tx: &SignedTransaction| {
+            post(
+                17215,
+                "/transaction/submit",
+                &serde_json::to_string(tx).unwrap(),
+            )
+        };
+
+        // the test network handle has no peers, but the announcement still goes out
+        let tx = signed(0);
+        let reply = submit(&tx);
+        assert_eq!(reply["hash"], tx.hash().to_string());
+        assert_eq!(reply["peers"], 0);
+        assert!(mempool.lock().unwrap().contains_transaction(&tx.hash()));
+        match broadcasts.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![tx.hash()]),
+            _ => panic!("expected the transaction to be announced"),
+        }
+
+        assert_eq!(submit(&tx)["success"], false);
+        let rejected = submit(&signed(5));
+        assert_eq!(rejected["success"], false);
+        assert!(rejected["message"].as_str().unwrap().contains("nonce"));
+        assert_eq!(mempool.lock().unwrap().len(), 1);
+        assert_eq!(
+            post(17215, "/transaction/submit", "not a transaction")["success"],
+            false
+        );
+        assert_eq!(get(17215, "/transaction/submit")["success"], false);
+    }
+
+    #[test]
+    fn fork_info_lists_competing_tips() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let first = generate_random_block(&genesis_hash);
+        let second = generate_random_block(&first.hash());
+        let side = generate_random_block(&genesis_hash);
+        for block in [&first, &second, &side] {
+            blockchain.insert(block);
+        }
+        let work = |hash: &H256| blockchain.get_work(hash).unwrap().to_string();
+        let expected = serde_json::json!([
+            {"hash": second.hash().to_string(), "height": 2, "work": work(&second.hash())},
+            {"hash": side.hash().to_string(), "height": 1, "work": work(&side.hash())},
+        ]);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        start_test_api(17208, &blockchain);
+        assert_eq!(get(17208, "/debug/fork-info"), expected);
+    }
+
+    #[test]
+    fn set_tip_rebuilds_state_along_the_branch() {
+        let ico = key_pair::ico();
+        let (alice, bob) = (key_pair::random(), key_pair::random());
+        let pay = |receiver: &Ed25519KeyPair, value: i64| {
+            let receiver = key_pair::address(receiver);
+            let transaction = Transaction::new(key_pair::address(&ico), receiver, value, 0, 0);
+            SignedTransaction::new(transaction, &ico)
+        };
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut first = generate_random_block(&genesis_hash);
+        first
+            .get_content_mut()
+            .add_transactions(vec![pay(&alice, 100)]);
+        let second = generate_random_block(&first.hash());
+        let mut side = generate_random_block(&genesis_hash);
+        side.get_content_mut().add_transactions(vec![pay(&bob, 50)]);
+        for block in [&first, &second, &side] {
+            blockchain.insert(block);
+        }
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let options = Options {
+            debug_api: true,
+            ..Options::default()
+        };
+        start_test_api_with(17225, &blockchain, &options);
+        let set_tip = |port: u16, block: &H256| {
+            post_with_headers(port, &format!("/debug/set-tip?block={}", block), "", "")
+        };
+
+        let (status, response) = set_tip(17225, &side.hash());
+        assert_eq!(status, 200);
+        assert_eq!(response["message"], side.hash().to_string());
+        {
+            let blockchain = blockchain.lock().unwrap();
+            let balance = |key: &Ed25519KeyPair| {
+                let address = AccountAddress::new(key_pair::address(key));
+                blockchain.get_state().get_balance(&address)
+            };
+            assert_eq!(blockchain.tip(), side.hash());
+            assert_eq!(blockchain.best_height(), 1);
+            assert_eq!((balance(&alice), balance(&bob)), (0, 50));
+        }
+        assert_eq!(set_tip(17225, &H256::from([7; 32])).0, 404);
+        assert_eq!(get(17225, "/debug/set-tip")["success"], false);
+
+        // without the flag the chain can't be touched
+        start_test_api(17226, &blockchain);
+        assert_eq!(set_tip(17226, &second.hash()).0, 403);
+        assert_eq!(blockchain.lock().unwrap().tip(), side.hash());
+    }
+
+    #[test]
+    fn fee_rates_of_the_mempool() {
+        let params = ConsensusParams {
+            max_block_size: 3,
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let mempool = start_test_api(17220, &blockchain);
+        assert_eq!(
+            get(17220, "/mempool/feerate"),
+            serde_json::json!({"p25": null, "p50": null, "p90": null, "next_block": 0})
+        );
+
+        let senders: Vec<Ed25519KeyPair> = (0..4).map(|_| key_pair::random()).collect();
+        let mut state = State::new();
+        for sender in senders.iter() {
+            state.add_account_with_balance(AccountAddress::new(key_pair::address(sender)), 100);
+        }
+        let mut mempool = mempool.lock().unwrap();
+        mempool.revalidate(&state);
+        for (sender, fee) in senders.iter().zip([6, 2, 8, 4]) {
+            let receiver = key_pair::address(&key_pair::random());
+            let tx = Transaction::new(key_pair::address(sender), receiver, 1, fee, 0);
+            assert!(mempool.add_transaction(SignedTransaction::new(tx, sender)));
+        }
+        drop(mempool);
+        assert_eq!(
+            get(17220, "/mempool/feerate"),
+            serde_json::json!({"p25": 2, "p50": 4, "p90": 8, "next_block": 5})
+        );
+    }
+
+    #[test]
+    fn next_nonce_counts_pending_transactions() {
+        let mempool = start_test_api(17221, &Arc::new(Mutex::new(Blockchain::new())));
+        let ico_address = key_pair::address(&key_pair::ico());
+        let address: String =
+            url::form_urlencoded::byte_serialize(ico_address.as_bytes()).collect();
+        let path = format!("/account/next-nonce?address={}", address);
+        let next_nonce = || get(17221, &path)["next_nonce"].as_u64().unwrap();
+        assert_eq!(next_nonce(), 0);
+        for nonce in 0..2 {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            assert!(mempool.lock().unwrap().add_transaction(tx));
+        }
+        assert_eq!(next_nonce(), 2);
+        assert_eq!(get(17221, &path)["state_nonce"], 0);
+        assert_eq!(get(17221, "/account/next-nonce")["success"], false);
+    }
+
+    #[test]
+    fn mempool_moves_between_nodes() {
+        let source = start_test_api(17209, &Arc::new(Mutex::new(Blockchain::new())));
+        for nonce in 0..3 {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            source.lock().unwrap().add_transaction(tx);
+        }
+        let target = start_test_api(17210, &Arc::new(Mutex::new(Blockchain::new())));
+
+        let exported = get_text(17209, "/mempool/export");
+        let reply = post(17210, "/mempool/import", &exported);
+        assert_eq!(reply["message"], "imported 3 of 3 transactions");
+        assert_eq!(
+            target.lock().unwrap().transaction_hashes(),
+            source.lock().unwrap().transaction_hashes()
+        );
+        assert_eq!(post(17210, "/mempool/import", "[1]")["success"], false);
+    }
+
+    #[test]
+    fn account_history_reports_changes() {
+        let ico = key_pair::ico();
+        let ico_address = key_pair::address(&ico);
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block
+            .get_content_mut()
+            .add_transactions(vec![SignedTransaction::new(
+                Transaction::new(ico_address.clone(), "alice".to_string(), 10, 0, 0),
+                &ico,
+            )]);
+        blockchain.insert(&block);
+        blockchain.insert(&generate_random_block(&block.hash()));
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        start_test_api(17211, &blockchain);
+
+        assert_eq!(
+            get(17211, "/account/history?address=alice"),
+            serde_json::json!([[0, 0], [1, 10]])
+        );
+        assert_eq!(
+            get(17211, "/account/history?address=alice&from=2"),
+            serde_json::json!([[2, 10]])
+        );
+        assert_eq!(
+            get(17211, "/account/history?address=alice&to=x")["success"],
+            false
+        );
+    }
+
+    #[test]
+    fn account_transactions_in_chain_order() {
+        let ico = key_pair::ico();
+        let ico_address = key_pair::address(&ico);
+        let pay = |nonce: u64| {
+            SignedTransaction::new(
+                Transaction::new(ico_address.clone(), "alice".to_string(), 5, 1, nonce),
+                &ico,
+            )
+        };
+        let sent = [pay(0), pay(1), pay(2)];
+        let mut blockchain = Blockchain::new();
+        let mut first = generate_random_block(&blockchain.tip());
+        first
+            .get_content_mut()
+            .add_transactions(vec![sent[0].clone(), sent[1].clone()]);
+        let mut second = generate_random_block(&first.hash());
+        second
+            .get_content_mut()
+            .add_transactions(vec![sent[2].clone()]);
+        blockchain.insert(&first);
+        blockchain.insert(&second);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        start_test_api(17219, &blockchain);
+
+        let address: String =
+            url::form_urlencoded::byte_serialize(ico_address.as_bytes()).collect();
+        let path = format!("/account/transactions?address={}", address);
+        let history = get(17219, &path);
+        let history = history.as_array().unwrap();
+        assert_eq!(history.len(), 3);
+        let blocks = [first.hash(), first.hash(), second.hash()];
+        for (i, entry) in history.iter().enumerate() {
+            assert_eq!(entry["block"], blocks[i].to_string());
+            assert_eq!(entry["height"], if i < 2 { 1 } else { 2 });
+            assert_eq!(entry["transaction"]["hash"], sent[i].hash().to_string());
+            assert_eq!(entry["transaction"]["nonce"], i);
+        }
+
+        let page = get(17219, &format!("{}&start=1&limit=1", path));
+        assert_eq!(page.as_array().unwrap().len(), 1);
+        assert_eq!(page[0]["transaction"]["hash"], sent[1].hash().to_string());
+        assert_eq!(
+            get(17219, "/account/transactions?address=alice"),
+            serde_json::json!([])
+        );
+        assert_eq!(get(17219, "/account/transactions")["success"], false);
+    }
+
+    #[test]
+    fn block_template_mined_and_submitted() {
+        // about half of all hashes meet this difficulty, mining takes a few tries and a block
+        // failing the proof of work is as quick to find
+        let params = ConsensusParams {
+            difficulty: [0x7f; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let genesis = blockchain.lock().unwrap().tip();
+        let (mempool, receiver) = start_test_api_with(17224, &blockchain, &Options::default());
+        let transaction = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        assert!(mempool.lock().unwrap().add_transaction(transaction.clone()));
+
+        let template = get(17224, "/miner/block-template");
+        assert_eq!(template["height"], 1);
+        assert_eq!(template["parent"], genesis.to_string());
+        let transactions = template["transactions"].as_array().unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[1]["transaction"]["nonce"], 0);
+
+        // an external miner grinds the nonce over the header fields it was given
+        let template: BlockTemplate = serde_json::from_value(template).unwrap();
+        let mut block = template.into_block().unwrap();
+        assert_eq!(
+            block.get_merkle_root(),
+            MerkleTree::new(block.get_transactions()).root()
+        );
+        let mut unmined = block.clone();
+        while unmined.hash().meets_difficulty(&unmined.get_difficulty()) {
+            unmined.set_nonce(unmined.get_nonce().wrapping_add(1));
+        }
+        crate::types::block::mine_block(&mut block);
+        let submit = |block: &Block| {
+            let body = serde_json::to_string(&BlockTemplate::new(block, 0)).unwrap();
+            post_with_headers(17224, "/miner/submit-block", "", &body)
+        };
+
+        let (status, response) = submit(&unmined);
+        assert_eq!(status, 422);
+        assert_eq!(response["message"], "block fails proof of work");
+        let mut tampered = block.clone();
+        tampered.get_transactions_mut().pop();
+        assert_eq!(submit(&tampered).0, 422);
+        let (status, response) = post_with_headers(17224, "/miner/submit-block", "", "{}");
+        assert_eq!((status, &response["success"]), (400, &false.into()));
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis);
+
+        let (status, response) = submit(&block);
+        assert_eq!(status, 200);
+        assert_eq!(response["message"], block.hash().to_string());
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+        assert_eq!(submit(&block).1["message"], "block is already known");
+        assert!(blockchain
+            .lock()
+            .unwrap()
+            .get_block(&block.hash())
+            .unwrap()
+            .get_transactions()
+            .iter()
+            .any(|tx| tx.hash() == transaction.hash()));
+        assert_eq!(mempool.lock().unwrap().len(), 0);
+        match receiver.recv() {
+            Some(Message::Inv(items)) => assert_eq!(items, vec![InvItem::Block(block.hash())]),
+            other => panic!("unexpected broadcast {:?}", other),
+        }
     }
 }
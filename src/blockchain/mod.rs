@@ -1,37 +1,240 @@
-use crate::types::block::Block;
+use crate::config::ConsensusParams;
+use crate::persistence::{read_checked, write_checked, DataDir};
+use crate::types::block::{Block, Header};
 use crate::types::hash::{Hashable, H256};
-use crate::types::state::{self, State};
+use crate::types::merkle::MerkleTree;
+use crate::types::signature_cache;
+use crate::types::state::{self, AccountAddress, State, StateError};
+use crate::types::transaction::SignedTransaction;
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use hex_literal::hex;
-use std::collections::HashMap;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::thread::current;
 
+/// Most blocks kept waiting for their parent unless configured otherwise, the oldest is dropped
+/// beyond that
+pub const ORPHAN_CAPACITY: usize = 128;
+
+/// The state after each block at a multiple of this height is kept, on any branch, so the state
+/// after any block is rebuilt from at most this many blocks
+pub const STATE_CHECKPOINT_INTERVAL: u32 = 64;
+
+/// What inserting a block did to the longest chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertResult {
+    /// The block was appended to the tip
+    Extended,
+    /// The block went on a branch that doesn't outrank the current one
+    SideBranch,
+    /// The block was inserted before, nothing changed
+    AlreadyKnown,
+    /// The block's parent is unknown or one of its transactions doesn't apply after it, the
+    /// block was dropped
+    Rejected,
+    /// The block made another branch outrank the current one, replacing the blocks after the
+    /// common ancestor
+    Reorg {
+        old_tip: H256,
+        common_ancestor: H256,
+    },
+}
+
+/// Why a block is turned away before it is inserted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    ProofOfWork,
+    /// More transactions than the size limit, the coinbase aside
+    TooManyTransactions {
+        count: usize,
+        limit: usize,
+    },
+    /// Transactions weighing more than the weight limit, in encoded bytes
+    TooHeavy {
+        weight: usize,
+        limit: usize,
+    },
+    DuplicateTransaction,
+    /// A Merkle root other than the one of the block's transactions
+    WrongMerkleRoot,
+    InvalidSignature,
+    /// A difficulty other than the parent's
+    WrongDifficulty,
+    UnknownParent(H256),
+    /// A state root other than the one of the state after the block's transactions
+    WrongStateRoot,
+    /// The transaction at `index` doesn't apply to the state after the parent and the ones
+    /// before it
+    InvalidTransaction {
+        index: usize,
+        error: StateError,
+    },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::ProofOfWork => write!(f, "block fails proof of work"),
+            BlockError::TooManyTransactions { count, limit } => write!(
+                f,
+                "block has {} transactions, more than the limit of {}",
+                count, limit
+            ),
+            BlockError::TooHeavy { weight, limit } => write!(
+                f,
+                "block weighs {} bytes, more than the limit of {}",
+                weight, limit
+            ),
+            BlockError::DuplicateTransaction => write!(f, "block has a duplicate transaction"),
+            BlockError::WrongMerkleRoot => write!(f, "block has a wrong merkle root"),
+            BlockError::InvalidSignature => write!(f, "block has an invalid signature"),
+            BlockError::WrongDifficulty => write!(f, "block has a wrong difficulty"),
+            BlockError::UnknownParent(parent) => {
+                write!(f, "block has an unknown parent {}", parent)
+            }
+            BlockError::WrongStateRoot => write!(f, "block has a wrong state root"),
+            BlockError::InvalidTransaction { index, error } => {
+                write!(f, "transaction {} of the block fails: {}", index, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Check what can be checked of a block without the chain: its proof of work, its size and
+/// weight under `params`, that no transaction is in it twice, that its header commits to its
+/// transactions, and every signature. Valid signatures are cached, so applying the block
+/// afterwards doesn't verify them again.
+pub fn check_block(block: &Block, params: &ConsensusParams) -> Result<(), BlockError> {
+    if !block.hash().meets_difficulty(&block.get_difficulty()) {
+        return Err(BlockError::ProofOfWork);
+    }
+    // size and weight first, before spending any time on the transactions
+    let transactions = block.get_transactions();
+    let skip = match transactions.first() {
+        Some(tx) if tx.is_coinbase() => 1,
+        _ => 0,
+    };
+    let count = transactions.len() - skip;
+    if count > params.max_block_size {
+        return Err(BlockError::TooManyTransactions {
+            count,
+            limit: params.max_block_size,
+        });
+    }
+    let weight: usize = transactions
+        .iter()
+        .skip(skip)
+        .map(|tx| tx.serialized_size())
+        .sum();
+    if weight > params.max_block_weight {
+        return Err(BlockError::TooHeavy {
+            weight,
+            limit: params.max_block_weight,
+        });
+    }
+    let mut tx_hashes = HashSet::with_capacity(transactions.len());
+    if !transactions.iter().all(|tx| tx_hashes.insert(tx.hash())) {
+        return Err(BlockError::DuplicateTransaction);
+    }
+    if MerkleTree::new(transactions).root() != block.get_merkle_root() {
+        return Err(BlockError::WrongMerkleRoot);
+    }
+    if !signature_cache::global().verify_all(&transactions[skip..]) {
+        return Err(BlockError::InvalidSignature);
+    }
+    Ok(())
+}
+
 pub struct Blockchain {
     blocks: HashMap<H256, Block>,
     tip: H256,
+    longest_chain: Vec<H256>, // hash of the block at each height of the longest chain
     lengths: HashMap<H256, u32>,
+    works: HashMap<H256, u128>, // cumulative work from genesis, the tip has the most
     state: State,
+    genesis_state: State, // balances before any block, replays start from it
+    // state after genesis and each block at a multiple of STATE_CHECKPOINT_INTERVAL
+    checkpoints: HashMap<H256, State>,
+    // balances, after each block on any branch, of the accounts its transactions touched
+    balances_after: HashMap<H256, HashMap<AccountAddress, u128>>,
+    // blocks whose parent is unknown with the peer that sent them, oldest first
+    orphans: VecDeque<(Block, SocketAddr)>,
+    orphan_capacity: usize,
+    tip_subscribers: Vec<Sender<H256>>,
+    best_height_tracker: Option<Arc<AtomicU32>>, // set to the tip's height, read without locking
+    tx_index: HashMap<H256, Vec<H256>>, // transaction hash to the blocks, on any branch, holding it
+    sender_index: HashMap<AccountAddress, Vec<H256>>, // blocks, on any branch, sent from by each
+    transaction_count: usize,           // transactions of all blocks, on any branch
+    params: ConsensusParams,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let genesis_block: Block = Block::get_genesis_block();
+        Self::with_params(ConsensusParams::default(), State::new())
+    }
+
+    /// Create a new blockchain with the default parameters except for `EASIEST_DIFFICULTY`, so
+    /// that tests build valid blocks without mining them
+    #[cfg(any(test, test_utilities))]
+    pub fn with_easiest_difficulty() -> Self {
+        let params = ConsensusParams {
+            difficulty: crate::types::block::EASIEST_DIFFICULTY.into(),
+            ..ConsensusParams::default()
+        };
+        Self::with_params(params, State::new())
+    }
+
+    /// Create a new blockchain, only containing the genesis block, following the given consensus
+    /// parameters and whose accounts start with the given balances
+    pub fn with_params(params: ConsensusParams, genesis_state: State) -> Self {
+        let genesis_block: Block = Block::get_genesis_block_with_difficulty(params.difficulty);
         let genesis_hash = genesis_block.hash();
-        println!("genesis_hash: {}", genesis_hash);
+        debug!("Genesis block {}", genesis_hash);
         let mut blocks = HashMap::new();
         let mut lengths = HashMap::new();
+        let mut works = HashMap::new();
+        let transaction_count = genesis_block.get_transactions().len();
         blocks.insert(genesis_hash, genesis_block.clone());
         lengths.insert(genesis_hash, 0);
+        works.insert(genesis_hash, block_work(&genesis_block.get_difficulty()));
+        let mut checkpoints = HashMap::new();
+        checkpoints.insert(genesis_hash, genesis_state.clone());
         Self {
             blocks,
             tip: genesis_hash,
+            longest_chain: vec![genesis_hash],
             lengths,
-            state: State::new(),
+            works,
+            state: genesis_state.clone(),
+            genesis_state,
+            checkpoints,
+            balances_after: HashMap::new(),
+            orphans: VecDeque::new(),
+            orphan_capacity: ORPHAN_CAPACITY,
+            tip_subscribers: Vec::new(),
+            best_height_tracker: None,
+            tx_index: HashMap::new(),
+            sender_index: HashMap::new(),
+            transaction_count,
+            params,
         }
     }
 
-    pub fn get_state_up_to_block(&self, mut block_number: u32) -> Result<State, String> {
-        let mut state = State::new(); // Start with a new state
+    /// The consensus parameters the chain follows
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    pub fn get_state_up_to_block(&self, mut block_number: u32) -> Result<State, StateError> {
+        let mut state = self.genesis_state.clone(); // Start with a new state
         let mut current_hash = self.tip;
         let mut current_block_number = self.lengths.get(&current_hash).copied().unwrap_or_default();
         if block_number > current_block_number {
@@ -41,14 +244,14 @@ impl Blockchain {
         while current_block_number >= block_number {
             while current_block_number > 0 && current_block_number <= block_number {
                 if let Some(block) = self.blocks.get(&current_hash) {
-                    for transaction in block.get_transactions() {
-                        state.apply_transaction(transaction)?;
+                    if let Err((_, e)) = state.apply_block(block.get_transactions()) {
+                        return Err(e);
                     }
                     current_hash = block.get_parent();
                     current_block_number =
                         self.lengths.get(&current_hash).copied().unwrap_or_default();
                 } else {
-                    return Err("Block not found".to_string());
+                    return Err(StateError::BlockNotFound(current_hash));
                 }
             }
             if current_block_number > 0 {
@@ -60,25 +263,344 @@ impl Blockchain {
         Ok(state)
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Get an account's balance after each block of the longest chain, as (height, balance) pairs
+    /// starting from genesis. No block is applied again, the balances each block left are kept
+    /// when it is inserted.
+    pub fn balance_series(&self, address: &AccountAddress) -> Vec<(u32, u128)> {
+        self.balances_up_to(address, self.best_height())
+    }
+
+    /// Get the points where an account's balance changed along the longest chain, between two
+    /// heights inclusive, as (height, balance) pairs. The first pair is the balance at
+    /// `from_height`, each of the others a height whose block changed it.
+    pub fn balance_history(
+        &self,
+        address: &AccountAddress,
+        from_height: u32,
+        to_height: u32,
+    ) -> Vec<(u32, u128)> {
+        let mut history: Vec<(u32, u128)> = Vec::new();
+        for (height, balance) in self.balances_up_to(address, to_height) {
+            let changed = history.last().map_or(true, |&(_, last)| last != balance);
+            if height >= from_height && changed {
+                history.push((height, balance));
+            }
+        }
+        history
+    }
+
+    /// Get an account's balance after each block of the longest chain up to `to_height`, carrying
+    /// it over the blocks that didn't touch the account
+    fn balances_up_to(&self, address: &AccountAddress, to_height: u32) -> Vec<(u32, u128)> {
+        let mut balance = self.genesis_state.get_balance(address);
+        self.longest_chain
+            .iter()
+            .take(to_height as usize + 1)
+            .enumerate()
+            .map(|(height, block_hash)| {
+                if let Some(after) = self
+                    .balances_after
+                    .get(block_hash)
+                    .and_then(|balances| balances.get(address))
+                {
+                    balance = *after;
+                }
+                (height as u32, balance)
+            })
+            .collect()
+    }
+
+    /// Insert a block into blockchain. The tip is the block with the most cumulative work, which
+    /// with a constant difficulty is the longest chain, and between equal work the smaller hash,
+    /// so nodes agree on it whatever order the blocks arrived in. The state after the block is
+    /// built on its parent's, so a block whose transactions don't all apply is rejected, and the
+    /// state follows the tip without replaying the chain, even when another branch outranks the
+    /// current one.
+    pub fn insert(&mut self, block: &Block) -> InsertResult {
         let block_hash = block.hash();
-        let cloned_block = block.clone();
-        self.blocks.insert(block_hash, cloned_block);
-        self.lengths.insert(
+        // a block echoed back by peers must not touch the indexes or the state again
+        if self.blocks.contains_key(&block_hash) {
+            return InsertResult::AlreadyKnown;
+        }
+        let state = match self.state_after_block(block) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Block {} rejected: {}", block_hash, e);
+                return InsertResult::Rejected;
+            }
+        };
+        self.blocks.insert(block_hash, block.clone());
+        self.transaction_count += block.get_transactions().len();
+        for transaction in block.get_transactions() {
+            if transaction.is_coinbase() {
+                continue;
+            }
+            let blocks = self
+                .sender_index
+                .entry(AccountAddress::new(transaction.get_sender().clone()))
+                .or_default();
+            if blocks.last() != Some(&block_hash) {
+                blocks.push(block_hash);
+            }
+        }
+        for transaction in block.get_transactions() {
+            self.tx_index
+                .entry(transaction.hash())
+                .or_default()
+                .push(block_hash);
+        }
+        let touched = block
+            .get_transactions()
+            .iter()
+            .flat_map(|tx| {
+                let sender = Some(tx.get_sender()).filter(|_| !tx.is_coinbase());
+                sender.into_iter().chain(Some(tx.get_receiver()))
+            })
+            .map(|address| {
+                let address = AccountAddress::new(address.clone());
+                let balance = state.get_balance(&address);
+                (address, balance)
+            })
+            .collect();
+        self.balances_after.insert(block_hash, touched);
+        let height = self.lengths[&block.get_parent()] + 1;
+        self.lengths.insert(block_hash, height);
+        if height % STATE_CHECKPOINT_INTERVAL == 0 {
+            self.checkpoints.insert(block_hash, state.clone());
+        }
+        let parent_work = self
+            .works
+            .get(&block.get_parent())
+            .copied()
+            .unwrap_or_default();
+        self.works.insert(
             block_hash,
-            self.lengths.get(&block.get_parent()).unwrap_or(&0) + 1,
+            parent_work.saturating_add(block_work(&block.get_difficulty())),
         );
-        if self.lengths.get(&block_hash) > self.lengths.get(&self.tip) {
+        if block.get_parent() == self.tip {
             self.tip = block_hash;
+            self.longest_chain.push(block_hash);
+            self.state = state;
+            self.publish_tip();
+            return InsertResult::Extended;
         }
-        // Apply transactions to the state
-        for transaction in block.get_transactions() {
-            match self.state.apply_transaction(transaction) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Failed to apply transaction: {}", e),
+        if self.rank(&block_hash) <= self.rank(&self.tip) {
+            return InsertResult::SideBranch;
+        }
+        let old_tip = self.tip;
+        let common_ancestor = self.common_ancestor(old_tip, block_hash);
+        self.tip = block_hash;
+        self.longest_chain = self.walk_longest_chain();
+        self.state = state;
+        self.publish_tip();
+        InsertResult::Reorg {
+            old_tip,
+            common_ancestor,
+        }
+    }
+
+    /// Make a known block the tip whatever its work, rebuilding the state from the common ancestor
+    /// along its branch, and return what that did to the longest chain. Fails without moving the
+    /// tip if a transaction of the branch doesn't apply. This breaks consensus, it is only for
+    /// testing reorgs: the next block on a branch with more work moves the tip again.
+    pub fn force_tip(&mut self, block_hash: H256) -> Result<InsertResult, String> {
+        if !self.blocks.contains_key(&block_hash) {
+            return Err(format!("unknown block {}", block_hash));
+        }
+        if block_hash == self.tip {
+            return Ok(InsertResult::AlreadyKnown);
+        }
+        let old_tip = self.tip;
+        let common_ancestor = self.common_ancestor(old_tip, block_hash);
+        let mut state = self
+            .replay_to(&common_ancestor)
+            .map_err(|e| e.to_string())?;
+        let mut branch = Vec::new();
+        let mut current_hash = block_hash;
+        while current_hash != common_ancestor {
+            let block = &self.blocks[&current_hash];
+            branch.push((current_hash, block));
+            current_hash = block.get_parent();
+        }
+        for (hash, block) in branch.into_iter().rev() {
+            apply_block(&mut state, block).map_err(|e| format!("block {}: {}", hash, e))?;
+        }
+        self.tip = block_hash;
+        self.longest_chain = self.walk_longest_chain();
+        self.state = state;
+        self.publish_tip();
+        Ok(InsertResult::Reorg {
+            old_tip,
+            common_ancestor,
+        })
+    }
+
+    /// Get a channel that receives the hash of every new tip, whether the longest chain was
+    /// extended or reorganized
+    pub fn subscribe_tip(&mut self) -> Receiver<H256> {
+        let (sender, receiver) = unbounded();
+        self.tip_subscribers.push(sender);
+        receiver
+    }
+
+    /// Keep `tracker` set to the height of the tip from now on
+    pub fn track_best_height(&mut self, tracker: Arc<AtomicU32>) {
+        tracker.store(self.best_height(), Ordering::Relaxed);
+        self.best_height_tracker = Some(tracker);
+    }
+
+    /// Send the tip to the subscribers, forgetting those that dropped their receiver
+    fn publish_tip(&mut self) {
+        if let Some(tracker) = &self.best_height_tracker {
+            tracker.store(self.best_height(), Ordering::Relaxed);
+        }
+        let tip = self.tip;
+        self.tip_subscribers
+            .retain(|subscriber| subscriber.send(tip).is_ok());
+    }
+
+    /// Find the latest block that both given blocks descend from
+    fn common_ancestor(&self, mut a: H256, mut b: H256) -> H256 {
+        let height = |hash: &H256| self.lengths.get(hash).copied().unwrap_or_default();
+        while a != b {
+            if height(&a) >= height(&b) {
+                match self.blocks.get(&a) {
+                    Some(block) if height(&a) > 0 => a = block.get_parent(),
+                    _ => break,
+                }
+            } else {
+                match self.blocks.get(&b) {
+                    Some(block) => b = block.get_parent(),
+                    None => break,
+                }
             }
         }
+        a
+    }
+
+    /// Buffer a block whose parent is not known yet, received from `source`. Returns false if it
+    /// was already buffered.
+    pub fn add_orphan(&mut self, block: Block, source: SocketAddr) -> bool {
+        let block_hash = block.hash();
+        if self
+            .orphans
+            .iter()
+            .any(|(orphan, _)| orphan.hash() == block_hash)
+        {
+            return false;
+        }
+        if self.orphans.len() >= self.orphan_capacity {
+            self.orphans.pop_front();
+        }
+        self.orphans.push_back((block, source));
+        true
+    }
+
+    /// Keep at most `capacity` orphans from now on, dropping the oldest ones beyond it
+    pub fn set_orphan_capacity(&mut self, capacity: usize) {
+        self.orphan_capacity = capacity;
+        while self.orphans.len() > capacity {
+            self.orphans.pop_front();
+        }
+    }
+
+    /// Remove and return the buffered blocks whose parent is `parent`
+    pub fn take_orphans_of(&mut self, parent: &H256) -> Vec<Block> {
+        let (children, rest): (VecDeque<_>, VecDeque<_>) = self
+            .orphans
+            .drain(..)
+            .partition(|(orphan, _)| &orphan.get_parent() == parent);
+        self.orphans = rest;
+        children.into_iter().map(|(orphan, _)| orphan).collect()
+    }
+
+    /// Drop the buffered descendants of a block that was rejected, returning how many there were
+    pub fn discard_orphans_below(&mut self, block_hash: &H256) -> usize {
+        let mut discarded = 0;
+        let mut parents = vec![*block_hash];
+        while let Some(parent) = parents.pop() {
+            let children = self.take_orphans_of(&parent);
+            discarded += children.len();
+            parents.extend(children.iter().map(|child| child.hash()));
+        }
+        discarded
+    }
+
+    /// Get the number of known blocks, on any branch, genesis included
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Get the number of transactions in all known blocks, on any branch
+    pub fn total_transaction_count(&self) -> usize {
+        self.transaction_count
+    }
+
+    /// Get the number of buffered orphan blocks
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Get the number of buffered orphan blocks received from `source`
+    pub fn orphans_from(&self, source: &SocketAddr) -> usize {
+        self.orphans
+            .iter()
+            .filter(|(_, orphan_source)| orphan_source == source)
+            .count()
+    }
+
+    /// Save all known blocks into a data directory, the state is rebuilt from them when loading
+    pub fn save_to_path(&self, dir: &DataDir) -> io::Result<()> {
+        // parents are written before their children so that loading can simply re-insert in order
+        let mut blocks: Vec<(u32, &Block)> = self
+            .blocks
+            .iter()
+            .map(|(hash, block)| (self.lengths[hash], block))
+            .collect();
+        blocks.sort_by_key(|(length, _)| *length);
+        let blocks: Vec<&Block> = blocks.into_iter().map(|(_, block)| block).collect();
+        let chain = bincode::serialize(&blocks)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_checked(&dir.chain_path(), &chain)
+    }
+
+    /// Load a blockchain written by `save_to_path`, or a fresh one if the directory holds none.
+    /// Every block goes through the checks of a block from a peer. Fails with `InvalidData` if the
+    /// file doesn't match its checksum or a block fails them.
+    pub fn load_from_path(dir: &DataDir) -> io::Result<Self> {
+        Self::load_from_path_with_params(dir, ConsensusParams::default(), State::new())
+    }
+
+    /// Like `load_from_path`, for a chain following the given consensus parameters and whose
+    /// accounts start with the given balances
+    pub fn load_from_path_with_params(
+        dir: &DataDir,
+        params: ConsensusParams,
+        genesis_state: State,
+    ) -> io::Result<Self> {
+        let mut blockchain = Self::with_params(params, genesis_state);
+        let chain = match read_checked(&dir.chain_path())? {
+            Some(bytes) => bytes,
+            None => return Ok(blockchain),
+        };
+        let blocks: Vec<Block> = bincode::deserialize(&chain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for block in blocks.iter() {
+            if blockchain.contains_block(&block.hash()) {
+                continue;
+            }
+            // a file edited on disk is no more trusted than a peer
+            check_block(block, &blockchain.params)
+                .and_then(|_| blockchain.check_block_context(block))
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("block {}: {}", block.hash(), e),
+                    )
+                })?;
+            blockchain.insert(block);
+        }
+        Ok(blockchain)
     }
 
     /// Get the last block's hash of the longest chain
@@ -88,6 +610,61 @@ impl Blockchain {
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        self.longest_chain.clone()
+    }
+
+    /// Get at most `limit` hashes of the longest chain, starting at height `start`
+    pub fn blocks_in_longest_chain(&self, start: usize, limit: usize) -> &[H256] {
+        let start = start.min(self.longest_chain.len());
+        let end = start.saturating_add(limit).min(self.longest_chain.len());
+        &self.longest_chain[start..end]
+    }
+
+    /// Hashes of the longest chain to tell a peer where it diverges from ours: the tip, then the
+    /// blocks 1, 2, 4, 8, ... below it, and genesis last
+    pub fn block_locator(&self) -> Vec<H256> {
+        let tip_height = self.longest_chain.len() - 1;
+        let mut locator = vec![self.longest_chain[tip_height]];
+        let mut step = 1;
+        while step < tip_height {
+            locator.push(self.longest_chain[tip_height - step]);
+            step *= 2;
+        }
+        if tip_height > 0 {
+            locator.push(self.longest_chain[0]);
+        }
+        locator
+    }
+
+    /// Height of the first block of `locator` on the longest chain, or of genesis if there is none
+    fn locate(&self, locator: &[H256]) -> usize {
+        locator
+            .iter()
+            .find(|hash| self.is_in_longest_chain(hash))
+            .map(|hash| self.lengths[hash] as usize)
+            .unwrap_or(0)
+    }
+
+    /// Get at most `limit` headers of the longest chain that follow the first block of `locator`
+    /// on it, or that follow genesis if there is none
+    pub fn headers_after(&self, locator: &[H256], limit: usize) -> Vec<Header> {
+        self.blocks_in_longest_chain(self.locate(locator) + 1, limit)
+            .iter()
+            .map(|hash| self.blocks[hash].get_header().clone())
+            .collect()
+    }
+
+    /// Get at most `limit` blocks of the longest chain that follow the first block of `locator`
+    /// on it, or that follow genesis if there is none
+    pub fn blocks_after(&self, locator: &[H256], limit: usize) -> Vec<Block> {
+        self.blocks_in_longest_chain(self.locate(locator) + 1, limit)
+            .iter()
+            .map(|hash| self.blocks[hash].clone())
+            .collect()
+    }
+
+    /// Collect the longest chain by following parents from the tip back to genesis
+    fn walk_longest_chain(&self) -> Vec<H256> {
         let mut current_hash = self.tip;
         let mut longest_chain = Vec::new();
         while let Some(block) = self.blocks.get(&current_hash) {
@@ -98,6 +675,114 @@ impl Blockchain {
         longest_chain
     }
 
+    /// Get the cumulative work of a block and its ancestors
+    pub fn get_work(&self, block_hash: &H256) -> Option<u128> {
+        self.works.get(block_hash).copied()
+    }
+
+    /// Get the blocks no known block builds on, the tips of the competing branches, from the most
+    /// cumulative work to the least, the tip first
+    pub fn leaves(&self) -> Vec<H256> {
+        let parents: HashSet<H256> = self
+            .blocks
+            .iter()
+            .filter(|(hash, _)| self.lengths[hash] > 0)
+            .map(|(_, block)| block.get_parent())
+            .collect();
+        let mut leaves: Vec<H256> = self
+            .blocks
+            .keys()
+            .filter(|hash| !parents.contains(hash))
+            .copied()
+            .collect();
+        leaves.sort_by_key(|hash| std::cmp::Reverse(self.rank(hash)));
+        leaves
+    }
+
+    /// Order blocks by cumulative work, then by the smaller hash, the highest ranked is the tip
+    fn rank(&self, block_hash: &H256) -> (u128, std::cmp::Reverse<H256>) {
+        (self.works[block_hash], std::cmp::Reverse(*block_hash))
+    }
+
+    /// Get the state after a block, rebuilt from the closest checkpoint below it unless it is the
+    /// tip
+    pub fn get_state_after(&self, block_hash: &H256) -> Option<State> {
+        self.replay_to(block_hash).ok()
+    }
+
+    /// Rebuild the state after a block from the tip's state or the closest checkpoint on its
+    /// branch, applying the blocks in between
+    fn replay_to(&self, block_hash: &H256) -> Result<State, BlockError> {
+        let mut branch = Vec::new();
+        let mut current_hash = *block_hash;
+        let mut state = loop {
+            if current_hash == self.tip {
+                break self.state.clone();
+            }
+            if let Some(checkpoint) = self.checkpoints.get(&current_hash) {
+                break checkpoint.clone();
+            }
+            let block = self
+                .blocks
+                .get(&current_hash)
+                .ok_or(BlockError::UnknownParent(current_hash))?;
+            branch.push(block);
+            current_hash = block.get_parent();
+        };
+        for block in branch.iter().rev() {
+            apply_block(&mut state, block)?;
+        }
+        Ok(state)
+    }
+
+    /// Compute the state after a block that may not be inserted yet, failing if its parent is
+    /// unknown or one of its transactions doesn't apply
+    fn state_after_block(&self, block: &Block) -> Result<State, BlockError> {
+        let mut state = self.replay_to(&block.get_parent())?;
+        apply_block(&mut state, block)?;
+        Ok(state)
+    }
+
+    /// Check a block against the chain: its parent must be known, its difficulty the parent's,
+    /// every transaction must apply after the parent and its state root must be the one of the
+    /// state after them
+    pub fn check_block_context(&self, block: &Block) -> Result<(), BlockError> {
+        let parent = match self.get_block(&block.get_parent()) {
+            Some(parent) => parent,
+            None => return Err(BlockError::UnknownParent(block.get_parent())),
+        };
+        if block.get_difficulty() != parent.get_difficulty() {
+            return Err(BlockError::WrongDifficulty);
+        }
+        if self.state_after_block(block)?.state_root() != block.get_state_root() {
+            return Err(BlockError::WrongStateRoot);
+        }
+        Ok(())
+    }
+
+    /// Compute the state root a block has to commit to, or None if its parent is unknown or one of
+    /// its transactions doesn't apply
+    pub fn expected_state_root(&self, block: &Block) -> Option<H256> {
+        self.state_after_block(block)
+            .ok()
+            .map(|state| state.state_root())
+    }
+
+    /// Hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.longest_chain[0]
+    }
+
+    /// Get the height of the tip, genesis being at height 0
+    pub fn best_height(&self) -> u32 {
+        self.lengths[&self.tip]
+    }
+
+    /// Get the height of a block, genesis being at height 0
+    pub fn get_height(&self, block_hash: &H256) -> Option<u32> {
+        self.lengths.get(block_hash).copied()
+    }
+
     /// Retrieve a block from the blockchain by its hash
     pub fn get_block(&self, block_hash: &H256) -> Option<&Block> {
         self.blocks.get(block_hash)
@@ -113,17 +798,80 @@ impl Blockchain {
         self.blocks.contains_key(block_hash)
     }
 
+    /// Find a transaction with the given hash in any block
+    pub fn get_transaction(&self, tx_hash: &H256) -> Option<&SignedTransaction> {
+        let block_hash = self.tx_index.get(tx_hash)?.first()?;
+        self.blocks[block_hash]
+            .get_transactions()
+            .iter()
+            .find(|transaction| &transaction.hash() == tx_hash)
+    }
+
     /// Check if the blockchain contains a transaction with the given hash
     pub fn contains_transaction(&self, tx_hash: &H256) -> bool {
-        // Iterate over all blocks and check each transaction
-        for block in self.blocks.values() {
-            for transaction in block.get_transactions() {
-                if &transaction.hash() == tx_hash {
-                    return true;
-                }
-            }
+        self.tx_index.contains_key(tx_hash)
+    }
+
+    /// Find the block of the longest chain holding a transaction, returning its hash and height
+    pub fn get_transaction_block(&self, tx_hash: &H256) -> Option<(H256, u32)> {
+        self.tx_index
+            .get(tx_hash)?
+            .iter()
+            .find(|block_hash| self.is_in_longest_chain(block_hash))
+            .map(|block_hash| (*block_hash, self.lengths[block_hash]))
+    }
+
+    /// Get the transactions sent from an account on the longest chain, in chain order, each with
+    /// the hash of its block
+    pub fn transactions_by_sender(
+        &self,
+        address: &AccountAddress,
+    ) -> Vec<(H256, SignedTransaction)> {
+        let mut blocks: Vec<&H256> = match self.sender_index.get(address) {
+            Some(blocks) => blocks
+                .iter()
+                .filter(|block_hash| self.is_in_longest_chain(block_hash))
+                .collect(),
+            None => return Vec::new(),
+        };
+        blocks.sort_by_key(|block_hash| self.lengths[*block_hash]);
+        blocks
+            .into_iter()
+            .flat_map(|block_hash| {
+                self.blocks[block_hash]
+                    .get_transactions()
+                    .iter()
+                    .filter(|tx| !tx.is_coinbase() && tx.get_sender() == &address.to_string())
+                    .map(move |tx| (*block_hash, tx.clone()))
+            })
+            .collect()
+    }
+
+    /// Check if a block is the tip or one of its ancestors
+    fn is_in_longest_chain(&self, block_hash: &H256) -> bool {
+        match self.lengths.get(block_hash) {
+            Some(height) => self.longest_chain.get(*height as usize) == Some(block_hash),
+            None => false,
         }
-        false
+    }
+}
+
+/// Apply a block's transactions to `state`, leaving it unchanged if one of them fails
+fn apply_block(state: &mut State, block: &Block) -> Result<(), BlockError> {
+    state
+        .apply_block(block.get_transactions())
+        .map_err(|(index, error)| BlockError::InvalidTransaction { index, error })
+}
+
+/// Expected number of hashes to find a block below `difficulty`, computed from the top 128 bits
+/// of the target so it fits in a u128
+pub fn block_work(difficulty: &H256) -> u128 {
+    let target: [u8; 32] = difficulty.into();
+    let mut high = [0u8; 16];
+    high.copy_from_slice(&target[..16]);
+    match u128::from_be_bytes(high).checked_add(1) {
+        Some(divisor) => u128::MAX / divisor,
+        None => 1,
     }
 }
 
@@ -132,8 +880,22 @@ impl Blockchain {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::block::generate_random_block;
+    use crate::types::block::{generate_block_with_difficulty, generate_random_block};
     use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::state::BLOCK_REWARD;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A valid payment from the ICO account to a new account
+    fn ico_payment(nonce: u64) -> SignedTransaction {
+        let ico = key_pair::ico();
+        let receiver = key_pair::address(&key_pair::random());
+        SignedTransaction::new(
+            Transaction::new(key_pair::address(&ico), receiver, 1, 0, nonce),
+            &ico,
+        )
+    }
 
     #[test]
     fn insert_one() {
@@ -143,6 +905,637 @@ mod tests {
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn reinserted_block_applied_once() {
+        let ico = key_pair::ico();
+        let alice = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let pay_alice = SignedTransaction::new(
+            Transaction::new(key_pair::address(&ico), alice.to_string(), 100, 0, 0),
+            &ico,
+        );
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.get_content_mut().add_transactions(vec![pay_alice]);
+        assert_eq!(blockchain.insert(&block), InsertResult::Extended);
+        let count = blockchain.total_transaction_count();
+
+        // our own block echoed back by a peer, before and after the chain moved on
+        assert_eq!(blockchain.insert(&block), InsertResult::AlreadyKnown);
+        let child = generate_random_block(&block.hash());
+        blockchain.insert(&child);
+        assert_eq!(blockchain.insert(&block), InsertResult::AlreadyKnown);
+        assert_eq!(blockchain.tip(), child.hash());
+        assert_eq!(blockchain.get_state().get_balance(&alice), 100);
+        assert_eq!(blockchain.total_transaction_count(), count);
+    }
+
+    #[test]
+    fn headers_follow_the_locator() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..30 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let locator = blockchain.block_locator();
+        assert_eq!(locator[0], blockchain.tip());
+        assert_eq!(locator.last(), Some(&chain[0]));
+        assert!(locator.len() < chain.len());
+
+        let headers = blockchain.headers_after(&[chain[20], chain[25]], 5);
+        let hashes: Vec<H256> = headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(hashes, chain[21..26].to_vec());
+        // an unknown locator starts after genesis
+        let headers = blockchain.headers_after(&[H256::from([7; 32])], 100);
+        assert_eq!(headers.len(), 30);
+    }
+
+    #[test]
+    fn locator_gaps_double() {
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.block_locator(), vec![blockchain.genesis_hash()]);
+        for _ in 0..30 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let heights: Vec<u32> = blockchain
+            .block_locator()
+            .iter()
+            .map(|hash| blockchain.get_height(hash).unwrap())
+            .collect();
+        assert_eq!(heights, vec![30, 29, 28, 26, 22, 14, 0]);
+
+        // a peer on a fork gets the blocks after the last one we share
+        let mut fork = blockchain.block_locator()[3..].to_vec();
+        fork.insert(0, H256::from([7; 32]));
+        let blocks = blockchain.blocks_after(&fork, 3);
+        let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+        assert_eq!(hashes, chain[27..30].to_vec());
+        assert!(blockchain.blocks_after(&[blockchain.tip()], 10).is_empty());
+    }
+
+    #[test]
+    fn subscribers_receive_new_tips() {
+        let mut blockchain = Blockchain::new();
+        let tips = blockchain.subscribe_tip();
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block);
+        assert_eq!(tips.try_recv(), Ok(block.hash()));
+
+        // a side branch doesn't move the tip
+        let side = loop {
+            let side = generate_random_block(&block.get_parent());
+            if side.hash() > block.hash() {
+                break side;
+            }
+        };
+        blockchain.insert(&side);
+        assert!(tips.try_recv().is_err());
+
+        drop(tips);
+        blockchain.insert(&generate_random_block(&blockchain.tip()));
+        assert!(blockchain.tip_subscribers.is_empty());
+    }
+
+    #[test]
+    fn leaves_are_the_branch_tips() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.leaves(), vec![genesis_hash]);
+        let first = generate_random_block(&genesis_hash);
+        let second = generate_random_block(&first.hash());
+        let side = generate_random_block(&genesis_hash);
+        for block in [&first, &second, &side] {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.leaves(), vec![second.hash(), side.hash()]);
+    }
+
+    #[test]
+    fn counts_follow_inserted_blocks() {
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.block_count(), 1);
+        assert_eq!(blockchain.total_transaction_count(), 0);
+        // each block goes on genesis or on a block without transactions
+        let with_transactions = |parent: &H256, count: u64| {
+            let mut block = generate_random_block(parent);
+            block
+                .get_content_mut()
+                .add_transactions((0..count).map(ico_payment).collect());
+            block
+        };
+        let genesis_hash = blockchain.tip();
+        let first = with_transactions(&genesis_hash, 3);
+        blockchain.insert(&first);
+        blockchain.insert(&with_transactions(&first.hash(), 0));
+        assert_eq!(blockchain.block_count(), 3);
+        assert_eq!(blockchain.total_transaction_count(), 3);
+
+        // side branches count too, a block inserted twice doesn't
+        blockchain.insert(&with_transactions(&genesis_hash, 5));
+        blockchain.insert(&first);
+        assert_eq!(blockchain.block_count(), 4);
+        assert_eq!(blockchain.total_transaction_count(), 8);
+    }
+
+    #[test]
+    fn transaction_block_follows_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let transaction = ico_payment(0);
+        let mut first = generate_random_block(&genesis_hash);
+        first
+            .get_content_mut()
+            .add_transactions(vec![transaction.clone()]);
+        blockchain.insert(&first);
+        assert!(blockchain.contains_transaction(&transaction.hash()));
+        assert_eq!(
+            blockchain.get_transaction_block(&transaction.hash()),
+            Some((first.hash(), 1))
+        );
+
+        // a longer branch without the transaction leaves it unconfirmed
+        let second = generate_random_block(&genesis_hash);
+        let third = generate_random_block(&second.hash());
+        blockchain.insert(&second);
+        blockchain.insert(&third);
+        assert!(blockchain.contains_transaction(&transaction.hash()));
+        assert_eq!(blockchain.get_transaction_block(&transaction.hash()), None);
+    }
+    #[test]
+    fn transactions_by_sender_in_chain_order() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress::new(key_pair::address(&ico));
+        let alice = key_pair::random();
+        let alice_address = AccountAddress::new(key_pair::address(&alice));
+        let pay = |key: &Ed25519KeyPair, value: i64, nonce: u64| {
+            let receiver = key_pair::address(&key_pair::random());
+            let sender = key_pair::address(key);
+            SignedTransaction::new(Transaction::new(sender, receiver, value, 0, nonce), key)
+        };
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut first = generate_random_block(&genesis_hash);
+        let funding = SignedTransaction::new(
+            Transaction::new(ico_address.to_string(), alice_address.to_string(), 50, 0, 0),
+            &ico,
+        );
+        first
+            .get_content_mut()
+            .add_transactions(vec![funding.clone(), pay(&ico, 5, 1)]);
+        let mut second = generate_random_block(&first.hash());
+        let (from_alice, from_ico) = (pay(&alice, 10, 0), pay(&ico, 5, 2));
+        second
+            .get_content_mut()
+            .add_transactions(vec![from_alice.clone(), from_ico.clone()]);
+        blockchain.insert(&first);
+        blockchain.insert(&second);
+
+        let history = |blockchain: &Blockchain, address: &AccountAddress| -> Vec<(H256, H256)> {
+            blockchain
+                .transactions_by_sender(address)
+                .iter()
+                .map(|(block_hash, tx)| (*block_hash, tx.hash()))
+                .collect()
+        };
+        assert_eq!(
+            history(&blockchain, &ico_address),
+            vec![
+                (first.hash(), funding.hash()),
+                (first.hash(), first.get_transactions()[1].hash()),
+                (second.hash(), from_ico.hash()),
+            ]
+        );
+        assert_eq!(
+            history(&blockchain, &alice_address),
+            vec![(second.hash(), from_alice.hash())]
+        );
+        assert!(history(&blockchain, &AccountAddress::new("nobody".to_string())).is_empty());
+
+        // transactions on a branch that lost are left out
+        let side = generate_random_block(&genesis_hash);
+        let side_tip = generate_random_block(&side.hash());
+        let longer = generate_random_block(&side_tip.hash());
+        for block in [&side, &side_tip, &longer] {
+            blockchain.insert(block);
+        }
+        assert!(history(&blockchain, &ico_address).is_empty());
+    }
+
+    #[test]
+    fn orphans_taken_by_parent() {
+        let mut blockchain = Blockchain::new();
+        let parent = generate_random_block(&blockchain.tip());
+        let first = generate_random_block(&parent.hash());
+        let second = generate_random_block(&parent.hash());
+        let unrelated = generate_random_block(&first.hash());
+        let source = "127.0.0.1:12321".parse().unwrap();
+        assert!(blockchain.add_orphan(first.clone(), source));
+        assert!(!blockchain.add_orphan(first.clone(), source));
+        assert!(blockchain.add_orphan(second.clone(), source));
+        assert!(blockchain.add_orphan(unrelated.clone(), source));
+
+        let children: Vec<H256> = blockchain
+            .take_orphans_of(&parent.hash())
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        assert_eq!(children, vec![first.hash(), second.hash()]);
+        assert_eq!(blockchain.orphan_count(), 1);
+        assert!(blockchain.take_orphans_of(&parent.hash()).is_empty());
+    }
+
+    #[test]
+    fn orphan_buffer_is_bounded() {
+        let mut blockchain = Blockchain::new();
+        let (first, second) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        let oldest = generate_random_block(&H256::from([1; 32]));
+        blockchain.add_orphan(oldest.clone(), first);
+        for _ in 0..ORPHAN_CAPACITY {
+            blockchain.add_orphan(generate_random_block(&H256::from([2; 32])), second);
+        }
+        assert_eq!(blockchain.orphan_count(), ORPHAN_CAPACITY);
+        assert_eq!(blockchain.orphans_from(&first), 0);
+        assert!(blockchain.take_orphans_of(&oldest.get_parent()).is_empty());
+
+        // a smaller capacity drops the oldest right away
+        blockchain.add_orphan(oldest.clone(), first);
+        blockchain.set_orphan_capacity(3);
+        assert_eq!(blockchain.orphan_count(), 3);
+        assert_eq!(blockchain.orphans_from(&first), 1);
+        for _ in 0..5 {
+            blockchain.add_orphan(generate_random_block(&H256::from([2; 32])), second);
+        }
+        assert_eq!(blockchain.orphan_count(), 3);
+        assert_eq!(blockchain.orphans_from(&second), 3);
+    }
+
+    #[test]
+    fn reorg_rebuilds_state_from_winning_branch() {
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let alice = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let bob = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let pay = |receiver: &AccountAddress, value: i64| {
+            SignedTransaction::new(
+                Transaction::new(ico_address.clone(), receiver.to_string(), value, 0, 0),
+                &ico,
+            )
+        };
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut to_alice = generate_random_block(&genesis_hash);
+        to_alice
+            .get_content_mut()
+            .add_transactions(vec![pay(&alice, 100)]);
+        assert_eq!(blockchain.insert(&to_alice), InsertResult::Extended);
+
+        // a competing branch of the same length and a larger hash doesn't touch the state
+        let to_bob = loop {
+            let mut to_bob = generate_random_block(&genesis_hash);
+            to_bob
+                .get_content_mut()
+                .add_transactions(vec![pay(&bob, 50)]);
+            if to_bob.hash() > to_alice.hash() {
+                break to_bob;
+            }
+        };
+        assert_eq!(blockchain.insert(&to_bob), InsertResult::SideBranch);
+        assert_eq!(blockchain.tip(), to_alice.hash());
+        assert_eq!(blockchain.get_state().get_balance(&bob), 0);
+
+        // once it grows longer it wins, and only its transactions count
+        let extension = generate_random_block(&to_bob.hash());
+        assert_eq!(
+            blockchain.insert(&extension),
+            InsertResult::Reorg {
+                old_tip: to_alice.hash(),
+                common_ancestor: genesis_hash,
+            }
+        );
+        assert_eq!(blockchain.tip(), extension.hash());
+        assert_eq!(blockchain.get_state().get_balance(&alice), 0);
+        assert_eq!(blockchain.get_state().get_balance(&bob), 50);
+    }
+
+    #[test]
+    fn block_with_failing_transaction_rejected() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let valid = {
+            let mut block = generate_random_block(&genesis_hash);
+            block
+                .get_content_mut()
+                .add_transactions(vec![ico_payment(0)]);
+            block
+        };
+        assert_eq!(blockchain.insert(&valid), InsertResult::Extended);
+
+        // the second payment reuses the nonce of the first, on the tip or on a side branch
+        for (parent, nonce) in [(valid.hash(), 1), (genesis_hash, 0)] {
+            let mut block = generate_random_block(&parent);
+            block
+                .get_content_mut()
+                .add_transactions(vec![ico_payment(nonce), ico_payment(nonce)]);
+            assert!(matches!(
+                blockchain.check_block_context(&block),
+                Err(BlockError::InvalidTransaction { index: 1, .. })
+            ));
+            assert_eq!(blockchain.expected_state_root(&block), None);
+            assert_eq!(blockchain.insert(&block), InsertResult::Rejected);
+            assert!(!blockchain.contains_block(&block.hash()));
+        }
+        assert_eq!(blockchain.tip(), valid.hash());
+        assert_eq!(blockchain.total_transaction_count(), 1);
+    }
+
+    #[test]
+    fn reorg_past_a_checkpoint_applies_the_winning_branch() {
+        let mut blockchain = Blockchain::new();
+        let ico = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        for nonce in 0..STATE_CHECKPOINT_INTERVAL as u64 + 2 {
+            let mut block = generate_random_block(&blockchain.tip());
+            block
+                .get_content_mut()
+                .add_transactions(vec![ico_payment(nonce)]);
+            assert_eq!(blockchain.insert(&block), InsertResult::Extended);
+        }
+        let old_tip = blockchain.tip();
+
+        // an empty branch forking below the checkpoint outgrows the chain
+        let fork = blockchain.all_blocks_in_longest_chain()[STATE_CHECKPOINT_INTERVAL as usize - 1];
+        let mut branch_tip = fork;
+        for _ in 0..4 {
+            let block = generate_random_block(&branch_tip);
+            branch_tip = block.hash();
+            blockchain.insert(&block);
+        }
+        assert_eq!(blockchain.tip(), branch_tip);
+        let nonce = |state: &State| state.get_account(&ico).unwrap().get_nonce();
+        assert_eq!(
+            nonce(blockchain.get_state()),
+            STATE_CHECKPOINT_INTERVAL as u64 - 1
+        );
+        assert_eq!(
+            blockchain.get_state().state_root(),
+            blockchain.get_state_after(&fork).unwrap().state_root()
+        );
+        // the losing branch is still rebuilt from its checkpoint
+        let old_state = blockchain.get_state_after(&old_tip).unwrap();
+        assert_eq!(nonce(&old_state), STATE_CHECKPOINT_INTERVAL as u64 + 2);
+    }
+
+    #[test]
+    fn most_work_wins_over_most_blocks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy = generate_random_block(&genesis_hash);
+        let easier = generate_random_block(&easy.hash());
+        blockchain.insert(&easy);
+        blockchain.insert(&easier);
+
+        // a single block with a much smaller target is worth more than two default blocks
+        let target = hex!("00000010ffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+        let hard = generate_block_with_difficulty(&genesis_hash, target.into());
+        assert!(matches!(
+            blockchain.insert(&hard),
+            InsertResult::Reorg { .. }
+        ));
+        assert_eq!(blockchain.tip(), hard.hash());
+        assert_eq!(blockchain.best_height(), 1);
+        assert!(blockchain.get_work(&hard.hash()) > blockchain.get_work(&easier.hash()));
+    }
+
+    #[test]
+    fn equal_work_tip_is_the_smaller_hash() {
+        let genesis_hash = Blockchain::new().tip();
+        let first = generate_random_block(&genesis_hash);
+        let second = generate_random_block(&genesis_hash);
+        let (smaller, larger) = if first.hash() < second.hash() {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&larger);
+        assert_eq!(
+            blockchain.insert(&smaller),
+            InsertResult::Reorg {
+                old_tip: larger.hash(),
+                common_ancestor: genesis_hash,
+            }
+        );
+        assert_eq!(blockchain.tip(), smaller.hash());
+
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&smaller);
+        assert_eq!(blockchain.insert(&larger), InsertResult::SideBranch);
+        assert_eq!(blockchain.tip(), smaller.hash());
+        assert_eq!(blockchain.leaves(), vec![smaller.hash(), larger.hash()]);
+
+        // inserting the tip again doesn't move it
+        assert_eq!(blockchain.insert(&smaller), InsertResult::AlreadyKnown);
+    }
+
+    #[test]
+    fn force_tip_rebuilds_from_the_common_ancestor() {
+        let ico = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        let paying = |parent: &H256, nonce: u64| {
+            let mut block = generate_random_block(parent);
+            block
+                .get_content_mut()
+                .add_transactions(vec![ico_payment(nonce)]);
+            block
+        };
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let first = paying(&genesis_hash, 0);
+        let second = paying(&first.hash(), 1);
+        let side = paying(&genesis_hash, 0);
+        for block in [&first, &second, &side] {
+            blockchain.insert(block);
+        }
+        let nonce = |blockchain: &Blockchain| {
+            blockchain
+                .get_state()
+                .get_account(&ico)
+                .unwrap()
+                .get_nonce()
+        };
+
+        assert_eq!(
+            blockchain.force_tip(side.hash()),
+            Ok(InsertResult::Reorg {
+                old_tip: second.hash(),
+                common_ancestor: genesis_hash,
+            })
+        );
+        assert_eq!(nonce(&blockchain), 1);
+        assert_eq!(blockchain.force_tip(second.hash()).map(|_| ()), Ok(()));
+        assert_eq!(nonce(&blockchain), 2);
+
+        // a block that slipped past the checks fails the rebuild, and the tip stays
+        let invalid = paying(&side.hash(), 5);
+        blockchain.blocks.insert(invalid.hash(), invalid.clone());
+        blockchain.lengths.insert(invalid.hash(), 2);
+        assert!(blockchain.force_tip(invalid.hash()).is_err());
+        assert_eq!(blockchain.tip(), second.hash());
+        assert_eq!(nonce(&blockchain), 2);
+    }
+
+    #[test]
+    fn balance_series_tracks_funding_and_spending() {
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let alice = key_pair::random();
+        let alice_address = base64::encode(alice.public_key());
+        let bob_address = base64::encode(key_pair::random().public_key());
+
+        let mut blockchain = Blockchain::new();
+        let mut funding = generate_random_block(&blockchain.tip());
+        funding
+            .get_content_mut()
+            .add_transactions(vec![SignedTransaction::new(
+                Transaction::new(ico_address, alice_address.clone(), 100, 0, 0),
+                &ico,
+            )]);
+        blockchain.insert(&funding);
+        let mut spending = generate_random_block(&funding.hash());
+        spending
+            .get_content_mut()
+            .add_transactions(vec![SignedTransaction::new(
+                Transaction::new(alice_address.clone(), bob_address, 30, 0, 0),
+                &alice,
+            )]);
+        blockchain.insert(&spending);
+
+        let alice_address = AccountAddress::new(alice_address);
+        let series = blockchain.balance_series(&alice_address);
+        assert_eq!(series, vec![(0, 0), (1, 100), (2, 70)]);
+
+        // after a reorg, only the blocks of the winning branch count
+        let side = generate_random_block(&funding.hash());
+        blockchain.insert(&side);
+        blockchain.insert(&generate_random_block(&side.hash()));
+        let series = blockchain.balance_series(&alice_address);
+        assert_eq!(series, vec![(0, 0), (1, 100), (2, 100), (3, 100)]);
+    }
+
+    #[test]
+    fn balance_history_keeps_changes() {
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let alice = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let mut blockchain = Blockchain::new();
+        // alice is paid at heights 2 and 4, the other blocks leave her alone
+        for (height, value) in [(1, 0), (2, 10), (3, 0), (4, 5), (5, 0)] {
+            let mut block = generate_random_block(&blockchain.tip());
+            if value > 0 {
+                let nonce = blockchain
+                    .get_state()
+                    .get_account(&AccountAddress::new(ico_address.clone()))
+                    .unwrap()
+                    .get_nonce();
+                block
+                    .get_content_mut()
+                    .add_transactions(vec![SignedTransaction::new(
+                        Transaction::new(ico_address.clone(), alice.to_string(), value, 0, nonce),
+                        &ico,
+                    )]);
+            }
+            blockchain.insert(&block);
+            assert_eq!(blockchain.best_height(), height);
+        }
+
+        assert_eq!(
+            blockchain.balance_history(&alice, 0, 5),
+            vec![(0, 0), (2, 10), (4, 15)]
+        );
+        assert_eq!(
+            blockchain.balance_history(&alice, 3, 5),
+            vec![(3, 10), (4, 15)]
+        );
+        assert_eq!(
+            blockchain.balance_history(&alice, 1, 3),
+            vec![(1, 0), (2, 10)]
+        );
+        assert_eq!(blockchain.balance_history(&alice, 6, 9), vec![]);
+    }
+
+    #[test]
+    fn coinbase_rewards_miner_per_block() {
+        let miner = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let mut blockchain = Blockchain::new();
+        for height in 1..=3u64 {
+            let mut block = generate_random_block(&blockchain.tip());
+            block
+                .get_content_mut()
+                .add_transactions(vec![SignedTransaction::coinbase(
+                    miner.to_string(),
+                    BLOCK_REWARD as i64,
+                    height,
+                )]);
+            blockchain.insert(&block);
+            assert_eq!(
+                blockchain.get_state().get_balance(&miner),
+                BLOCK_REWARD * height as u128
+            );
+        }
+    }
+
+    #[test]
+    fn custom_params_take_effect() {
+        let params = ConsensusParams {
+            difficulty: [0xff; 32].into(),
+            block_reward: 50,
+            ico_balance: 7,
+            ..ConsensusParams::default()
+        };
+        let mut blockchain =
+            Blockchain::with_params(params.clone(), State::with_params(&params).unwrap());
+        let genesis_hash = blockchain.tip();
+        assert_ne!(genesis_hash, Block::get_genesis_block().hash());
+        assert_eq!(
+            blockchain
+                .get_block(&genesis_hash)
+                .unwrap()
+                .get_difficulty(),
+            params.difficulty
+        );
+        let ico = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        assert_eq!(blockchain.get_state().get_balance(&ico), 7);
+
+        let miner = AccountAddress::new("miner".to_string());
+        let paying = |reward: u128| {
+            let mut block = Block::with_difficulty(genesis_hash, params.difficulty);
+            block
+                .get_content_mut()
+                .add_transactions(vec![SignedTransaction::coinbase(
+                    miner.to_string(),
+                    reward as i64,
+                    1,
+                )]);
+            block
+        };
+        // the default reward no longer pays anything
+        let mut state = blockchain.get_state().clone();
+        assert_eq!(
+            state.apply_block(paying(BLOCK_REWARD).get_transactions()),
+            Err((0, StateError::WrongReward(BLOCK_REWARD as i64)))
+        );
+        let mut block = paying(50);
+        block.set_state_root(blockchain.expected_state_root(&block).unwrap());
+        assert_eq!(blockchain.insert(&block), InsertResult::Extended);
+        assert_eq!(blockchain.get_state().get_balance(&miner), 50);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
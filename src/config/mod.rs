@@ -0,0 +1,135 @@
+use crate::types::hash::H256;
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Proof-of-work target of every block unless configured otherwise
+pub const DEFAULT_DIFFICULTY: [u8; 32] =
+    hex!("000010ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+/// Amount credited to the miner by the coinbase transaction of each block
+pub const DEFAULT_BLOCK_REWARD: u128 = 10;
+/// Most transactions in a block besides its coinbase
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 20;
+/// Most bytes of encoded transactions in a block besides its coinbase, a transfer between two
+/// accounts takes 240
+pub const DEFAULT_MAX_BLOCK_WEIGHT: usize = 8000;
+/// Balance of the built-in ICO account at genesis
+pub const DEFAULT_ICO_BALANCE: u128 = 200000;
+/// Smallest value a transfer may move, anything below is rejected as dust
+pub const DEFAULT_DUST_THRESHOLD: u128 = 1;
+
+/// Parameters all nodes of a network must agree on. They are read from a JSON object, where
+/// missing fields keep their default and the difficulty is a hex string, e.g.
+/// `{"difficulty": "00ff...", "block_reward": 50}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsensusParams {
+    /// Target a block's hash must not exceed, also the difficulty of the genesis block
+    #[serde(with = "hex_hash")]
+    pub difficulty: H256,
+    pub block_reward: u128,
+    /// Miners fill blocks up to it, peers reject blocks with more transactions
+    pub max_block_size: usize,
+    /// Like `max_block_size`, for the summed `serialized_size` of the transactions
+    pub max_block_weight: usize,
+    pub ico_balance: u128,
+    /// Transfers of a smaller value are invalid
+    pub dust_threshold: u128,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            difficulty: DEFAULT_DIFFICULTY.into(),
+            block_reward: DEFAULT_BLOCK_REWARD,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_block_weight: DEFAULT_MAX_BLOCK_WEIGHT,
+            ico_balance: DEFAULT_ICO_BALANCE,
+            dust_threshold: DEFAULT_DUST_THRESHOLD,
+        }
+    }
+}
+
+impl ConsensusParams {
+    /// Read the parameters from a JSON file
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        let params: Self = serde_json::from_str(&contents)
+            .map_err(|e| format!("Error parsing {}: {}", path.display(), e))?;
+        if params.max_block_size == 0 {
+            return Err(format!(
+                "{}: max_block_size must be at least 1",
+                path.display()
+            ));
+        }
+        if params.max_block_weight == 0 {
+            return Err(format!(
+                "{}: max_block_weight must be at least 1",
+                path.display()
+            ));
+        }
+        Ok(params)
+    }
+}
+
+/// Parse a difficulty target written as 64 hex digits, as in the `difficulty` field
+pub fn parse_difficulty(hex_str: &str) -> Result<H256, String> {
+    let bytes = hex::decode(hex_str).map_err(|e| format!("invalid difficulty: {}", e))?;
+    if bytes.len() != 32 {
+        return Err(format!(
+            "invalid difficulty: expected 32 bytes, got {}",
+            bytes.len()
+        ));
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash.into())
+}
+
+/// (De)serialize a hash as a hex string rather than an array of bytes
+mod hex_hash {
+    use crate::types::hash::H256;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &H256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hash.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<H256, D::Error> {
+        let hex_str = String::deserialize(deserializer)?;
+        super::parse_difficulty(&hex_str).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::test_data_dir;
+
+    #[test]
+    fn missing_fields_keep_defaults() {
+        let dir = test_data_dir("config");
+        let path = dir.root().join("consensus.json");
+        let difficulty = "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+        std::fs::write(
+            &path,
+            format!(r#"{{"difficulty": "{}", "block_reward": 50}}"#, difficulty),
+        )
+        .unwrap();
+        let params = ConsensusParams::from_file(&path).unwrap();
+        assert_eq!(params.difficulty.to_string(), difficulty);
+        assert_eq!(params.block_reward, 50);
+        assert_eq!(params.max_block_size, DEFAULT_MAX_BLOCK_SIZE);
+        assert_eq!(params.max_block_weight, DEFAULT_MAX_BLOCK_WEIGHT);
+        assert_eq!(params.ico_balance, DEFAULT_ICO_BALANCE);
+        assert_eq!(params.dust_threshold, DEFAULT_DUST_THRESHOLD);
+
+        std::fs::write(&path, r#"{"difficulty": "00ff"}"#).unwrap();
+        assert!(ConsensusParams::from_file(&path).is_err());
+
+        assert_eq!(parse_difficulty(difficulty).unwrap(), params.difficulty);
+        assert!(parse_difficulty("00ff").is_err());
+        assert!(parse_difficulty(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,66 +1,227 @@
-use log::info;
+use log::{debug, error, info};
+use rand::Rng;
+use ring::signature::Ed25519KeyPair;
+use std::collections::HashMap;
 use std::thread;
 use std::time;
 
+use crate::blockchain::Blockchain;
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::types::hash::Hashable;
+use crate::types::key_pair;
 use crate::types::mempool::Mempool;
+use crate::types::state::{AccountAddress, State};
 use crate::types::transaction::{SignedTransaction, Transaction};
 use std::sync::{Arc, Mutex};
 
+/// Smallest value of a generated transaction unless configured otherwise
+pub const DEFAULT_MIN_VALUE: i64 = 1;
+/// Largest value of a generated transaction unless configured otherwise
+pub const DEFAULT_MAX_VALUE: i64 = 5;
+
 #[derive(Clone)]
-pub struct TransactionGenerator {}
+pub struct TransactionGenerator {
+    senders: Arc<Vec<Ed25519KeyPair>>, // empty to send from the ICO account
+    next_nonces: HashMap<String, u64>, // nonce after the last transaction sent by each sender
+    turn: usize,
+    min_value: i64,
+    max_value: i64,
+}
+
+impl Default for TransactionGenerator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_VALUE, DEFAULT_MAX_VALUE).unwrap()
+    }
+}
 
 impl TransactionGenerator {
-    pub fn new() -> Self {
-        Self {}
+    /// A generator sending values between `min_value` and `max_value`, inclusive. Values are not
+    /// capped by the sender's balance, so a range above it yields transactions the mempool
+    /// rejects.
+    pub fn new(min_value: i64, max_value: i64) -> Result<Self, String> {
+        if min_value < 1 {
+            return Err(format!(
+                "minimum transaction value {} is not positive",
+                min_value
+            ));
+        }
+        if min_value > max_value {
+            return Err(format!(
+                "minimum transaction value {} exceeds the maximum {}",
+                min_value, max_value
+            ));
+        }
+        Ok(Self {
+            senders: Arc::new(Vec::new()),
+            next_nonces: HashMap::new(),
+            turn: 0,
+            min_value,
+            max_value,
+        })
+    }
+
+    /// Send from each of the given accounts in turn, instead of the ICO account
+    pub fn with_senders(mut self, senders: Vec<Ed25519KeyPair>) -> Self {
+        self.senders = Arc::new(senders);
+        self
     }
 
-    // This function needs access to the network server handle and the mempool
-    pub fn start(self, theta: u64, network: NetworkServerHandle, mempool: Arc<Mutex<Mempool>>) {
+    // This function needs access to the network server handle, the mempool and the blockchain
+    pub fn start(
+        self,
+        theta: u64,
+        network: NetworkServerHandle,
+        mempool: Arc<Mutex<Mempool>>,
+        blockchain: Arc<Mutex<Blockchain>>,
+    ) {
         thread::Builder::new()
             .name("transaction-generator".to_string())
             .spawn(move || {
-                self.generate_transactions(theta, network, mempool);
+                self.generate_transactions(theta, network, mempool, blockchain);
             })
             .unwrap();
         info!("Transaction generator started");
     }
 
     fn generate_transactions(
-        &self,
+        mut self,
         theta: u64,
         network: NetworkServerHandle,
         mempool: Arc<Mutex<Mempool>>,
+        blockchain: Arc<Mutex<Blockchain>>,
     ) {
-        let mut nonce: u64 = 0;
+        if self.senders.is_empty() {
+            match key_pair::ico_key_pair() {
+                Ok(ico) => self.senders = Arc::new(vec![ico]),
+                Err(e) => {
+                    error!("Transaction generator stopped: {}", e);
+                    return;
+                }
+            }
+        }
         loop {
-            println!("attempt to generate transaction from ICO");
-            let signed_transaction =
-                SignedTransaction::get_random_signed_transaction_from_ico(nonce);
-            println!("generated random transaction from ICO");
-            println!(
-                "Signature Verify: {}",
-                signed_transaction.verify_signed_transaction()
-            );
-            println!("{}", signed_transaction.get_sender());
-            println!("nonce: {}", signed_transaction.get_nonce());
-            // Lock the mutex to get access to the mempool.
-            let mut mempool_guard = mempool.lock().unwrap();
-            // Now you can add the transaction to the mempool.
-            mempool_guard.add_transaction(signed_transaction.clone());
-            drop(mempool_guard); // Explicitly drop the lock if you want to release it here
-
-            network.broadcast(Message::NewTransactionHashes(vec![
-                signed_transaction.hash()
-            ]));
+            let generated = self.next_transaction(blockchain.lock().unwrap().get_state());
+            if let Some(signed_transaction) = generated {
+                let sender = signed_transaction.get_sender().clone();
+                if mempool
+                    .lock()
+                    .unwrap()
+                    .add_transaction(signed_transaction.clone())
+                {
+                    let hash = signed_transaction.hash();
+                    let peers = network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                    debug!(
+                        "Generated {} announced to {} peers",
+                        signed_transaction, peers
+                    );
+                } else {
+                    // start over from the sender's nonce in the state
+                    self.next_nonces.remove(&sender);
+                }
+            }
 
             if theta != 0 {
                 let interval = time::Duration::from_millis(10 * theta);
                 thread::sleep(interval);
             }
-            nonce = nonce + 1;
         }
     }
+
+    /// Sign a transfer within the value range to a random account from the next sender with a
+    /// balance.
+    /// Its nonce follows the sender's last transaction, or its account in `state` if that is
+    /// ahead. Returns None if no sender has a balance.
+    pub fn next_transaction(&mut self, state: &State) -> Option<SignedTransaction> {
+        for _ in 0..self.senders.len() {
+            let key = &self.senders[self.turn % self.senders.len()];
+            self.turn = self.turn.wrapping_add(1);
+            let sender = key_pair::address(key);
+            let account = match state.get_account(&AccountAddress::new(sender.clone())) {
+                Some(account) if account.get_balance() > 0 => account,
+                _ => continue,
+            };
+            let nonce = self
+                .next_nonces
+                .get(&sender)
+                .copied()
+                .unwrap_or_default()
+                .max(account.get_nonce());
+            let value = rand::thread_rng().gen_range(self.min_value..=self.max_value);
+            let receiver = key_pair::address(&key_pair::random());
+            let transaction = Transaction::new(sender.clone(), receiver, value, 0, nonce);
+            self.next_nonces.insert(sender, nonce + 1);
+            return Some(SignedTransaction::new(transaction, key));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn generated_transactions_are_valid() {
+        let mut blockchain = Blockchain::new();
+        let ico = key_pair::ico();
+        let mut generator = TransactionGenerator::default().with_senders(vec![key_pair::ico()]);
+        let mut state = blockchain.get_state().clone();
+        let mut block = generate_random_block(&blockchain.tip());
+        for _ in 0..3 {
+            let tx = generator.next_transaction(&state).unwrap();
+            assert!(state.is_transaction_valid(&tx));
+            state.apply_transaction(&tx).unwrap();
+            block.get_content_mut().add_transactions(vec![tx]);
+        }
+        blockchain.insert(&block);
+
+        // a new generator picks up the ICO's nonce from the chain
+        let mut generator = TransactionGenerator::default().with_senders(vec![ico]);
+        let tx = generator.next_transaction(blockchain.get_state()).unwrap();
+        assert_eq!(tx.get_nonce(), 3);
+        assert!(blockchain.get_state().is_transaction_valid(&tx));
+    }
+
+    #[test]
+    fn senders_take_turns() {
+        let mut state = State::new();
+        let (alice, bob, broke) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let addresses = [key_pair::address(&alice), key_pair::address(&bob)];
+        for address in &addresses {
+            state.add_account_with_balance(AccountAddress::new(address.clone()), 100);
+        }
+        let mut generator = TransactionGenerator::default().with_senders(vec![alice, broke, bob]);
+        for round in 0..2 {
+            for address in &addresses {
+                let tx = generator.next_transaction(&state).unwrap();
+                assert_eq!(tx.get_sender(), address);
+                assert_eq!(tx.get_nonce(), round);
+                assert!(tx.verify_signed_transaction());
+            }
+        }
+        assert!(TransactionGenerator::default()
+            .next_transaction(&state)
+            .is_none());
+    }
+
+    #[test]
+    fn values_stay_within_range() {
+        let mut generator = TransactionGenerator::new(40, 42)
+            .unwrap()
+            .with_senders(vec![key_pair::ico()]);
+        let state = State::new();
+        for _ in 0..50 {
+            let value = generator.next_transaction(&state).unwrap().get_value();
+            assert!((40..=42).contains(&value), "{} out of range", value);
+        }
+        let mut generator = TransactionGenerator::new(7, 7)
+            .unwrap()
+            .with_senders(vec![key_pair::ico()]);
+        assert_eq!(generator.next_transaction(&state).unwrap().get_value(), 7);
+
+        assert!(TransactionGenerator::new(6, 5).is_err());
+        assert!(TransactionGenerator::new(0, 5).is_err());
+    }
 }
@@ -4,48 +4,61 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod config;
 pub mod generator;
+pub mod metrics;
 pub mod miner;
 pub mod network;
+pub mod persistence;
+pub mod shutdown;
 pub mod types;
+pub mod wallet;
 
 use api::Server as ApiServer;
 use blockchain::Blockchain;
 use clap::clap_app;
+use config::ConsensusParams;
+use generator::generator::TransactionGenerator;
 use log::{error, info};
-use ring::signature;
+use network::message::Message;
+use persistence::{DataDir, Snapshotter};
 use smol::channel;
 use std::net;
 use std::process;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 use types::mempool::Mempool;
+use types::state::State;
 
 extern crate ring;
-use ring::signature::{Ed25519KeyPair, KeyPair};
-use std::fs::File;
-use std::io::Write;
 extern crate base64;
 
-fn generate_and_save_keys() -> Result<(), std::io::Error> {
-    let rng = ring::rand::SystemRandom::new();
-
-    // Generate a key pair
-    let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    let pkcs8_bytes: &[u8] = pkcs8_bytes.as_ref();
-
-    // Save private key to file
-    let mut file = File::create("key_pair.pem")?;
-    file.write_all(pkcs8_bytes)?;
-
+/// Run a key management subcommand, printing the address of the key it handled
+fn run_key_command(matches: &clap::ArgMatches) -> Result<(), String> {
+    let key_pair = match matches.subcommand() {
+        ("keygen", Some(args)) => {
+            let pkcs8 = types::key_pair::generate_pkcs8();
+            types::key_pair::export(args.value_of("out").unwrap().as_ref(), &pkcs8)?;
+            types::key_pair::from_pkcs8(&pkcs8)?
+        }
+        ("import", Some(args)) => {
+            let (pkcs8, key_pair) =
+                types::key_pair::import(args.value_of("file").unwrap().as_ref())?;
+            types::key_pair::export(args.value_of("out").unwrap().as_ref(), &pkcs8)?;
+            key_pair
+        }
+        ("address", Some(args)) => {
+            types::key_pair::import(args.value_of("file").unwrap().as_ref())?.1
+        }
+        _ => unreachable!(),
+    };
+    println!("{}", types::key_pair::address(&key_pair));
     Ok(())
 }
 
 fn main() {
-    if let Err(e) = generate_and_save_keys() {
-        println!("Failed to generate keys: {}", e);
-    }
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
@@ -53,16 +66,145 @@ fn main() {
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg admin_token: --("admin-token") [TOKEN] "Enables POST /admin/shutdown for clients sending this token in an Authorization: Bearer header")
+     (@arg api_workers: --("api-workers") [INT] default_value("8") "Sets the number of worker threads for the API server")
+     (@arg enable_debug_api: --("enable-debug-api") "Enables the debug API endpoints that change the chain, such as POST /debug/set-tip, which break consensus")
+     (@arg api_rate_limit: --("api-rate-limit") [INT] default_value("100") "Sets how many API requests per second each client IP may make")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, as IP:port or hostname:port")
+     (@arg max_peers: --("max-peers") [INT] default_value("64") "Sets how many peers may be connected before incoming connections are refused")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory the chain, state and mempool are saved to")
+     (@arg save_interval: --("save-interval") [SECS] default_value("30") "Sets how often, in seconds, a snapshot is saved to the data directory")
+     (@arg config: --config [FILE] "Sets a JSON file of consensus parameters: difficulty, block_reward, max_block_size, max_block_weight, ico_balance and dust_threshold")
+     (@arg difficulty: --difficulty [HEX] "Overrides the consensus difficulty with a 64-digit hex target, e.g. an easy one for demos; every node must use the same")
+     (@arg block_size: --("block-size") [INT] "Sets the maximum number of mempool transactions in a mined block, at most the consensus limit")
+     (@arg orphan_capacity: --("orphan-capacity") [INT] "Sets the maximum number of blocks buffered while their parent is unknown, 128 if not given")
+     (@arg mempool_capacity: --("mempool-capacity") [INT] "Sets the maximum number of transactions held in the mempool, unbounded if not given")
+     (@arg mempool_ttl: --("mempool-ttl") [SECS] default_value("3600") "Sets how long, in seconds, a transaction stays in the mempool before it is dropped unmined")
+     (@arg sig_cache_size: --("sig-cache-size") [INT] default_value("100000") "Sets how many verified transaction signatures are cached, 0 disables the cache")
+     (@arg key_file: --("key-file") [FILE] default_value("node_key.pk8") "Sets the PKCS8 file holding this node's key, which is generated there if missing")
+     (@arg mining_reward_address: --("mining-reward-address") [ADDR] "Sets the address credited by the coinbase of mined blocks, instead of this node's key")
+     (@arg tx_value_min: --("tx-value-min") [INT] default_value("1") "Sets the smallest value of a transaction sent by the transaction generator")
+     (@arg tx_value_max: --("tx-value-max") [INT] default_value("5") "Sets the largest value of a transaction sent by the transaction generator")
+     (@arg genesis_alloc: --("genesis-alloc") [FILE] "Sets a JSON file mapping addresses to their balance at genesis, instead of funding the built-in ICO account")
+     (@subcommand keygen =>
+      (about: "Generates a key pair, saves it as PKCS8 and prints its address")
+      (@arg out: default_value("node_key.pk8") "Sets the file the key is saved to"))
+     (@subcommand import =>
+      (about: "Imports a PKCS8 key pair as the node key and prints its address")
+      (@arg file: +required "Sets the PKCS8 file to import")
+      (@arg out: --out [FILE] default_value("node_key.pk8") "Sets the file the key is saved to"))
+     (@subcommand address =>
+      (about: "Prints the address of a PKCS8 key pair")
+      (@arg file: default_value("node_key.pk8") "Sets the PKCS8 file to read"))
     )
     .get_matches();
 
+    if matches.subcommand_name().is_some() {
+        if let Err(e) = run_key_command(&matches) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    shutdown::install_handler();
+
+    // size the signature cache
+    let sig_cache_size = matches
+        .value_of("sig_cache_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing signature cache size: {}", e);
+            process::exit(1);
+        });
+    types::signature_cache::global().set_capacity(sig_cache_size);
+
+    // open the data directory, if any, and load the last snapshot from it
+    let data_dir = matches.value_of("data_dir").map(|dir| {
+        DataDir::new(dir).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", dir, e);
+            process::exit(1);
+        })
+    });
+    // consensus parameters
+    let mut params = match matches.value_of("config") {
+        Some(path) => ConsensusParams::from_file(path.as_ref()).unwrap_or_else(|e| {
+            error!("Error loading consensus parameters: {}", e);
+            process::exit(1);
+        }),
+        None => ConsensusParams::default(),
+    };
+    if let Some(hex_str) = matches.value_of("difficulty") {
+        params.difficulty = config::parse_difficulty(hex_str).unwrap_or_else(|e| {
+            error!("Error parsing difficulty: {}", e);
+            process::exit(1);
+        });
+    }
+    // balances at genesis
+    let genesis_state = match matches.value_of("genesis_alloc") {
+        Some(path) => State::from_genesis_alloc(path.as_ref(), &params),
+        None => State::with_params(&params),
+    }
+    .unwrap_or_else(|e| {
+        error!("Error creating the genesis state: {}", e);
+        process::exit(1);
+    });
+    let max_block_size = params.max_block_size;
+    let mut blockchain = match &data_dir {
+        Some(dir) => {
+            let loaded =
+                Blockchain::load_from_path_with_params(dir, params.clone(), genesis_state.clone());
+            match loaded {
+                Ok(blockchain) => blockchain,
+                // a corrupt snapshot is replaced by the next one, the chain is synced again
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    error!("Error loading blockchain, starting from genesis: {}", e);
+                    Blockchain::with_params(params, genesis_state)
+                }
+                Err(e) => {
+                    error!("Error loading blockchain: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => Blockchain::with_params(params, genesis_state),
+    };
+    if let Some(capacity) = matches.value_of("orphan_capacity") {
+        blockchain.set_orphan_capacity(capacity.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing orphan capacity: {}", e);
+            process::exit(1);
+        }));
+    }
+    // the mempool checks transactions against the tip state, so it is created after the chain
+    let mut mempool = Mempool::from_state(blockchain.get_state().clone());
+    if let Some(capacity) = matches.value_of("mempool_capacity") {
+        mempool = mempool.with_capacity(capacity.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing mempool capacity: {}", e);
+            process::exit(1);
+        }));
+    }
+    if let Some(dir) = &data_dir {
+        mempool.load_from_path(dir).unwrap_or_else(|e| {
+            error!("Error loading mempool: {}", e);
+            process::exit(1);
+        });
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(mempool));
+    let mempool_ttl = matches
+        .value_of("mempool_ttl")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool TTL: {}", e);
+            process::exit(1);
+        });
+    types::mempool::start_expiry(&mempool, time::Duration::from_secs(mempool_ttl));
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -87,8 +229,20 @@ fn main() {
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
+    let max_peers = matches
+        .value_of("max_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max peers: {}", e);
+            process::exit(1);
+        });
     let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    server_ctx.limit_peers(max_peers).start().unwrap();
+    blockchain
+        .lock()
+        .unwrap()
+        .track_best_height(server.best_height_tracker());
 
     // start the worker
     let p2p_workers = matches
@@ -100,9 +254,22 @@ fn main() {
             process::exit(1);
         });
 
-    // Initialize the mempool
-    let mempool = Mempool::new();
-    let mempool = Arc::new(Mutex::new(mempool));
+    // start saving snapshots
+    let snapshotter = data_dir.map(|dir| Snapshotter::new(dir, &blockchain, &mempool));
+    if let Some(snapshotter) = &snapshotter {
+        let save_interval = matches
+            .value_of("save_interval")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing save interval: {}", e);
+                process::exit(1);
+            });
+        snapshotter
+            .clone()
+            .start(time::Duration::from_secs(save_interval));
+    }
+
     let cloned_blockchain = Arc::clone(&blockchain);
     let cloned_mempool = Arc::clone(&mempool); // Clone the Arc to pass to the worker
     let worker_ctx = network::worker::Worker::new(
@@ -114,9 +281,44 @@ fn main() {
     );
     worker_ctx.start();
 
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) =
-        miner::new(&Arc::clone(&blockchain), &Arc::clone(&mempool));
+    // start the miner, paying the rewards to the configured address or else to this node's key,
+    // kept across restarts
+    let reward_address = match matches.value_of("mining_reward_address") {
+        Some(address) => types::state::AccountAddress::parse(address)
+            .map(|address| address.to_string())
+            .unwrap_or_else(|e| {
+                error!("Error parsing mining reward address: {}", e);
+                process::exit(1);
+            }),
+        None => {
+            let key_file = matches.value_of("key_file").unwrap();
+            types::key_pair::load_or_generate(key_file.as_ref())
+                .map(|key_pair| types::key_pair::address(&key_pair))
+                .unwrap_or_else(|e| {
+                    error!("Error loading node key: {}", e);
+                    process::exit(1);
+                })
+        }
+    };
+    let block_size = matches.value_of("block_size").map_or(max_block_size, |size| {
+        size.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing block size: {}", e);
+            process::exit(1);
+        })
+    });
+    if block_size == 0 || block_size > max_block_size {
+        error!(
+            "Block size must be between 1 and the consensus limit of {}",
+            max_block_size
+        );
+        process::exit(1);
+    }
+    let (miner_ctx, miner, finished_block_chan) = miner::new(
+        &Arc::clone(&blockchain),
+        &Arc::clone(&mempool),
+        reward_address,
+        block_size,
+    );
     let miner_worker_ctx = miner::worker::Worker::new(
         &server,
         finished_block_chan,
@@ -124,31 +326,64 @@ fn main() {
         &server,
     );
     miner_ctx.start();
-    miner_worker_ctx.start();
+    let miner_worker = miner_worker_ctx.start();
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
+    // connect to known peers, the miner is released once they are all connected
+    let genesis = blockchain.lock().unwrap().genesis_hash();
+    let known_peers: Vec<String> = matches
+        .values_of("known_peer")
+        .map(|peers| peers.map(|x| x.to_owned()).collect())
+        .unwrap_or_default();
+    {
+        // open the handshake again whenever a lost peer is reconnected
+        let best_height = server.best_height_tracker();
+        let node_id = server.node_id();
+        server.on_reconnect(move |peer| {
+            let best_height = best_height.load(Ordering::Relaxed);
+            peer.write(Message::version(best_height, genesis, node_id));
+        });
+    }
+    {
         let server = server.clone();
+        let miner = miner.clone();
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
+                    // names are looked up again on every attempt, a service may not be up yet
+                    let addrs = match network::server::resolve(&peer) {
+                        Ok(addrs) => addrs,
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
                             error!("Error parsing peer address {}: {}", &peer, e);
                             break;
                         }
+                        Err(e) => {
+                            error!(
+                                "Error resolving peer {}, retrying in one second: {}",
+                                &peer, e
+                            );
+                            thread::sleep(time::Duration::from_millis(1000));
+                            continue;
+                        }
                     };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
+                    match server.connect_any(&addrs) {
+                        Ok((addr, mut handle)) => {
+                            info!("Connected to outgoing peer {} at {}", &peer, &addr);
+                            server.add_persistent_peer(addr);
+                            handle.write(Message::version(
+                                server.best_height(),
+                                genesis,
+                                server.node_id(),
+                            ));
+                            break;
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                            info!("Already connected to peer {}", &peer);
                             break;
                         }
                         Err(e) => {
                             error!(
                                 "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
+                                &peer, e
                             );
                             thread::sleep(time::Duration::from_millis(1000));
                             continue;
@@ -156,13 +391,81 @@ fn main() {
                     }
                 }
             }
+            miner.initialized();
         });
     }
 
-    // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain, &mempool);
+    // start the API server, which starts the transaction generator on request
+    let tx_value = |name: &str| {
+        matches.value_of(name).unwrap().parse::<i64>().unwrap_or_else(|e| {
+            error!("Error parsing transaction value: {}", e);
+            process::exit(1);
+        })
+    };
+    let generator = TransactionGenerator::new(tx_value("tx_value_min"), tx_value("tx_value_max"))
+        .unwrap_or_else(|e| {
+            error!("Invalid transaction values: {}", e);
+            process::exit(1);
+        });
+    let api_rate_limit = matches
+        .value_of("api_rate_limit")
+        .unwrap()
+        .parse::<u32>()
+        .ok()
+        .filter(|rate| *rate > 0)
+        .unwrap_or_else(|| {
+            error!("API rate limit must be a positive number of requests per second");
+            process::exit(1);
+        });
+    let admin_token = matches.value_of("admin_token").map(|token| {
+        if token.is_empty() {
+            error!("Admin token must not be empty");
+            process::exit(1);
+        }
+        token.to_string()
+    });
+    let api_workers = matches
+        .value_of("api_workers")
+        .unwrap()
+        .parse::<usize>()
+        .ok()
+        .filter(|workers| *workers > 0)
+        .unwrap_or_else(|| {
+            error!("API workers must be a positive number of threads");
+            process::exit(1);
+        });
+    let api_options = api::Options {
+        rate_limit: Some(api_rate_limit),
+        admin_token,
+        workers: api_workers,
+        debug_api: matches.is_present("enable_debug_api"),
+    };
+    let api_server = ApiServer::start(
+        api_addr,
+        &miner,
+        &server,
+        &blockchain,
+        &mempool,
+        &generator,
+        &api_options,
+    );
 
-    loop {
-        std::thread::park();
+    // run until SIGINT, SIGTERM or a shutdown request through the API, then stop the servers
+    // and the miner, and save a last snapshot once nothing changes the chain anymore
+    shutdown::wait();
+    info!("Shutting down");
+    api_server.stop();
+    miner.exit();
+    // the worker is done once the miner has shut down and its last block is inserted
+    if miner_worker.join().is_err() {
+        error!("Miner worker panicked");
+    }
+    server.stop();
+    if let Some(snapshotter) = &snapshotter {
+        match snapshotter.flush() {
+            Ok(_) => info!("Saved snapshot before exiting"),
+            Err(e) => error!("Error saving snapshot: {}", e),
+        }
     }
+    process::exit(0);
 }
@@ -0,0 +1,226 @@
+use crate::types::hash::H256;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// How many of the most recently inserted blocks' propagation delays are kept
+pub const PROPAGATION_WINDOW: usize = 100;
+
+/// Counters of the node's activity, bumped by the miner, the network workers and the mempool and
+/// read by the `/metrics` endpoint. Values that can be read off the shared state when scraped,
+/// such as the chain height, are not kept here.
+#[derive(Default)]
+pub struct Metrics {
+    blocks_mined: AtomicU64,
+    blocks_received: AtomicU64,
+    transactions_processed: AtomicU64,
+    propagation: Mutex<Propagation>,
+}
+
+/// Delays between the creation of the most recently received blocks and their insertion
+#[derive(Clone, Default)]
+pub struct Propagation {
+    recent: VecDeque<(H256, u64)>, // block and delay in milliseconds, oldest first
+}
+
+impl Propagation {
+    /// Record a block stamped at `timestamp` and inserted at `arrival`, both in milliseconds
+    /// since the epoch. A block stamped after its arrival, by a clock running ahead, counts as
+    /// no delay.
+    pub fn record(&mut self, block: H256, timestamp: u128, arrival: u128) {
+        if self.recent.len() == PROPAGATION_WINDOW {
+            self.recent.pop_front();
+        }
+        let delay = arrival.saturating_sub(timestamp).min(u64::MAX as u128) as u64;
+        self.recent.push_back((block, delay));
+    }
+
+    /// The recorded blocks and their delays in milliseconds, oldest first
+    pub fn recent(&self) -> impl Iterator<Item = &(H256, u64)> {
+        self.recent.iter()
+    }
+
+    /// Mean delay in milliseconds over the recorded blocks, None if there are none
+    pub fn average(&self) -> Option<f64> {
+        if self.recent.is_empty() {
+            return None;
+        }
+        let total: u128 = self.recent.iter().map(|(_, delay)| *delay as u128).sum();
+        Some(total as f64 / self.recent.len() as f64)
+    }
+}
+
+/// Values read off the blockchain, mempool and network when the metrics are scraped
+pub struct Gauges {
+    pub chain_height: u64,
+    pub tip_age_seconds: u64,
+    pub mempool_size: u64,
+    pub peer_count: u64,
+    pub block_count: u64,
+    pub transaction_count: u64,
+}
+
+impl Metrics {
+    pub fn block_mined(&self) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_received(&self) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A transaction was accepted into the mempool
+    pub fn transaction_processed(&self) {
+        self.transactions_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A block stamped at `timestamp` was inserted at `arrival`, see `Propagation::record`
+    pub fn block_propagated(&self, block: H256, timestamp: u128, arrival: u128) {
+        let mut propagation = self.propagation.lock().unwrap();
+        propagation.record(block, timestamp, arrival);
+    }
+
+    pub fn blocks_mined(&self) -> u64 {
+        self.blocks_mined.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_received(&self) -> u64 {
+        self.blocks_received.load(Ordering::Relaxed)
+    }
+
+    pub fn transactions_processed(&self) -> u64 {
+        self.transactions_processed.load(Ordering::Relaxed)
+    }
+
+    /// A copy of the recent propagation delays
+    pub fn propagation(&self) -> Propagation {
+        self.propagation.lock().unwrap().clone()
+    }
+
+    /// Render the counters and the given gauges in the Prometheus text exposition format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let metrics = [
+            (
+                "bitcoin_chain_height",
+                "gauge",
+                "Height of the tip of the longest chain",
+                gauges.chain_height,
+            ),
+            (
+                "bitcoin_tip_age_seconds",
+                "gauge",
+                "Seconds since the tip of the longest chain was created",
+                gauges.tip_age_seconds,
+            ),
+            (
+                "bitcoin_mempool_size",
+                "gauge",
+                "Transactions waiting in the mempool",
+                gauges.mempool_size,
+            ),
+            (
+                "bitcoin_peers",
+                "gauge",
+                "Connected peers",
+                gauges.peer_count,
+            ),
+            (
+                "bitcoin_blocks",
+                "gauge",
+                "Blocks known, on any branch",
+                gauges.block_count,
+            ),
+            (
+                "bitcoin_block_transactions",
+                "gauge",
+                "Transactions in the blocks known, on any branch",
+                gauges.transaction_count,
+            ),
+            (
+                "bitcoin_blocks_mined_total",
+                "counter",
+                "Blocks mined by this node",
+                self.blocks_mined(),
+            ),
+            (
+                "bitcoin_blocks_received_total",
+                "counter",
+                "Blocks received from peers and added to the blockchain",
+                self.blocks_received(),
+            ),
+            (
+                "bitcoin_transactions_processed_total",
+                "counter",
+                "Transactions accepted into the mempool",
+                self.transactions_processed(),
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        out
+    }
+}
+
+/// The metrics shared by the whole node
+pub fn global() -> &'static Metrics {
+    static GLOBAL: OnceLock<Metrics> = OnceLock::new();
+    GLOBAL.get_or_init(Metrics::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        let metrics = Metrics::default();
+        metrics.block_mined();
+        metrics.block_mined();
+        let gauges = Gauges {
+            chain_height: 3,
+            tip_age_seconds: 0,
+            mempool_size: 1,
+            peer_count: 0,
+            block_count: 4,
+            transaction_count: 7,
+        };
+        let text = metrics.render(&gauges);
+        assert!(text.contains("# TYPE bitcoin_blocks_mined_total counter\n"));
+        assert!(text.contains("\nbitcoin_blocks_mined_total 2\n"));
+        assert!(text.contains("\nbitcoin_chain_height 3\n"));
+        assert!(text.contains("\nbitcoin_blocks 4\n"));
+        assert!(text.contains("\nbitcoin_block_transactions 7\n"));
+        assert!(text.contains("\nbitcoin_transactions_processed_total 0\n"));
+    }
+
+    #[test]
+    fn propagation_delay_of_known_timestamps() {
+        let mut propagation = Propagation::default();
+        assert_eq!(propagation.average(), None);
+        let (first, second, early) = (
+            H256::from([1; 32]),
+            H256::from([2; 32]),
+            H256::from([3; 32]),
+        );
+        propagation.record(first, 1_000, 1_250);
+        propagation.record(second, 2_000, 2_750);
+        // stamped by a clock running ahead of ours
+        propagation.record(early, 5_000, 4_000);
+        let recent: Vec<(H256, u64)> = propagation.recent().cloned().collect();
+        assert_eq!(recent, vec![(first, 250), (second, 750), (early, 0)]);
+        assert_eq!(propagation.average(), Some(1_000.0 / 3.0));
+
+        // only the latest window is kept
+        for i in 0..PROPAGATION_WINDOW as u128 {
+            propagation.record(H256::from([4; 32]), i, i + 100);
+        }
+        assert_eq!(propagation.recent().count(), PROPAGATION_WINDOW);
+        assert!(propagation.recent().all(|(_, delay)| *delay == 100));
+        assert_eq!(propagation.average(), Some(100.0));
+    }
+}
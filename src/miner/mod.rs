@@ -1,6 +1,6 @@
 pub mod worker;
 
-use log::info;
+use log::{debug, info};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
@@ -8,17 +8,27 @@ use std::time;
 use std::thread;
 
 use crate::blockchain::Blockchain; // Import the Blockchain type
+use crate::metrics;
 use crate::types::block::Block;
-use crate::types::hash::Hashable;
+use crate::types::hash::{Hashable, H256};
 use crate::types::mempool::Mempool;
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::SignedTransaction;
 use std::sync::{Arc, Mutex};
+
+/// How many nonces are tried between two looks at whether the tip moved
+const TIP_CHECK_INTERVAL: u64 = 4096;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update,     // update the block in mining, it may due to new blockchain tip or new transaction
+    Initialized, // the node finished loading, start requests received before this are queued
+    MineOne(Sender<H256>), // mine a single block, send back its hash and pause
     Exit,
 }
 
-enum OperatingState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
@@ -28,46 +38,79 @@ pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    reported_state: Arc<Mutex<OperatingState>>, // copy of operating_state read by the handles
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>, // Add the blockchain field
     mempool: Arc<Mutex<Mempool>>,       // Add the mempool field
+    tips: Receiver<H256>,               // every new tip, from the network or this miner
+    initialized: bool,
+    pending_start: Option<u64>,
+    mine_one_reply: Option<Sender<H256>>, // set while mining a block asked for by MineOne
+    miner_address: String, // address credited by the coinbase of mined blocks
+    max_block_size: usize, // most mempool transactions put in a block
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    operating_state: Arc<Mutex<OperatingState>>,
+    miner_address: String,
+    max_block_size: usize,
 }
 
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    miner_address: String,
+    max_block_size: usize,
 ) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let operating_state = Arc::new(Mutex::new(OperatingState::Paused));
 
     let ctx: Context = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        reported_state: Arc::clone(&operating_state),
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain), // Clone the blockchain Arc
         mempool: Arc::clone(mempool),       // Clone the mempool Arc
+        tips: blockchain.lock().unwrap().subscribe_tip(),
+        initialized: false,
+        pending_start: None,
+        mine_one_reply: None,
+        miner_address: miner_address.clone(),
+        max_block_size,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        operating_state,
+        miner_address,
+        max_block_size,
     };
 
     (ctx, handle, finished_block_receiver)
 }
 
+#[cfg(any(test, test_utilities))]
+fn test_miner_address() -> String {
+    use crate::types::key_pair;
+    use ring::signature::KeyPair;
+    base64::encode(key_pair::random().public_key())
+}
+
 #[cfg(any(test, test_utilities))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     use crate::types::mempool;
 
     let blockchain = Arc::new(Mutex::new(Blockchain::new())); // Create a blockchain for testing
     let mempool = Arc::new(Mutex::new(Mempool::new())); // Create a blockchain for testing
-    new(&blockchain, &mempool)
+    let (ctx, handle, finished_block_chan) =
+        new(&blockchain, &mempool, test_miner_address(), 20);
+    handle.initialized();
+    (ctx, handle, finished_block_chan)
 }
 
 impl Handle {
@@ -84,6 +127,75 @@ impl Handle {
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Mine exactly one block on the current tip, whether or not the node is initialized, and
+    /// leave the miner paused. Blocks until the block is mined and returns its hash, or None if
+    /// the miner shut down first.
+    pub fn mine_one(&self) -> Option<H256> {
+        let (reply, mined) = unbounded();
+        self.control_chan.send(ControlSignal::MineOne(reply)).ok()?;
+        mined.recv().ok()
+    }
+
+    /// Tell the miner the node is ready, releasing a start request that arrived earlier
+    pub fn initialized(&self) {
+        self.control_chan.send(ControlSignal::Initialized).unwrap();
+    }
+
+    /// What the miner is doing, as of the last control signal it handled
+    pub fn operating_state(&self) -> OperatingState {
+        *self.operating_state.lock().unwrap()
+    }
+
+    /// The address credited by the coinbase of mined blocks
+    pub fn address(&self) -> &str {
+        &self.miner_address
+    }
+
+    /// Most mempool transactions put in a block
+    pub fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+}
+
+/// The block to mine next on the tip and its height: a coinbase paying `miner_address`, then at
+/// most `max_block_size` transactions of the mempool, committing to their Merkle root and to the
+/// state after them. Its nonce is the random seed mining starts from.
+pub fn block_template(
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    miner_address: &str,
+    max_block_size: usize,
+) -> (Block, u32) {
+    // one view of the chain for the whole template, locked before the mempool like everywhere else
+    let blockchain = blockchain.lock().unwrap();
+    let tip = blockchain.tip();
+    let params = blockchain.params();
+    let height = blockchain.get_height(&tip).unwrap_or(0) + 1;
+    // a block must keep the difficulty of its parent
+    let difficulty = blockchain
+        .get_block(&tip)
+        .map_or(params.difficulty, |block| block.get_difficulty());
+    let mut block = Block::with_difficulty(tip, difficulty);
+    // The reward to the miner comes first
+    block.get_content_mut().add_transactions(vec![SignedTransaction::coinbase(
+        miner_address.to_string(),
+        params.block_reward as i64,
+        height as u64,
+    )]);
+    // Fetch transactions from the mempool
+    block.get_content_mut().add_transactions(mempool.lock().unwrap().get_transactions_for_block(
+        max_block_size,
+        params.max_block_weight,
+        blockchain.get_state(),
+        &blockchain,
+    ));
+    block.set_merkle_root(MerkleTree::new(block.get_transactions()).root());
+    // Commit to the state the block leads to
+    if let Some(state_root) = blockchain.expected_state_root(&block) {
+        block.set_state_root(state_root);
+    }
+    (block, height)
 }
 
 impl Context {
@@ -97,28 +209,61 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    /// Whether a new tip was announced since the block on `parent` was started, only taking the
+    /// lock when there was an announcement
+    fn tip_moved(&self, parent: &H256) -> bool {
+        if self.tips.try_iter().count() == 0 {
+            return false;
+        }
+        self.blockchain.lock().unwrap().tip() != *parent
+    }
+
+    fn set_operating_state(&mut self, state: OperatingState) {
+        self.operating_state = state;
+        *self.reported_state.lock().unwrap() = state;
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
-        println!("starting miner");
+        debug!("Miner loop started");
         loop {
             // check and react to control signals
             match self.operating_state {
-                OperatingState::Paused => {
+                // a paused miner still finishes the block asked for by MineOne
+                OperatingState::Paused if self.mine_one_reply.is_none() => {
                     let signal = self.control_chan.recv().unwrap();
                     match signal {
                         ControlSignal::Exit => {
                             info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_operating_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            if self.initialized {
+                                info!("Miner starting in continuous mode with lambda {}", i);
+                                self.set_operating_state(OperatingState::Run(i));
+                            } else {
+                                info!("Miner start queued until the node is initialized");
+                                self.pending_start = Some(i);
+                            }
                         }
                         ControlSignal::Update => {
                             // in paused state, don't need to update
                         }
+                        ControlSignal::Initialized => {
+                            self.initialized = true;
+                            if let Some(i) = self.pending_start.take() {
+                                info!("Miner starting in continuous mode with lambda {}", i);
+                                self.set_operating_state(OperatingState::Run(i));
+                            }
+                        }
+                        ControlSignal::MineOne(reply) => {
+                            info!("Miner mining a single block");
+                            self.mine_one_reply = Some(reply);
+                        }
                     };
-                    continue;
+                    if self.mine_one_reply.is_none() {
+                        continue;
+                    }
                 }
                 OperatingState::ShutDown => {
                     return;
@@ -128,14 +273,22 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_operating_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_operating_state(OperatingState::Run(i));
                             }
                             ControlSignal::Update => {
-                                unimplemented!()
+                                // each block is built on the latest tip and mempool, and
+                                // abandoned as soon as the tip moves
+                            }
+                            ControlSignal::Initialized => {
+                                self.initialized = true;
+                            }
+                            ControlSignal::MineOne(reply) => {
+                                info!("Miner mining a single block, then pausing");
+                                self.mine_one_reply = Some(reply);
                             }
                         };
                     }
@@ -150,47 +303,70 @@ impl Context {
             // TODO for student: actual mining, create a block
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 
-            let parent = { self.blockchain.lock().unwrap().tip() };
-            let mut block = Block::new(parent);
-            // Fetch transactions from the mempool
-            {
-                let mempool = self.mempool.lock().unwrap();
-
-                block.get_content_mut().add_transactions(
-                    mempool.get_transactions_for_block(20, &self.blockchain.lock().unwrap()),
-                ); // Assume Block has a method to add a transaction
-            }
-            println!(
-                "{:?}",
-                self.blockchain
-                    .lock()
-                    .unwrap()
-                    .all_blocks_in_longest_chain()
-                    .len()
+            // tips announced so far are behind us, the block goes on the current one
+            self.tips.try_iter().for_each(drop);
+            let (mut block, height) = block_template(
+                &self.blockchain,
+                &self.mempool,
+                &self.miner_address,
+                self.max_block_size,
+            );
+            let parent = block.get_parent();
+            debug!(
+                "Mining a block at height {} on {} with {} transactions",
+                height,
+                parent,
+                block.get_transactions().len()
             );
 
-            let mut nonce = 0;
+            // grind from the block's random nonce, wrapping around
+            let seed = block.get_nonce();
+            let mut tried: u64 = 0;
+            let mut stale = false;
             loop {
+                if tried % TIP_CHECK_INTERVAL == 0 && self.tip_moved(&parent) {
+                    debug!("Tip moved, abandoning the block on {}", parent);
+                    stale = true;
+                    break;
+                }
+                if tried > u32::MAX as u64 {
+                    debug!("Every nonce tried, starting over with a new timestamp");
+                    stale = true;
+                    break;
+                }
                 // Set the nonce field of the block
-                block.set_nonce(nonce);
+                block.set_nonce(seed.wrapping_add(tried as u32));
                 // Calculate the block's hash
                 let hash = block.hash();
 
                 //print!("{}", block.get_parent());
 
                 // Check if the hash meets the proof-of-work condition
-                if hash <= block.get_difficulty() {
+                if hash.meets_difficulty(&block.get_difficulty()) {
                     // Mining successful, send the mined block
+                    metrics::global().block_mined();
                     self.finished_block_chan
                         .send(block.clone())
                         .expect("Send finished block error");
-                    {
-                        println!("found new block");
-                        self.blockchain.lock().unwrap().insert(&block.clone());
-                    }
+                    let state = {
+                        info!("Mined {} at height {}", block, height);
+                        let mut blockchain = self.blockchain.lock().unwrap();
+                        blockchain.insert(&block.clone());
+                        blockchain.get_state().clone()
+                    };
+                    self.mempool.lock().unwrap().revalidate(&state);
                     break; // Exit the mining loop
                 }
-                nonce += 1; // Increment nonce for the next iteration
+                tried += 1; // Move to the next nonce
+            }
+            if stale {
+                continue;
+            }
+
+            if let Some(reply) = self.mine_one_reply.take() {
+                self.set_operating_state(OperatingState::Paused);
+                let _ = reply.send(block.hash());
+                continue;
             }
 
             // Continue with the next mining iteration or sleep if necessary
@@ -208,8 +384,14 @@ impl Context {
 
 #[cfg(test)]
 mod test {
+    use crate::blockchain::Blockchain;
+    use crate::config::ConsensusParams;
+    use crate::network::server::Handle as ServerHandle;
     use crate::types::hash::Hashable;
+    use crate::types::mempool::Mempool;
+    use crate::types::state::State;
     use ntest::timeout;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     #[timeout(60000)]
@@ -224,6 +406,223 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_waits_for_initialization() {
+        use crate::blockchain::Blockchain;
+        use crate::types::mempool::Mempool;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 20);
+        miner_ctx.start();
+        miner_handle.start(0);
+        assert!(finished_block_chan
+            .recv_timeout(Duration::from_millis(1000))
+            .is_err());
+        miner_handle.initialized();
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.get_parent(), blockchain.lock().unwrap().all_blocks_in_longest_chain()[0]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_respects_block_size() {
+        use crate::blockchain::Blockchain;
+        use crate::types::mempool::Mempool;
+        use crate::types::transaction::SignedTransaction;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        for nonce in 0..5 {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            mempool.lock().unwrap().add_transaction(tx);
+        }
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 2);
+        miner_ctx.start();
+        miner_handle.initialized();
+        miner_handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        // the coinbase comes on top of the mempool transactions
+        assert!(block.get_transactions().len() <= 2 + 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn exit_shuts_down_miner() {
+        use super::OperatingState;
+
+        let (mut miner_ctx, miner_handle, _finished_block_chan) = super::test_new();
+        assert_eq!(miner_handle.operating_state(), OperatingState::Paused);
+        miner_handle.exit();
+        miner_ctx.miner_loop();
+        assert!(matches!(miner_ctx.operating_state, OperatingState::ShutDown));
+        assert_eq!(miner_handle.operating_state(), OperatingState::ShutDown);
+
+        // a running miner checks for the signal before starting each block
+        let (mut miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_handle.start(0);
+        miner_handle.exit();
+        miner_ctx.miner_loop();
+        assert!(matches!(miner_ctx.operating_state, OperatingState::ShutDown));
+        assert!(finished_block_chan.len() <= 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mined_blocks_update_best_height() {
+        let params = ConsensusParams {
+            difficulty: [0xff; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        blockchain
+            .lock()
+            .unwrap()
+            .track_best_height(server.best_height_tracker());
+        assert_eq!(server.best_height(), 0);
+
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 20);
+        miner_handle.initialized();
+        miner_ctx.start();
+        miner_handle.start(0);
+        finished_block_chan.recv().unwrap();
+        miner_handle.exit();
+        // the channel closes once the miner stopped, after inserting its last block
+        while finished_block_chan.recv().is_ok() {}
+        assert!(server.best_height() >= 1);
+        assert_eq!(server.best_height(), blockchain.lock().unwrap().best_height());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn better_tip_interrupts_mining() {
+        use crate::types::block::generate_block_with_difficulty;
+        use std::time::Duration;
+
+        // nothing can be mined on genesis, only on the blocks that come from elsewhere
+        let params = ConsensusParams {
+            difficulty: [0; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner_handle, _finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 20);
+        miner_ctx.start();
+        let mining = {
+            let miner_handle = miner_handle.clone();
+            std::thread::spawn(move || miner_handle.mine_one())
+        };
+        std::thread::sleep(Duration::from_millis(100));
+
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let first = generate_block_with_difficulty(&genesis_hash, [0xff; 32].into());
+        let second = generate_block_with_difficulty(&first.hash(), [0xff; 32].into());
+        blockchain.lock().unwrap().insert(&first);
+        blockchain.lock().unwrap().insert(&second);
+
+        let mined = mining.join().unwrap().unwrap();
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.get_block(&mined).unwrap().get_parent(), second.hash());
+        assert_eq!(blockchain.tip(), mined);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_one_mines_a_single_block() {
+        use super::OperatingState;
+        use std::time::Duration;
+
+        let params = ConsensusParams {
+            difficulty: [0xff; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 20);
+        miner_ctx.start();
+        for height in 1..=2 {
+            let hash = miner_handle.mine_one().unwrap();
+            assert_eq!(blockchain.lock().unwrap().best_height(), height);
+            assert_eq!(blockchain.lock().unwrap().tip(), hash);
+            assert_eq!(finished_block_chan.recv().unwrap().hash(), hash);
+            assert_eq!(miner_handle.operating_state(), OperatingState::Paused);
+        }
+        assert!(finished_block_chan
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+        assert_eq!(blockchain.lock().unwrap().best_height(), 2);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mined_blocks_credit_the_reward_address() {
+        use crate::types::state::AccountAddress;
+
+        let params = ConsensusParams {
+            difficulty: [0xff; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(
+            params.clone(),
+            State::new(),
+        )));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let pool = super::test_miner_address();
+        let reward_address = AccountAddress::parse(&pool).unwrap();
+        let (miner_ctx, miner_handle, _finished_block_chan) =
+            super::new(&blockchain, &mempool, reward_address.to_string(), 20);
+        miner_ctx.start();
+        let hash = miner_handle.mine_one().unwrap();
+
+        let blockchain = blockchain.lock().unwrap();
+        let coinbase = &blockchain.get_block(&hash).unwrap().get_transactions()[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.get_receiver(), &pool);
+        assert_eq!(
+            blockchain.get_state().get_balance(&reward_address),
+            params.block_reward
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn easy_difficulty_mines_quickly() {
+        use std::time::{Duration, Instant};
+
+        let easy = "0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+        let params = ConsensusParams {
+            difficulty: crate::config::parse_difficulty(easy).unwrap(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(
+            params.clone(),
+            State::new(),
+        )));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner_handle, _finished_block_chan) =
+            super::new(&blockchain, &mempool, super::test_miner_address(), 20);
+        miner_ctx.start();
+        let started = Instant::now();
+        let hash = miner_handle.mine_one().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let blockchain = blockchain.lock().unwrap();
+        let block = blockchain.get_block(&hash).unwrap();
+        assert_eq!(block.get_difficulty(), params.difficulty);
+        assert!(hash.meets_difficulty(&params.difficulty));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,8 +1,9 @@
 use crate::blockchain::Blockchain;
-use crate::network::message::Message;
+use crate::network::message::{InvItem, Message};
 // Import the Blockchain type
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::Block;
+use crate::types::hash::Hashable;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
 use std::sync::{Arc, Mutex};
@@ -31,26 +32,85 @@ impl Worker {
         }
     }
 
-    pub fn start(self) {
-        thread::Builder::new()
+    /// Start the worker, its thread ends once the miner has shut down and every block it
+    /// finished is inserted
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
                 self.worker_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn worker_loop(&self) {
         loop {
-            let new_block = self
-                .finished_block_chan
-                .recv()
-                .expect("Receive finished block error");
+            // the channel closes once the miner has shut down
+            let new_block = match self.finished_block_chan.recv() {
+                Ok(block) => block,
+                Err(_) => {
+                    info!("Miner worker shutting down");
+                    return;
+                }
+            };
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
             self.blockchain.lock().unwrap().insert(&new_block);
+            // announce the hash only, peers that miss the block ask for it as a compact block
             self.net_server
-                .broadcast(Message::Blocks(vec![new_block.clone()]));
+                .broadcast(Message::Inv(vec![InvItem::Block(new_block.hash())]));
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Worker;
+    use crate::blockchain::Blockchain;
+    use crate::network::message::{InvItem, Message};
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use crate::types::mempool::Mempool;
+    use crossbeam::channel::unbounded;
+    use ntest::timeout;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    #[timeout(60000)]
+    fn mined_block_announced_by_hash() {
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (finished_block_sender, finished_block_chan) = unbounded();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        Worker::new(&server, finished_block_chan, &blockchain, &server).start();
+
+        let block = generate_random_block(&blockchain.lock().unwrap().tip());
+        finished_block_sender.send(block.clone()).unwrap();
+        match server_receiver.recv() {
+            Some(Message::Inv(items)) => assert_eq!(items, vec![InvItem::Block(block.hash())]),
+            other => panic!("expected Inv, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn worker_ends_once_the_miner_shut_down() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner, finished_block_chan) = crate::miner::new(
+            &blockchain,
+            &mempool,
+            crate::miner::test_miner_address(),
+            20,
+        );
+        let worker = Worker::new(&server, finished_block_chan, &blockchain, &server).start();
+        miner_ctx.start();
+
+        let mined = miner.mine_one().unwrap();
+        miner.exit();
+        worker.join().unwrap();
+        assert_eq!(blockchain.lock().unwrap().tip(), mined);
+    }
+}
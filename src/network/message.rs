@@ -1,15 +1,80 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{hash::{Hashable, H256}, block::{Block, Header}, transaction::SignedTransaction};
+
+/// Version of the message format spoken by this node, peers on another version are dropped.
+/// Version 2 signs transactions over their hash, version 3 adds a state root to block headers,
+/// version 4 adds headers-first sync, version 5 adds inventories, version 6 adds mempool sync,
+/// version 7 adds the genesis hash to the version message, version 8 downloads blocks from a
+/// locator, version 9 answers requests for blocks of an inventory with compact blocks, version 10
+/// adds the node id to the version message.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Largest encoded message accepted from a peer, in bytes. A frame announcing more is not read
+/// and the peer is disconnected.
+pub const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
+
+/// A block or transaction announced in an `Inv` or requested in a `GetData`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvItem {
+    Block(H256),
+    Tx(H256),
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    // peers with another genesis block are on another chain and are dropped, the node id tells
+    // two connections to the same node apart from connections to two nodes
+    Version { protocol_version: u32, best_height: u32, genesis: H256, node_id: u64 },
+    VerAck,
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
+    GetBlocksByRange { start: u32, end: u32 }, // heights on the longest chain, inclusive
     Blocks(Vec<Block>),
+    // hashes of our longest chain from the tip back to genesis, with growing gaps, the peer
+    // answers with the headers that follow the first hash it has on its own longest chain
+    GetHeaders { locator: Vec<H256> },
+    Headers(Vec<Header>),
+    // a locator as in GetHeaders, answered with one Blocks message of the blocks that follow
+    GetBlocksAfter { locator: Vec<H256> },
+    // a block without its transaction bodies, except the coinbase no mempool holds
+    CompactBlock { header: Header, coinbase: Option<SignedTransaction>, tx_hashes: Vec<H256> },
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    // announces blocks and transactions at once, replacing NewBlockHashes and
+    // NewTransactionHashes, which are still understood
+    Inv(Vec<InvItem>),
+    // asks for the items of an Inv the peer lacks, answered with Transactions, then a
+    // CompactBlock for each block, which the transactions just sent help rebuild
+    GetData(Vec<InvItem>),
+    // sent once the handshake completes, answered with NewTransactionHashes of the peer's mempool
+    GetMempool,
+}
+
+impl Message {
+    /// Relay a block as its header and transaction hashes, the receiver fills in the
+    /// transactions from its mempool
+    pub fn compact_block(block: &Block) -> Message {
+        let transactions = block.get_transactions();
+        let coinbase = transactions.first().filter(|tx| tx.is_coinbase()).cloned();
+        let skip = if coinbase.is_some() { 1 } else { 0 };
+        Message::CompactBlock {
+            header: block.get_header().clone(),
+            coinbase,
+            tx_hashes: transactions.iter().skip(skip).map(|tx| tx.hash()).collect(),
+        }
+    }
+
+    /// The version message this node opens a connection with
+    pub fn version(best_height: u32, genesis: H256, node_id: u64) -> Message {
+        Message::Version {
+            protocol_version: PROTOCOL_VERSION,
+            best_height,
+            genesis,
+            node_id,
+        }
+    }
 }
@@ -5,17 +5,19 @@ use smol::Async;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -25,6 +27,7 @@ pub enum Direction {
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    direction: Direction,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -46,12 +49,23 @@ impl Handle {
         &self.addr
     }
 
+    /// Whether the peer connected to us or we connected to it
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Close the connection once the messages already queued are written
+    pub fn disconnect(&mut self) {
+        self.write_queue.close_channel();
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            direction: Direction::Incoming,
         },
         TestReceiver {
             r
@@ -66,4 +80,10 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
+
+    /// Like `recv`, but returns `None` once the peer was disconnected
+    pub fn next(&mut self) -> Option<Message> {
+        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r))?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
 }
\ No newline at end of file
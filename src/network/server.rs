@@ -7,39 +7,98 @@ use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
+/// Misbehavior score at which a peer is disconnected
+pub const BAN_THRESHOLD: u32 = 100;
+/// Delay before the first attempt to reconnect to a lost persistent peer, doubled after every
+/// failed attempt
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between two attempts to reconnect to a persistent peer
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Called with every connection re-established to a persistent peer, to open the handshake
+type ReconnectHook = Arc<Mutex<Option<Box<dyn Fn(&mut peer::Handle) + Send>>>>;
+
+/// Resolve a peer given as `host:port`, where the host is an IP address or a name looked up in
+/// DNS, to the addresses to try connecting to. A peer without a port is an `InvalidInput` error.
+pub fn resolve(peer: &str) -> std::io::Result<Vec<std::net::SocketAddr>> {
+    use std::net::ToSocketAddrs;
+
+    let addrs: Vec<std::net::SocketAddr> = peer.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} resolved to no address", peer),
+        ));
+    }
+    Ok(addrs)
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let connected = Arc::new(Mutex::new(HashSet::new()));
+    let persistent = Arc::new(Mutex::new(HashSet::new()));
+    let misbehavior = Arc::new(Mutex::new(HashMap::new()));
+    let peer_ids = Arc::new(Mutex::new(HashMap::new()));
+    let on_reconnect: ReconnectHook = Arc::new(Mutex::new(None));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        connected: Arc::clone(&connected),
+        misbehavior: Arc::clone(&misbehavior),
+        node_id: rand::random(),
+        peer_ids: Arc::clone(&peer_ids),
+        persistent: Arc::clone(&persistent),
+        on_reconnect: Arc::clone(&on_reconnect),
+        best_height: Arc::new(AtomicU32::new(0)),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
+        connected,
+        misbehavior,
+        peer_ids,
+        persistent,
+        on_reconnect,
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        max_peers: None,
     };
     Ok((ctx, handle))
 }
 
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    connected: Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+    misbehavior: Arc<Mutex<HashMap<std::net::IpAddr, u32>>>, // shared with the handle
+    peer_ids: Arc<Mutex<HashMap<u64, peer::Handle>>>,        // shared with the handle
+    persistent: Arc<Mutex<HashSet<std::net::SocketAddr>>>, // reconnected to when lost
+    on_reconnect: ReconnectHook,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    max_peers: Option<usize>, // incoming connections are refused beyond it, unbounded if None
 }
 
 impl Context {
+    /// Refuse incoming connections while `max_peers` peers are connected. Connections this node
+    /// opens itself are not limited.
+    pub fn limit_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = Some(max_peers);
+        self
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
@@ -49,12 +108,11 @@ impl Context {
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
-        ex.spawn(async move {
-            self.dispatch_control(ex_clone).await.unwrap();
-        })
-            .detach();
-        ex.spawn(async move {
+        let listener = ex.spawn(async move {
             Self::listener_loop(listener, control_chan).await.unwrap();
+        });
+        ex.spawn(async move {
+            self.dispatch_control(ex_clone, listener).await.unwrap();
         })
             .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
@@ -76,13 +134,26 @@ impl Context {
         }
     }
 
-    async fn dispatch_control(mut self, ex: Arc<Executor<'_>>) -> std::io::Result<()> {
+    async fn dispatch_control(
+        mut self,
+        ex: Arc<Executor<'_>>,
+        listener: smol::Task<()>,
+    ) -> std::io::Result<()> {
+        // the listener task, taken once the server is stopped
+        let mut listener = Some(listener);
         // read the next control signal
         while let Ok(ctrl) = self.control_chan.recv().await {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
+                    let handle = if listener.is_none() {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::NotConnected,
+                            "the server is stopped",
+                        ))
+                    } else {
+                        self.connect(&addr, ex.clone()).await
+                    };
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
@@ -93,27 +164,100 @@ impl Context {
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    // a peer accepted just before the server stopped is dropped
+                    if listener.is_some() {
+                        self.accept(stream, ex.clone()).await?;
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    // both the reader and the writer report a drop, only log it once
+                    if self.peers.remove(&addr).is_some() {
+                        self.connected.lock().unwrap().remove(&addr);
+                        self.peer_ids
+                            .lock()
+                            .unwrap()
+                            .retain(|_, peer| peer.addr() != &addr);
+                        info!("Peer {} disconnected", addr);
+                        if self.persistent.lock().unwrap().contains(&addr) {
+                            self.schedule_reconnect(addr, RECONNECT_INITIAL_DELAY, &ex);
+                        }
+                    }
+                }
+                ControlSignal::Reconnect(addr, delay) => {
+                    trace!("Processing Reconnect({})", addr);
+                    if listener.is_none() || self.peers.contains_key(&addr) {
+                        continue;
+                    }
+                    match self.connect(&addr, ex.clone()).await {
+                        Ok(mut handle) => {
+                            info!("Reconnected to peer {}", addr);
+                            if let Some(hook) = self.on_reconnect.lock().unwrap().as_ref() {
+                                hook(&mut handle);
+                            }
+                        }
+                        Err(e) => {
+                            let delay = std::cmp::min(delay * 2, RECONNECT_MAX_DELAY);
+                            warn!(
+                                "Error reconnecting to peer {}, retrying in {:?}: {}",
+                                addr, delay, e
+                            );
+                            self.schedule_reconnect(addr, delay, &ex);
+                        }
+                    }
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
                 }
+                ControlSignal::Stop(result_chan) => {
+                    trace!("Processing Stop command");
+                    // dropping the listener closes the socket
+                    if let Some(listener) = listener.take() {
+                        listener.cancel().await;
+                        info!("P2P server stopped listening at {}", self.addr);
+                    }
+                    // no lost peer is reconnected to, the connected ones are dropped once the
+                    // messages queued for them are written
+                    self.persistent.lock().unwrap().clear();
+                    for (_, hd) in self.peers.iter_mut() {
+                        hd.disconnect();
+                    }
+                    result_chan.send(()).unwrap();
+                }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
+    /// Try to connect to a lost persistent peer again after `delay`
+    fn schedule_reconnect(
+        &self,
+        addr: std::net::SocketAddr,
+        delay: Duration,
+        ex: &Arc<Executor<'_>>,
+    ) {
+        debug!("Reconnecting to peer {} in {:?}", addr, delay);
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            smol::Timer::after(delay).await;
+            let _ = control_chan.send(ControlSignal::Reconnect(addr, delay)).await;
+        })
+            .detach();
+    }
+
+    /// Connect to a peer, and register this peer. Fails with `AlreadyExists` if it is connected
+    /// already, a second connection would only deliver every message twice.
     async fn connect(
         &mut self,
         addr: &std::net::SocketAddr,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
+        if self.peers.contains_key(addr) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("already connected to peer {}", addr),
+            ));
+        }
         debug!("Establishing connection to peer {}", addr);
         let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
 
@@ -126,6 +270,24 @@ impl Context {
         stream: Async<net::TcpStream>,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
+        let addr = stream.get_ref().peer_addr()?;
+        let score = self.misbehavior.lock().unwrap().get(&addr.ip()).copied();
+        if score.unwrap_or(0) >= BAN_THRESHOLD {
+            // a banned peer doesn't get back in by connecting again, from any port
+            info!("Refusing incoming peer {}: it is banned", addr);
+            return Ok(());
+        }
+        if let Some(max_peers) = self.max_peers {
+            if self.peers.len() >= max_peers {
+                // dropping the stream closes the connection
+                warn!(
+                    "Refusing incoming peer {:?}: already connected to {} peers",
+                    stream.get_ref().peer_addr().ok(),
+                    self.peers.len()
+                );
+                return Ok(());
+            }
+        }
         self.register(stream, peer::Direction::Incoming, ex).await?;
         Ok(())
     }
@@ -133,15 +295,16 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, handle) = peer::new(&stream, direction)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
+        let reader_control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
 
         // start the reactor for this peer
@@ -160,6 +323,12 @@ impl Context {
                         break;
                     }
                 };
+                // don't allocate for a frame larger than any valid message
+                if msg_size > message::MAX_MESSAGE_SIZE {
+                    warn!("Peer {} sent a {} byte message, disconnecting", addr, msg_size);
+                    handle_copy.clone().disconnect();
+                    break;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -181,15 +350,24 @@ impl Context {
                 }
             }
             // the peer is disconnected
+            reader_control_chan
+                .send(ControlSignal::DroppedPeer(addr))
+                .await
+                .unwrap();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
+        let closing_stream = stream.clone();
         ex.spawn(async move {
             loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                // first, get a message to write from the queue, it ends when the peer handle
+                // disconnects
+                let new_msg = match write_queue.next().await {
+                    Some(msg) => msg,
+                    None => break,
+                };
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -214,7 +392,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, make sure the reader stops too
+            let _ = closing_stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -224,6 +403,7 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        self.connected.lock().unwrap().insert(addr);
         Ok(handle)
     }
 }
@@ -231,6 +411,13 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    connected: Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+    misbehavior: Arc<Mutex<HashMap<std::net::IpAddr, u32>>>, // scores of misbehaving hosts
+    node_id: u64, // random, announced in version messages to tell this node from others
+    peer_ids: Arc<Mutex<HashMap<u64, peer::Handle>>>, // connection to each identified node
+    persistent: Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+    on_reconnect: ReconnectHook,
+    best_height: Arc<AtomicU32>, // height of our tip, kept up to date by the blockchain
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -259,8 +446,147 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
-    pub fn broadcast(&self, msg: message::Message) {
+    /// Connect to the first of `addrs` that accepts, returning its address along with the handle,
+    /// or the error of the last attempt. Stops at an address already connected to, the others
+    /// likely lead to the same peer.
+    pub fn connect_any(
+        &self,
+        addrs: &[std::net::SocketAddr],
+    ) -> std::io::Result<(std::net::SocketAddr, peer::Handle)> {
+        let mut last_error = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no address to connect to",
+        );
+        for addr in addrs {
+            match self.connect(*addr) {
+                Ok(handle) => return Ok((*addr, handle)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(e),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Keep a connection to `addr`: whenever it is lost, reconnect with exponential backoff
+    pub fn add_persistent_peer(&self, addr: std::net::SocketAddr) {
+        self.persistent.lock().unwrap().insert(addr);
+    }
+
+    /// Set what to do with a connection re-established to a persistent peer, typically sending
+    /// the version message
+    pub fn on_reconnect<F>(&self, hook: F)
+    where
+        F: Fn(&mut peer::Handle) + Send + 'static,
+    {
+        *self.on_reconnect.lock().unwrap() = Some(Box::new(hook));
+    }
+
+    /// Addresses of the currently connected peers, in ascending order
+    pub fn peers(&self) -> Vec<std::net::SocketAddr> {
+        let mut peers: Vec<std::net::SocketAddr> =
+            self.connected.lock().unwrap().iter().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// Height of this node's best chain, readable without locking the blockchain
+    pub fn best_height(&self) -> u32 {
+        self.best_height.load(Ordering::Relaxed)
+    }
+
+    /// The counter behind `best_height`, for the blockchain to update
+    pub fn best_height_tracker(&self) -> Arc<AtomicU32> {
+        Arc::clone(&self.best_height)
+    }
+
+    /// Id this node announces in its version messages
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    /// Record the node id a peer announced in its version message, returning whether to keep
+    /// the connection. Of two connections to the same node, one opened by each end, only the
+    /// one opened by the node with the smaller id is kept, which both ends agree on. A
+    /// connection to this node itself is never kept.
+    pub fn identify_peer(&self, peer: &mut peer::Handle, node_id: u64) -> bool {
+        if node_id == self.node_id {
+            info!("Peer {} is this node itself, disconnecting", peer.addr());
+            self.persistent.lock().unwrap().remove(peer.addr());
+            peer.disconnect();
+            return false;
+        }
+        let mut peer_ids = self.peer_ids.lock().unwrap();
+        let existing = match peer_ids.get(&node_id) {
+            Some(existing) if existing.addr() != peer.addr() => existing.clone(),
+            _ => {
+                peer_ids.insert(node_id, peer.clone());
+                return true;
+            }
+        };
+        let opener = |connection: &peer::Handle| match connection.direction() {
+            peer::Direction::Outgoing => self.node_id,
+            peer::Direction::Incoming => node_id,
+        };
+        let (kept, mut dropped) = if opener(peer) < opener(&existing) {
+            (peer.clone(), existing)
+        } else {
+            (existing, peer.clone())
+        };
+        peer_ids.insert(node_id, kept.clone());
+        drop(peer_ids);
+        info!(
+            "Peer {} is connected through {} already, disconnecting it",
+            dropped.addr(),
+            kept.addr()
+        );
+        // the connection that is kept stands in for this one, it isn't opened again
+        self.persistent.lock().unwrap().remove(dropped.addr());
+        dropped.disconnect();
+        kept.addr() == peer.addr()
+    }
+
+    /// Add `penalty` to the misbehavior score of a peer's IP address for a protocol violation,
+    /// and disconnect it once the score reaches `BAN_THRESHOLD`. Returns whether the peer is
+    /// banned, its incoming connections are then refused.
+    pub fn report_misbehavior(&self, peer: &mut peer::Handle, penalty: u32, reason: &str) -> bool {
+        let score = {
+            let mut misbehavior = self.misbehavior.lock().unwrap();
+            let score = misbehavior.entry(peer.addr().ip()).or_insert(0);
+            *score = score.saturating_add(penalty);
+            *score
+        };
+        debug!("Peer {} misbehaved ({}), score {}", peer.addr(), reason, score);
+        if score >= BAN_THRESHOLD {
+            warn!("Banning peer {}: {}", peer.addr(), reason);
+            peer.disconnect();
+            return true;
+        }
+        false
+    }
+
+    /// Whether the misbehavior score of an IP address reached `BAN_THRESHOLD`
+    pub fn is_banned(&self, ip: &std::net::IpAddr) -> bool {
+        self.misbehavior_score(ip) >= BAN_THRESHOLD
+    }
+
+    /// Current misbehavior score of an IP address, shared by all the connections from it
+    pub fn misbehavior_score(&self, ip: &std::net::IpAddr) -> u32 {
+        self.misbehavior.lock().unwrap().get(ip).copied().unwrap_or(0)
+    }
+
+    /// Stop accepting peers and disconnect the connected ones for good, connecting to a peer
+    /// afterwards fails. Returns once the listening socket is closed.
+    pub fn stop(&self) {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::Stop(sender))).unwrap();
+        smol::block_on(receiver).unwrap();
+    }
+
+    /// Queue a message for every connected peer, returning how many peers were connected
+    pub fn broadcast(&self, msg: message::Message) -> usize {
+        let peers = self.connected.lock().unwrap().len();
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
+        peers
     }
 
     pub fn send(&self, receiver: Address, msg: message::Message) {
@@ -270,7 +596,16 @@ impl Handle {
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {
+            control_chan: s,
+            connected: Arc::new(Mutex::new(HashSet::new())),
+            misbehavior: Arc::new(Mutex::new(HashMap::new())),
+            node_id: rand::random(),
+            peer_ids: Arc::new(Mutex::new(HashMap::new())),
+            persistent: Arc::new(Mutex::new(HashSet::new())),
+            on_reconnect: Arc::new(Mutex::new(None)),
+            best_height: Arc::new(AtomicU32::new(0)),
+        };
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -284,5 +619,251 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
+    Reconnect(std::net::SocketAddr, Duration), // with the delay waited before this attempt
     SendToPeer((Address,message::Message)),
+    Stop(oneshot::Sender<()>),
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    fn start_server(addr: std::net::SocketAddr) -> super::Handle {
+        let (msg_tx, _msg_rx) = smol::channel::bounded(10000);
+        let (ctx, handle) = super::new(addr, msg_tx).unwrap();
+        ctx.start().unwrap();
+        handle
+    }
+
+    #[test]
+    fn peers_list_both_ends() {
+        let addr_a = "127.0.0.1:17101".parse().unwrap();
+        let addr_b = "127.0.0.1:17102".parse().unwrap();
+        let a = start_server(addr_a);
+        let b = start_server(addr_b);
+        a.connect(addr_b).unwrap();
+        assert_eq!(a.peers(), vec![addr_b]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while b.peers().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let peers_of_b = b.peers();
+        assert_eq!(peers_of_b.len(), 1);
+        assert!(peers_of_b[0].ip().is_loopback());
+    }
+
+    #[test]
+    fn second_connection_to_a_peer_refused() {
+        let addr_a = "127.0.0.1:17109".parse().unwrap();
+        let addr_b = "127.0.0.1:17110".parse().unwrap();
+        let a = start_server(addr_a);
+        let b = start_server(addr_b);
+        a.connect(addr_b).unwrap();
+        assert_eq!(
+            a.connect(addr_b).unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            a.connect_any(&[addr_b]).unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(a.peers(), vec![addr_b]);
+
+        // give a second incoming connection time to show up at the other end
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(b.peers().len(), 1);
+    }
+
+    #[test]
+    fn reconnect_to_lost_persistent_peer() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:17104").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => return stream,
+                    Err(_) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(e) => panic!("no connection: {}", e),
+                }
+            }
+        };
+
+        let handle = start_server("127.0.0.1:17105".parse().unwrap());
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&reconnects);
+        handle.on_reconnect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        handle.add_persistent_peer(addr);
+        handle.connect(addr).unwrap();
+        // the remote end drops the connection
+        drop(accept());
+        // and the server comes back on its own
+        let _stream = accept();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reconnects.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+        assert_eq!(handle.peers(), vec![addr]);
+    }
+
+    #[test]
+    fn oversized_message_disconnects() {
+        use std::io::{Read, Write};
+
+        let addr: std::net::SocketAddr = "127.0.0.1:17103".parse().unwrap();
+        let (msg_tx, msg_rx) = smol::channel::bounded(10000);
+        let (ctx, handle) = super::new(addr, msg_tx).unwrap();
+        ctx.start().unwrap();
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(&(super::message::MAX_MESSAGE_SIZE + 1).to_be_bytes()).unwrap();
+        // the server closes the connection without waiting for the payload
+        let mut buffer = [0u8; 1];
+        assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+        assert!(msg_rx.try_recv().is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle.peers().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.peers().is_empty());
+    }
+
+    #[test]
+    fn incoming_peers_beyond_the_limit_refused() {
+        use std::io::Read;
+
+        let addr: std::net::SocketAddr = "127.0.0.1:17106".parse().unwrap();
+        let (msg_tx, _msg_rx) = smol::channel::bounded(10000);
+        let (ctx, handle) = super::new(addr, msg_tx).unwrap();
+        ctx.limit_peers(2).start().unwrap();
+
+        let wait_for_peers = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while handle.peers().len() != count && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(handle.peers().len(), count);
+        };
+        let mut accepted = Vec::new();
+        for count in 1..=2 {
+            accepted.push(std::net::TcpStream::connect(addr).unwrap());
+            wait_for_peers(count);
+        }
+        let mut buffer = [0u8; 1];
+        for _ in 0..2 {
+            let mut refused = std::net::TcpStream::connect(addr).unwrap();
+            refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(refused.read(&mut buffer).unwrap(), 0);
+        }
+        assert_eq!(handle.peers().len(), 2);
+
+        // a slot frees up once a peer leaves
+        drop(accepted.pop());
+        wait_for_peers(1);
+        let _stream = std::net::TcpStream::connect(addr).unwrap();
+        wait_for_peers(2);
+    }
+
+    #[test]
+    fn broadcast_counts_connected_peers() {
+        let addr: std::net::SocketAddr = "127.0.0.1:17107".parse().unwrap();
+        let handle = start_server(addr);
+        let ping = || super::message::Message::Ping("hello".to_string());
+        assert_eq!(handle.broadcast(ping()), 0);
+
+        let _streams: Vec<_> = (0..2)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.peers().len() != 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handle.broadcast(ping()), handle.peers().len());
+        assert_eq!(handle.peers().len(), 2);
+    }
+
+    #[test]
+    fn banned_host_refused_on_any_port() {
+        use std::io::Read;
+
+        let addr_a: std::net::SocketAddr = "127.0.0.1:17111".parse().unwrap();
+        let addr_b: std::net::SocketAddr = "127.0.0.1:17112".parse().unwrap();
+        let a = start_server(addr_a);
+        let _b = start_server(addr_b);
+        let mut peer = a.connect(addr_b).unwrap();
+        assert!(a.report_misbehavior(&mut peer, super::BAN_THRESHOLD, "test"));
+        assert!(a.is_banned(&addr_b.ip()));
+
+        // the banned host connecting from another port is dropped right away
+        let mut stream = std::net::TcpStream::connect(addr_a).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buffer = [0u8; 1];
+        assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !a.peers().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(a.peers().is_empty());
+    }
+
+    #[test]
+    fn stopped_server_disconnects_and_refuses() {
+        let addr_a: std::net::SocketAddr = "127.0.0.1:17115".parse().unwrap();
+        let addr_b: std::net::SocketAddr = "127.0.0.1:17116".parse().unwrap();
+        let a = start_server(addr_a);
+        let b = start_server(addr_b);
+        a.connect(addr_b).unwrap();
+        a.add_persistent_peer(addr_b);
+
+        a.stop();
+        assert!(std::net::TcpStream::connect(addr_a).is_err());
+        assert!(a.connect(addr_b).is_err());
+        // the peer is dropped and not reconnected to
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !(a.peers().is_empty() && b.peers().is_empty()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(a.peers().is_empty());
+        assert!(b.peers().is_empty());
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(b.peers().is_empty());
+    }
+
+    #[test]
+    fn peers_resolved_by_name() {
+        let addrs = super::resolve("localhost:6000").unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 6000));
+        assert_eq!(
+            super::resolve("127.0.0.1:6000").unwrap(),
+            vec!["127.0.0.1:6000".parse().unwrap()]
+        );
+        assert_eq!(
+            super::resolve("localhost").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        // an address that refuses the connection is skipped
+        let addr: std::net::SocketAddr = "127.0.0.1:17108".parse().unwrap();
+        let handle = start_server(addr);
+        let refusing = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refusing_addr = refusing.local_addr().unwrap();
+        drop(refusing);
+        let (connected, _peer) = handle.connect_any(&[refusing_addr, addr]).unwrap();
+        assert_eq!(connected, addr);
+        assert!(handle.connect_any(&[refusing_addr]).is_err());
+        assert!(handle.connect_any(&[]).is_err());
+    }
 }
@@ -1,18 +1,51 @@
-use super::message::Message;
+use super::message::{InvItem, Message, PROTOCOL_VERSION};
 use super::peer;
 use super::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
-use crate::types::block::Block;
+use crate::blockchain::{self, BlockError, Blockchain, InsertResult};
+use crate::config::ConsensusParams;
+use crate::metrics;
+use crate::types::block::{Block, Header};
 use crate::types::hash::{Hashable, H256};
 use crate::types::mempool::{self, Mempool};
 use crate::types::transaction::{SignedTransaction, Transaction};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex}; // Import the Blockchain type // Add for orphan block buffer // Assuming you have a Mempool struct defined
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex}; // Import the Blockchain type // Assuming you have a Mempool struct defined
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 
 use std::thread;
 
+/// Most blocks carried by a single `Blocks` message, a larger reply is split across several
+const MAX_BLOCKS_PER_MESSAGE: usize = 64;
+/// Most hashes asked for by a single `GetBlocks`, the rest of a larger request is ignored
+const MAX_GET_BLOCKS: usize = 500;
+/// Most blocks requested or served by a single `GetBlocksByRange`, which fit in one reply
+const MAX_BLOCKS_PER_RANGE: u32 = MAX_BLOCKS_PER_MESSAGE as u32;
+/// Most headers served by a single `GetHeaders`, a full reply tells the peer to ask for more
+const MAX_HEADERS: usize = 2000;
+/// Misbehavior score added for a block that fails validation
+const INVALID_BLOCK_PENALTY: u32 = 20;
+/// Misbehavior score added for a message that can't be decoded
+const MALFORMED_MESSAGE_PENALTY: u32 = 50;
+/// Most orphans of a single peer buffered before it is penalized, an honest peer's orphans are
+/// linked as soon as their parent arrives
+const MAX_ORPHANS_PER_PEER: usize = 16;
+/// Misbehavior score added for each orphan of a peer beyond `MAX_ORPHANS_PER_PEER`
+const ORPHAN_FLOOD_PENALTY: u32 = 10;
+/// Most transaction hashes remembered as already relayed, the oldest is forgotten beyond that
+const SEEN_TRANSACTIONS_CAPACITY: usize = 50000;
+/// How long the parent of an orphan is waited for before it is requested again
+const PARENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most times the parent of an orphan is requested before giving up on it
+const MAX_PARENT_REQUESTS: u32 = 3;
+/// Most compact blocks waiting for transactions, the oldest is dropped beyond that
+const MAX_PENDING_COMPACT_BLOCKS: usize = 16;
+/// How long a compact block waits for its missing transactions before the full block is
+/// requested instead
+const COMPACT_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[cfg(any(test, test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test, test_utilities))]
@@ -23,9 +56,60 @@ pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>, // Add the blockchain field
-    orphan_blocks: HashMap<H256, Block>,
+    blockchain: Arc<Mutex<Blockchain>>, // Add the blockchain field, which also buffers orphans
     mempool: Arc<Mutex<Mempool>>,
+    peer_heights: Arc<Mutex<HashMap<SocketAddr, u32>>>, // best heights advertised in handshakes
+    pending_compact_blocks: Arc<Mutex<HashMap<H256, PendingCompactBlock>>>,
+    headers: Arc<Mutex<HashMap<H256, Header>>>, // validated headers whose block is still missing
+    seen_transactions: Arc<Mutex<RecentHashes>>, // transactions already received, relayed once
+    parent_requests: Arc<Mutex<HashMap<H256, ParentRequest>>>, // missing parents of orphans
+}
+
+/// A missing parent asked for with `GetBlocks`, until it arrives or too many requests went
+/// unanswered
+struct ParentRequest {
+    sent: Instant,
+    attempts: u32,
+}
+
+/// The most recently inserted hashes, up to a capacity
+struct RecentHashes {
+    capacity: usize,
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>, // insertion order, the oldest is dropped first
+}
+
+impl RecentHashes {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember a hash, returning whether it was new
+    fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// A compact block waiting for transactions that were not in the mempool
+#[derive(Clone)]
+struct PendingCompactBlock {
+    header: Header,
+    coinbase: Option<SignedTransaction>,
+    tx_hashes: Vec<H256>,
+    received: Instant,
 }
 
 impl Worker {
@@ -41,8 +125,12 @@ impl Worker {
             num_worker,
             server: server.clone(),
             blockchain: blockchain, // Assign the blockchain to the field
-            orphan_blocks: HashMap::new(),
             mempool: mempool,
+            peer_heights: Arc::new(Mutex::new(HashMap::new())),
+            pending_compact_blocks: Arc::new(Mutex::new(HashMap::new())),
+            headers: Arc::new(Mutex::new(HashMap::new())),
+            seen_transactions: Arc::new(Mutex::new(RecentHashes::new(SEEN_TRANSACTIONS_CAPACITY))),
+            parent_requests: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -55,72 +143,399 @@ impl Worker {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::spawn(move || {
+            // stop along with the workers, once no more messages can arrive
+            while !self.msg_chan.is_closed() {
+                thread::sleep(Duration::from_secs(1));
+                self.retry_parent_requests(Instant::now());
+                self.expire_compact_blocks(Instant::now());
+            }
+        });
     }
 
-    fn process_block(&mut self, block: &Block) -> bool {
-        // PoW check
-        if block.hash() > block.get_difficulty() {
-            warn!("Block's hash does not satisfy PoW requirement.");
-            return false;
+    /// Ask for a missing parent, unless it was already asked for
+    fn request_parent(&self, parent: H256) {
+        let mut parent_requests = self.parent_requests.lock().unwrap();
+        if parent_requests.contains_key(&parent) {
+            return;
         }
+        parent_requests.insert(
+            parent,
+            ParentRequest {
+                sent: Instant::now(),
+                attempts: 1,
+            },
+        );
+        drop(parent_requests);
+        self.server.broadcast(Message::GetBlocks(vec![parent]));
+    }
 
-        let mut blockchain = self.blockchain.lock().unwrap();
-
-        // Check if the difficulty is as expected
-        let parent_difficulty = if !blockchain.contains_block(&block.get_parent()) {
-            block.get_difficulty()
-        } else {
-            match blockchain.get_block(&block.get_parent()) {
-                Some(parent_block) => parent_block.get_difficulty(),
-                None => return false,
+    /// Ask again for the parents that went unanswered for `PARENT_REQUEST_TIMEOUT` at `now`, and
+    /// give up on those asked for `MAX_PARENT_REQUESTS` times
+    fn retry_parent_requests(&self, now: Instant) {
+        let mut retries = Vec::new();
+        self.parent_requests
+            .lock()
+            .unwrap()
+            .retain(|parent, request| {
+                if now.saturating_duration_since(request.sent) < PARENT_REQUEST_TIMEOUT {
+                    return true;
+                }
+                if request.attempts >= MAX_PARENT_REQUESTS {
+                    warn!(
+                        "Giving up on block {} after {} unanswered requests",
+                        parent, request.attempts
+                    );
+                    return false;
+                }
+                request.sent = now;
+                request.attempts += 1;
+                retries.push(*parent);
+                true
+            });
+        if !retries.is_empty() {
+            debug!("Requesting {} missing parents again", retries.len());
+            for chunk in retries.chunks(MAX_GET_BLOCKS) {
+                self.server.broadcast(Message::GetBlocks(chunk.to_vec()));
             }
-        };
-        if block.get_difficulty() != parent_difficulty {
-            warn!("Block's difficulty doesn't match with the parent's difficulty.");
-            return false;
         }
+    }
+
+    fn process_block(&mut self, block: &Block, peer: &mut peer::Handle) -> bool {
+        // the block answers any request for it
+        self.parent_requests.lock().unwrap().remove(&block.hash());
 
-        // Check if the block's parent exists
-        if !blockchain.contains_block(&block.get_parent()) {
-            // Add to orphan buffer
-            self.orphan_blocks.insert(block.get_parent(), block.clone());
-            // Send GetBlocks message with this parent hash
-            println!(
-                "send GetBlocks msg with parent hash: {}, in process_block()",
-                block.get_parent()
+        let params = self.blockchain.lock().unwrap().params().clone();
+        // verify every signature across cores before taking the lock, applying the block under
+        // it then only hits the signature cache
+        if let Err(e) = blockchain::check_block(block, &params) {
+            warn!(
+                "Block {} from {} rejected: {}",
+                block.hash(),
+                peer.addr(),
+                e
             );
             self.server
-                .broadcast(Message::GetBlocks(vec![block.get_parent()]));
+                .report_misbehavior(peer, INVALID_BLOCK_PENALTY, &e.to_string());
             return false;
         }
 
+        let mut blockchain = self.blockchain.lock().unwrap();
+        match blockchain.check_block_context(block) {
+            Ok(()) => {}
+            Err(BlockError::UnknownParent(parent)) => {
+                // Add to the orphan buffer shared by all workers
+                blockchain.add_orphan(block.clone(), *peer.addr());
+                if blockchain.orphans_from(peer.addr()) > MAX_ORPHANS_PER_PEER {
+                    warn!(
+                        "Peer {} has more than {} orphan blocks buffered",
+                        peer.addr(),
+                        MAX_ORPHANS_PER_PEER
+                    );
+                    self.server.report_misbehavior(
+                        peer,
+                        ORPHAN_FLOOD_PENALTY,
+                        "too many unconnectable blocks",
+                    );
+                }
+                // Send GetBlocks message with this parent hash
+                debug!(
+                    "Block {} from {} has an unknown parent {}, requesting it",
+                    block.hash(),
+                    peer.addr(),
+                    parent
+                );
+                self.request_parent(parent);
+                return false;
+            }
+            Err(e) => {
+                warn!(
+                    "Block {} from {} rejected: {}",
+                    block.hash(),
+                    peer.addr(),
+                    e
+                );
+                self.server
+                    .report_misbehavior(peer, INVALID_BLOCK_PENALTY, &e.to_string());
+                return false;
+            }
+        }
+
         // If all checks passed, add block to the blockchain
-        println!(
-            "adding block: {} to blockchain, in process_block()",
-            block.hash()
-        );
+        debug!("Inserting block {} from {}", block.hash(), peer.addr());
 
-        blockchain.insert(&block);
-        true
+        let result = blockchain.insert(&block);
+        log_insert(&block, result);
+        // nothing to relay for a block we had, such as our own echoed back
+        !matches!(result, InsertResult::AlreadyKnown | InsertResult::Rejected)
     }
 
-    fn process_orphan_blocks(&mut self, parent_hash: H256) {
+    /// Link the buffered descendants of a newly inserted block, returning their hashes
+    fn process_orphan_blocks(&mut self, parent_hash: H256) -> Vec<H256> {
         let mut blockchain = self.blockchain.lock().unwrap();
+        let mut linked = Vec::new();
+        let mut parents = vec![parent_hash];
 
-        // Get orphan blocks associated with the parent_hash
-        let mut orphan_block = self.orphan_blocks.remove(&parent_hash);
+        while let Some(parent_hash) = parents.pop() {
+            for block in blockchain.take_orphans_of(&parent_hash) {
+                // it passed `check_block` on arrival, what depends on the parent is checked now
+                if let Err(e) = blockchain.check_block_context(&block) {
+                    let dropped = blockchain.discard_orphans_below(&block.hash());
+                    warn!(
+                        "Orphan block {} rejected with {} descendants: {}",
+                        block.hash(),
+                        dropped,
+                        e
+                    );
+                    continue;
+                }
+                // Add the block to the blockchain
+                debug!(
+                    "Inserting orphan block {} below {}",
+                    block.hash(),
+                    parent_hash
+                );
+                log_insert(&block, blockchain.insert(&block));
+                // its own orphans can be linked next
+                parents.push(block.hash());
+                linked.push(block.hash());
+            }
+        }
+        linked
+    }
 
-        while let Some(block) = orphan_block {
-            // Add the block to the blockchain
-            println!("adding block: {} to blockchain", block.hash());
-            blockchain.insert(&block);
-            // Get the next orphan block
-            orphan_block = self.orphan_blocks.remove(&block.hash());
+    /// Ask a peer for the next blocks it has beyond our tip, if any
+    fn request_missing_blocks(&self, peer: &mut peer::Handle) {
+        let peer_height = match self.peer_heights.lock().unwrap().get(peer.addr()) {
+            Some(height) => *height,
+            None => return,
+        };
+        let blockchain = self.blockchain.lock().unwrap();
+        let our_height = blockchain.best_height();
+        if peer_height > our_height {
+            // a locator rather than our height, so a peer on another branch finds where we fork
+            let locator = blockchain.block_locator();
+            drop(blockchain);
+            debug!(
+                "Requesting blocks beyond height {} from {}",
+                our_height,
+                peer.addr()
+            );
+            peer.write(Message::GetBlocksAfter { locator });
         }
     }
 
+    /// Validate and insert blocks received from a peer, then relay the new ones
+    fn process_blocks(&mut self, blocks: Vec<Block>, peer: &mut peer::Handle) {
+        // println!("receiving Blocks msg");
+        let mut new_hashes = Vec::new();
+        for block in blocks {
+            if !self.process_block(&block, peer) {
+                continue;
+            }
+            // println!("adding new chain");
+            new_hashes.push(block.hash());
+            new_hashes.extend(self.process_orphan_blocks(block.hash()));
+        }
+        {
+            let mut headers = self.headers.lock().unwrap();
+            for hash in new_hashes.iter() {
+                headers.remove(hash);
+            }
+        }
+        let progressed = !new_hashes.is_empty();
+        if progressed {
+            self.revalidate_mempool();
+            // println!("broadcasting NewBlockHashes");
+            self.server.broadcast(Message::NewBlockHashes(new_hashes));
+        }
+        // keep downloading from a peer that is ahead of us, unless it stopped
+        // serving what it advertised
+        let peer_height = self.peer_heights.lock().unwrap().get(peer.addr()).copied();
+        if let Some(peer_height) = peer_height {
+            let our_height = self.blockchain.lock().unwrap().best_height();
+            if peer_height > our_height {
+                if progressed {
+                    self.request_missing_blocks(peer);
+                } else {
+                    warn!(
+                        "Peer {} advertised height {} but only served up to {}",
+                        peer.addr(),
+                        peer_height,
+                        our_height
+                    );
+                    self.peer_heights
+                        .lock()
+                        .unwrap()
+                        .insert(*peer.addr(), our_height);
+                }
+            }
+        }
+    }
+
+    /// Check that headers received from a peer link up and carry proof of work, remember them,
+    /// then request the blocks we don't have and the headers that follow
+    fn process_headers(&mut self, headers: Vec<Header>, peer: &mut peer::Handle) {
+        let mut missing = Vec::new();
+        {
+            let blockchain = self.blockchain.lock().unwrap();
+            let mut known_headers = self.headers.lock().unwrap();
+            let mut parent = match headers.first() {
+                Some(header) => header.get_parent(),
+                None => return,
+            };
+            if !blockchain.contains_block(&parent) && !known_headers.contains_key(&parent) {
+                debug!("Headers from {} don't connect to our chain", peer.addr());
+                return;
+            }
+            for header in headers.iter() {
+                let hash = header.hash();
+                if header.get_parent() != parent || !hash.meets_difficulty(&header.get_difficulty())
+                {
+                    warn!("Invalid header {} from {}", hash, peer.addr());
+                    drop(blockchain);
+                    drop(known_headers);
+                    self.server.report_misbehavior(
+                        peer,
+                        INVALID_BLOCK_PENALTY,
+                        "header doesn't link or fails proof of work",
+                    );
+                    return;
+                }
+                if !blockchain.contains_block(&hash) {
+                    known_headers.insert(hash, header.clone());
+                    missing.push(hash);
+                }
+                parent = hash;
+            }
+        }
+        if !missing.is_empty() {
+            debug!(
+                "Requesting {} block bodies from {}",
+                missing.len(),
+                peer.addr()
+            );
+            request_blocks(peer, &missing);
+        }
+        if headers.len() >= MAX_HEADERS {
+            let last = headers.last().unwrap().hash();
+            peer.write(Message::GetHeaders {
+                locator: vec![last],
+            });
+        }
+    }
+
+    /// Rebuild the block of a compact block from the mempool and `received` transactions, or
+    /// return the hashes of the transactions that are missing. The rebuilt block is checked like
+    /// any other, so transactions in another order than its header commits to fail the merkle
+    /// root check instead of being stored under the header's hash.
+    fn reconstruct_block(
+        &self,
+        pending: &PendingCompactBlock,
+        received: &HashMap<H256, SignedTransaction>,
+    ) -> Result<Block, Vec<H256>> {
+        let mempool = self.mempool.lock().unwrap();
+        let mut transactions: Vec<SignedTransaction> = pending.coinbase.iter().cloned().collect();
+        let mut missing = Vec::new();
+        for tx_hash in pending.tx_hashes.iter() {
+            match received
+                .get(tx_hash)
+                .or_else(|| mempool.get_transaction(tx_hash))
+            {
+                Some(tx) => transactions.push(tx.clone()),
+                None => missing.push(*tx_hash),
+            }
+        }
+        if missing.is_empty() {
+            Ok(Block::from_parts(pending.header.clone(), transactions))
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// Keep a compact block until its missing transactions arrive, dropping the oldest one
+    /// waiting beyond `MAX_PENDING_COMPACT_BLOCKS`
+    fn buffer_compact_block(&self, block_hash: H256, pending: PendingCompactBlock) {
+        let mut pending_compact_blocks = self.pending_compact_blocks.lock().unwrap();
+        pending_compact_blocks.insert(block_hash, pending);
+        if pending_compact_blocks.len() > MAX_PENDING_COMPACT_BLOCKS {
+            let oldest = pending_compact_blocks
+                .iter()
+                .min_by_key(|(_, pending)| pending.received)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                debug!("Dropping compact block {}, too many are waiting", oldest);
+                pending_compact_blocks.remove(&oldest);
+            }
+        }
+    }
+
+    /// Give up on the compact blocks that waited `COMPACT_BLOCK_TIMEOUT` at `now` for their
+    /// transactions, and ask for the full blocks instead
+    fn expire_compact_blocks(&self, now: Instant) {
+        let mut expired = Vec::new();
+        self.pending_compact_blocks
+            .lock()
+            .unwrap()
+            .retain(|block_hash, pending| {
+                if now.saturating_duration_since(pending.received) < COMPACT_BLOCK_TIMEOUT {
+                    return true;
+                }
+                expired.push(*block_hash);
+                false
+            });
+        if !expired.is_empty() {
+            debug!(
+                "{} compact blocks are still missing transactions, requesting the full blocks",
+                expired.len()
+            );
+            for chunk in expired.chunks(MAX_GET_BLOCKS) {
+                self.server.broadcast(Message::GetBlocks(chunk.to_vec()));
+            }
+        }
+    }
+
+    /// Finish the compact blocks that were waiting for transactions from a peer, falling back to
+    /// requesting the full block when some are still missing
+    fn complete_compact_blocks(
+        &mut self,
+        received: &HashMap<H256, SignedTransaction>,
+        peer: &mut peer::Handle,
+    ) {
+        let pending: Vec<(H256, PendingCompactBlock)> = self
+            .pending_compact_blocks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, pending)| {
+                pending
+                    .tx_hashes
+                    .iter()
+                    .any(|tx_hash| received.contains_key(tx_hash))
+            })
+            .map(|(hash, pending)| (*hash, pending.clone()))
+            .collect();
+        for (block_hash, pending) in pending {
+            self.pending_compact_blocks
+                .lock()
+                .unwrap()
+                .remove(&block_hash);
+            match self.reconstruct_block(&pending, received) {
+                Ok(block) => self.process_blocks(vec![block], peer),
+                Err(_) => peer.write(Message::GetBlocks(vec![block_hash])),
+            }
+        }
+    }
+
+    /// Drop mempool transactions invalidated by newly inserted blocks
+    fn revalidate_mempool(&self) {
+        // revalidate against a copy, so the chain isn't locked for the whole mempool
+        let state = self.blockchain.lock().unwrap().get_state().clone();
+        self.mempool.lock().unwrap().revalidate(&state);
+    }
+
     fn worker_loop(&mut self) {
-        print!("worker started");
+        debug!("Network worker started");
         loop {
             let result = smol::block_on(self.msg_chan.recv());
             if let Err(e) = result {
@@ -129,8 +544,71 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if self.server.is_banned(&peer.addr().ip()) {
+                continue;
+            }
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Malformed message from {}: {}", peer.addr(), e);
+                    self.server.report_misbehavior(
+                        &mut peer,
+                        MALFORMED_MESSAGE_PENALTY,
+                        "malformed message",
+                    );
+                    continue;
+                }
+            };
             match msg {
+                Message::Version {
+                    protocol_version,
+                    best_height,
+                    genesis,
+                    node_id,
+                } => {
+                    if protocol_version != PROTOCOL_VERSION {
+                        warn!(
+                            "Disconnecting peer {}: protocol version {} is incompatible with ours ({})",
+                            peer.addr(),
+                            protocol_version,
+                            PROTOCOL_VERSION
+                        );
+                        peer.disconnect();
+                        continue;
+                    }
+                    let our_genesis = self.blockchain.lock().unwrap().genesis_hash();
+                    if genesis != our_genesis {
+                        warn!(
+                            "Disconnecting peer {}: its genesis block {} differs from ours ({})",
+                            peer.addr(),
+                            genesis,
+                            our_genesis
+                        );
+                        peer.disconnect();
+                        continue;
+                    }
+                    if !self.server.identify_peer(&mut peer, node_id) {
+                        continue;
+                    }
+                    info!("Peer {} is at height {}", peer.addr(), best_height);
+                    self.peer_heights
+                        .lock()
+                        .unwrap()
+                        .insert(*peer.addr(), best_height);
+                    // the connecting side speaks first, answer it with our own version
+                    if peer.direction() == peer::Direction::Incoming {
+                        let best_height = self.server.best_height();
+                        let node_id = self.server.node_id();
+                        peer.write(Message::version(best_height, our_genesis, node_id));
+                    }
+                    peer.write(Message::VerAck);
+                    self.request_missing_blocks(&mut peer);
+                }
+                Message::VerAck => {
+                    debug!("Handshake with {} complete", peer.addr());
+                    // blocks are requested on the version, pending transactions once connected
+                    peer.write(Message::GetMempool);
+                }
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -139,42 +617,112 @@ impl Worker {
                     debug!("Pong: {}", nonce);
                 }
                 Message::NewBlockHashes(hashes) => {
-                    println!("receiving NewBlockHashes msg");
+                    debug!("{} announced {} blocks", peer.addr(), hashes.len());
                     let blockchain = self.blockchain.lock().unwrap();
                     let unknown_hashes: Vec<H256> = hashes
                         .into_iter()
                         .filter(|hash| !blockchain.contains_block(hash))
                         .collect();
-                    if !unknown_hashes.is_empty() {
-                        peer.write(Message::GetBlocks(unknown_hashes));
-                    }
+                    request_blocks(&mut peer, &unknown_hashes);
                 }
                 Message::GetBlocks(hashes) => {
-                    // println!("receiving GetBlocks msg");
+                    if hashes.len() > MAX_GET_BLOCKS {
+                        warn!(
+                            "{} asked for {} blocks, serving the first {}",
+                            peer.addr(),
+                            hashes.len(),
+                            MAX_GET_BLOCKS
+                        );
+                    }
                     let blockchain = self.blockchain.lock().unwrap();
                     let blocks: Vec<Block> = hashes
                         .iter()
+                        .take(MAX_GET_BLOCKS)
                         .filter_map(|hash: &H256| blockchain.get_block(hash).cloned())
                         .collect();
-                    if !blocks.is_empty() {
-                        peer.write(Message::Blocks(blocks));
-                    }
+                    drop(blockchain);
+                    send_blocks(&mut peer, blocks);
+                }
+                Message::GetBlocksByRange { start, end } => {
+                    let end = std::cmp::min(end, start.saturating_add(MAX_BLOCKS_PER_RANGE - 1));
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let blocks: Vec<Block> = blockchain
+                        .all_blocks_in_longest_chain()
+                        .iter()
+                        .skip(start as usize)
+                        .take((end as usize + 1).saturating_sub(start as usize))
+                        .filter_map(|hash| blockchain.get_block(hash).cloned())
+                        .collect();
+                    // always answer, an empty reply tells the peer we have nothing more
+                    peer.write(Message::Blocks(blocks));
                 }
                 Message::Blocks(blocks) => {
-                    // println!("receiving Blocks msg");
-                    let mut new_hashes = Vec::new();
-                    for block in blocks {
-                        println!("{}", block.hash());
-                        if !self.process_block(&block) {
-                            continue;
-                        }
-                        // println!("adding new chain");
-                        new_hashes.push(block.hash());
-                        self.process_orphan_blocks(block.hash());
+                    self.process_blocks(blocks, &mut peer);
+                }
+                Message::GetHeaders { locator } => {
+                    let headers = self
+                        .blockchain
+                        .lock()
+                        .unwrap()
+                        .headers_after(&locator, MAX_HEADERS);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    self.process_headers(headers, &mut peer);
+                }
+                Message::GetBlocksAfter { locator } => {
+                    let blocks = self
+                        .blockchain
+                        .lock()
+                        .unwrap()
+                        .blocks_after(&locator, MAX_BLOCKS_PER_MESSAGE);
+                    // always answer, an empty reply tells the peer we have nothing more
+                    peer.write(Message::Blocks(blocks));
+                }
+                Message::CompactBlock {
+                    header,
+                    coinbase,
+                    tx_hashes,
+                } => {
+                    let block_hash = header.hash();
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if blockchain.contains_block(&block_hash) {
+                        continue;
                     }
-                    if !new_hashes.is_empty() {
-                        // println!("broadcasting NewBlockHashes");
-                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                    let params = blockchain.params().clone();
+                    drop(blockchain);
+                    // nothing is looked up, buffered or requested for a header without work
+                    if let Err(e) = check_compact_block(&header, tx_hashes.len(), &params) {
+                        warn!(
+                            "Compact block {} from {} rejected: {}",
+                            block_hash,
+                            peer.addr(),
+                            e
+                        );
+                        self.server.report_misbehavior(
+                            &mut peer,
+                            INVALID_BLOCK_PENALTY,
+                            &e.to_string(),
+                        );
+                        continue;
+                    }
+                    let pending = PendingCompactBlock {
+                        header,
+                        coinbase,
+                        tx_hashes,
+                        received: Instant::now(),
+                    };
+                    match self.reconstruct_block(&pending, &HashMap::new()) {
+                        Ok(block) => self.process_blocks(vec![block], &mut peer),
+                        Err(missing) => {
+                            debug!(
+                                "Compact block {} misses {} transactions",
+                                block_hash,
+                                missing.len()
+                            );
+                            self.buffer_compact_block(block_hash, pending);
+                            peer.write(Message::GetTransactions(missing));
+                        }
                     }
                 }
 
@@ -183,10 +731,11 @@ impl Worker {
                     let blockchain = self.blockchain.lock().unwrap();
                     let mempool = self.mempool.lock().unwrap();
 
+                    // a transaction mined only on a losing branch is wanted again
                     let unknown_hashes: Vec<H256> = tx_hashes
                         .into_iter()
                         .filter(|hash| {
-                            !blockchain.contains_transaction(hash)
+                            blockchain.get_transaction_block(hash).is_none()
                                 && !mempool.contains_transaction(hash)
                         })
                         .collect();
@@ -197,11 +746,17 @@ impl Worker {
                 }
                 Message::GetTransactions(tx_hashes) => {
                     // println!("Receiving GetTransactions msg");
+                    // transactions of a compact block may already have left the mempool
+                    let blockchain = self.blockchain.lock().unwrap();
                     let mempool = self.mempool.lock().unwrap();
 
                     let transactions: Vec<SignedTransaction> = tx_hashes
                         .iter()
-                        .filter_map(|hash| mempool.get_transaction(hash))
+                        .filter_map(|hash| {
+                            mempool
+                                .get_transaction(hash)
+                                .or_else(|| blockchain.get_transaction(hash))
+                        })
                         .cloned()
                         .collect();
 
@@ -209,30 +764,167 @@ impl Worker {
                         peer.write(Message::Transactions(transactions));
                     }
                 }
+                Message::Inv(items) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let mempool = self.mempool.lock().unwrap();
+                    let unknown: Vec<InvItem> = items
+                        .into_iter()
+                        .filter(|item| match item {
+                            InvItem::Block(hash) => !blockchain.contains_block(hash),
+                            InvItem::Tx(hash) => {
+                                blockchain.get_transaction_block(hash).is_none()
+                                    && !mempool.contains_transaction(hash)
+                            }
+                        })
+                        .collect();
+                    // blocks and transactions are requested together
+                    if !unknown.is_empty() {
+                        peer.write(Message::GetData(unknown));
+                    }
+                }
+                Message::GetData(items) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let mempool = self.mempool.lock().unwrap();
+                    let mut blocks = Vec::new();
+                    let mut transactions = Vec::new();
+                    for item in items.iter() {
+                        match item {
+                            InvItem::Block(hash) => {
+                                blocks.extend(blockchain.get_block(hash).cloned())
+                            }
+                            InvItem::Tx(hash) => transactions.extend(
+                                mempool
+                                    .get_transaction(hash)
+                                    .or_else(|| blockchain.get_transaction(hash))
+                                    .cloned(),
+                            ),
+                        }
+                    }
+                    drop(mempool);
+                    drop(blockchain);
+                    if !transactions.is_empty() {
+                        peer.write(Message::Transactions(transactions));
+                    }
+                    // the peer fills in the bodies it has, and asks for the others
+                    for block in blocks.iter() {
+                        peer.write(Message::compact_block(block));
+                    }
+                }
+                Message::GetMempool => {
+                    let tx_hashes: Vec<H256> = self
+                        .mempool
+                        .lock()
+                        .unwrap()
+                        .export()
+                        .iter()
+                        .map(|tx| tx.hash())
+                        .collect();
+                    debug!(
+                        "Sending {} mempool hashes to {}",
+                        tx_hashes.len(),
+                        peer.addr()
+                    );
+                    if !tx_hashes.is_empty() {
+                        peer.write(Message::NewTransactionHashes(tx_hashes));
+                    }
+                }
                 Message::Transactions(transactions) => {
                     // println!("Receiving Transactions msg");
+                    let received: HashMap<H256, SignedTransaction> = transactions
+                        .iter()
+                        .map(|tx| (tx.hash(), tx.clone()))
+                        .collect();
                     let mut mempool = self.mempool.lock().unwrap();
+                    let mut seen = self.seen_transactions.lock().unwrap();
 
+                    let mut accepted = Vec::new();
                     for tx in transactions {
-                        if !mempool.contains_transaction(&tx.hash())
-                            && mempool.is_valid(&tx)
-                            && self
-                                .blockchain
-                                .lock()
-                                .unwrap()
-                                .get_state()
-                                .is_transaction_valid(&tx)
-                        {
-                            mempool.add_transaction(tx);
+                        let tx_hash = tx.hash();
+                        // checked against the tip state, or queued if its nonce is ahead. Only
+                        // an accepted transaction counts as seen, one rejected now, e.g. before
+                        // its funds arrived, is relayed once it is accepted.
+                        if mempool.add_transaction(tx) && seen.insert(tx_hash) {
+                            accepted.push(tx_hash);
                         }
                     }
+                    drop(seen);
+                    drop(mempool);
+                    // announce each transaction once, so it doesn't bounce between peers
+                    if !accepted.is_empty() {
+                        self.server
+                            .broadcast(Message::NewTransactionHashes(accepted));
+                    }
+                    self.complete_compact_blocks(&received, &mut peer);
+                }
+                #[allow(unreachable_patterns)]
+                _ => {
+                    // a message this node doesn't handle mustn't take the worker down
+                    warn!("Ignoring an unexpected message from {}", peer.addr());
                 }
-                _ => unimplemented!(),
             }
         }
     }
 }
 
+/// Check the header of a compact block with `tx_count` transaction hashes before its
+/// transactions are looked for: its proof of work at the chain's difficulty, and its size
+fn check_compact_block(
+    header: &Header,
+    tx_count: usize,
+    params: &ConsensusParams,
+) -> Result<(), BlockError> {
+    if header.get_difficulty() != params.difficulty {
+        return Err(BlockError::WrongDifficulty);
+    }
+    if !header.hash().meets_difficulty(&header.get_difficulty()) {
+        return Err(BlockError::ProofOfWork);
+    }
+    if tx_count > params.max_block_size {
+        return Err(BlockError::TooManyTransactions {
+            count: tx_count,
+            limit: params.max_block_size,
+        });
+    }
+    Ok(())
+}
+
+/// Ask a peer for blocks by hash, in requests of at most `MAX_GET_BLOCKS` hashes
+fn request_blocks(peer: &mut peer::Handle, hashes: &[H256]) {
+    for chunk in hashes.chunks(MAX_GET_BLOCKS) {
+        peer.write(Message::GetBlocks(chunk.to_vec()));
+    }
+}
+
+/// Send blocks to a peer in messages of at most `MAX_BLOCKS_PER_MESSAGE` blocks
+fn send_blocks(peer: &mut peer::Handle, blocks: Vec<Block>) {
+    for chunk in blocks.chunks(MAX_BLOCKS_PER_MESSAGE) {
+        peer.write(Message::Blocks(chunk.to_vec()));
+    }
+}
+
+fn log_insert(block: &Block, result: InsertResult) {
+    let block_hash = block.hash();
+    let arrival = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    metrics::global().block_received();
+    metrics::global().block_propagated(block_hash, block.get_timestamp(), arrival);
+    match result {
+        InsertResult::Extended => debug!("Block {} extended the longest chain", block_hash),
+        InsertResult::SideBranch => debug!("Block {} went on a side branch", block_hash),
+        InsertResult::AlreadyKnown => debug!("Block {} was already known", block_hash),
+        InsertResult::Rejected => debug!("Block {} was rejected", block_hash),
+        InsertResult::Reorg {
+            old_tip,
+            common_ancestor,
+        } => info!(
+            "Block {} reorganized the chain: old tip {}, common ancestor {}",
+            block_hash, old_tip, common_ancestor
+        ),
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
@@ -249,6 +941,11 @@ impl TestMsgSender {
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
         let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bytes)
+    }
+
+    /// Deliver raw bytes, as received from the network, instead of an encoded message
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -257,22 +954,32 @@ impl TestMsgSender {
 #[cfg(any(test, test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let (test_msg_sender, server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+    let block_hashes = blockchain.lock().unwrap().all_blocks_in_longest_chain(); // Assuming this method exists based on description.
+    (test_msg_sender, server_receiver, block_hashes)
+}
+
+#[cfg(any(test, test_utilities))]
+/// like `generate_test_worker_and_start`, but working on the given blockchain, which may be
+/// shared with other workers
+fn generate_test_worker_with_blockchain(
+    blockchain: &Arc<Mutex<Blockchain>>,
+) -> (TestMsgSender, ServerTestReceiver) {
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
     // Initialize the mempool
     let mempool = Mempool::new();
     let shared_mempool = Arc::new(Mutex::new(mempool));
-    let blockchain = Blockchain::new();
-    let block_hashes = blockchain.all_blocks_in_longest_chain(); // Assuming this method exists based on description.
     let worker = Worker::new(
         1,
         msg_chan,
         &server,
-        Arc::new(Mutex::new(blockchain)),
+        Arc::clone(blockchain),
         Arc::clone(&shared_mempool),
     );
     worker.start();
-    (test_msg_sender, server_receiver, block_hashes)
+    (test_msg_sender, server_receiver)
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -280,11 +987,41 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
 #[cfg(test)]
 mod test {
     use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use crate::types::hash::{Hashable, H256};
     use ntest::timeout;
 
-    use super::super::message::Message;
-    use super::generate_test_worker_and_start;
+    use super::super::message::{InvItem, Message, PROTOCOL_VERSION};
+    use super::super::server::{Handle as ServerHandle, BAN_THRESHOLD};
+    use super::{
+        generate_test_worker_and_start, generate_test_worker_with_blockchain, RecentHashes,
+        TestMsgSender, Worker, COMPACT_BLOCK_TIMEOUT, INVALID_BLOCK_PENALTY,
+        MAX_BLOCKS_PER_MESSAGE, MAX_GET_BLOCKS, MAX_ORPHANS_PER_PEER, MAX_PARENT_REQUESTS,
+        MAX_PENDING_COMPACT_BLOCKS, ORPHAN_FLOOD_PENALTY, PARENT_REQUEST_TIMEOUT,
+    };
+    use crate::blockchain::Blockchain;
+    use crate::config::ConsensusParams;
+    use crate::types::block::{generate_block_with_difficulty, Block, EASIEST_DIFFICULTY};
+    use crate::types::block::{generate_easiest_block, generate_easiest_block_with_state_root};
+    use crate::types::key_pair;
+    use crate::types::mempool::Mempool;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::signature_cache;
+    use crate::types::state::{AccountAddress, State, BLOCK_REWARD};
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use ring::signature::KeyPair;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn recent_hashes_forget_the_oldest() {
+        let mut recent = RecentHashes::new(2);
+        let hashes: Vec<H256> = (0..3u8).map(|i| [i; 32].into()).collect();
+        assert!(recent.insert(hashes[0]));
+        assert!(!recent.insert(hashes[0]));
+        assert!(recent.insert(hashes[1]));
+        assert!(recent.insert(hashes[2]));
+        assert!(recent.insert(hashes[0]));
+        assert!(!recent.insert(hashes[2]));
+    }
 
     #[test]
     #[timeout(60000)]
@@ -314,6 +1051,39 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn large_get_blocks_split_and_capped() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..150 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        let hashes = blockchain.all_blocks_in_longest_chain();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+
+        // the handle is dropped once the request was served
+        let replies = |mut peer_receiver: super::PeerTestReceiver| {
+            let mut sizes = Vec::new();
+            while let Some(reply) = peer_receiver.next() {
+                match reply {
+                    Message::Blocks(blocks) => sizes.push(blocks.len()),
+                    _ => panic!("expected blocks"),
+                }
+            }
+            sizes
+        };
+        let sizes = replies(test_msg_sender.send(Message::GetBlocks(hashes.clone())));
+        assert!(sizes.iter().all(|&size| size <= MAX_BLOCKS_PER_MESSAGE));
+        assert_eq!(sizes.iter().sum::<usize>(), hashes.len());
+        assert_eq!(sizes.len(), 3);
+
+        // hashes beyond the cap are ignored
+        let mut request: Vec<H256> = (0..MAX_GET_BLOCKS).map(|_| H256::default()).collect();
+        request.push(hashes[1]);
+        assert!(replies(test_msg_sender.send(Message::GetBlocks(request))).is_empty());
+    }
+
     #[test]
     #[timeout(60000)]
     fn reply_blocks() {
@@ -327,6 +1097,1032 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn reply_version() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver =
+            test_msg_sender.send(Message::version(3, Blockchain::new().genesis_hash(), 1));
+        match peer_receiver.recv() {
+            Message::Version {
+                protocol_version,
+                best_height,
+                genesis,
+                ..
+            } => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                assert_eq!(best_height as usize, v.len() - 1);
+                assert_eq!(genesis, v[0]);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(peer_receiver.recv(), Message::VerAck));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_mismatched_version() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(Message::Version {
+            protocol_version: PROTOCOL_VERSION + 1,
+            best_height: 0,
+            genesis: Blockchain::new().genesis_hash(),
+            node_id: 1,
+        });
+        assert!(peer_receiver.next().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_mismatched_genesis() {
+        let params = ConsensusParams {
+            difficulty: [0xff; 32].into(),
+            ..ConsensusParams::default()
+        };
+        let other_chain = Blockchain::with_params(params, State::new());
+        assert_ne!(other_chain.genesis_hash(), Blockchain::new().genesis_hash());
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+
+        // neither a version back nor a block request, only the disconnect
+        let other_genesis = other_chain.genesis_hash();
+        let mut peer_receiver = test_msg_sender.send(Message::version(5, other_genesis, 1));
+        assert!(peer_receiver.next().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn connections_both_ways_deduplicated() {
+        use std::time::{Duration, Instant};
+
+        let start_node = |addr: std::net::SocketAddr| {
+            let (msg_sender, msg_chan) = smol::channel::bounded(10000);
+            let (ctx, server) = super::super::server::new(addr, msg_sender).unwrap();
+            ctx.start().unwrap();
+            let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            Worker::new(1, msg_chan, &server, blockchain, mempool).start();
+            server
+        };
+        let addr_a = "127.0.0.1:17113".parse().unwrap();
+        let addr_b = "127.0.0.1:17114".parse().unwrap();
+        let a = start_node(addr_a);
+        let b = start_node(addr_b);
+        let genesis = Blockchain::with_easiest_difficulty().genesis_hash();
+
+        // each node opens a connection to the other, and starts the handshake on it
+        a.connect(addr_b)
+            .unwrap()
+            .write(Message::version(0, genesis, a.node_id()));
+        b.connect(addr_a)
+            .unwrap()
+            .write(Message::version(0, genesis, b.node_id()));
+        // at both ends, only the connection opened by the node with the smaller id is left
+        let (smaller, smaller_addr, larger, larger_addr) = if a.node_id() < b.node_id() {
+            (&a, addr_a, &b, addr_b)
+        } else {
+            (&b, addr_b, &a, addr_a)
+        };
+        let deduplicated = || {
+            let peers = larger.peers();
+            smaller.peers() == vec![larger_addr] && peers.len() == 1 && peers[0] != smaller_addr
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !deduplicated() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(deduplicated());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn initial_block_download() {
+        let mut longer = Blockchain::with_easiest_difficulty();
+        let genesis_hash = longer.genesis_hash();
+        for _ in 0..2 {
+            longer.insert(&generate_easiest_block(&longer.tip()));
+        }
+        let longer_tip = longer.tip();
+        let (ahead_sender, _ahead_server) =
+            generate_test_worker_with_blockchain(&Arc::new(Mutex::new(longer)));
+        let fresh_chain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let (fresh_sender, fresh_server) = generate_test_worker_with_blockchain(&fresh_chain);
+
+        // the node that is ahead connects and advertises its height
+        let mut fresh_receiver = fresh_sender.send(Message::version(2, genesis_hash, 1));
+        assert!(matches!(fresh_receiver.recv(), Message::Version { .. }));
+        assert!(matches!(fresh_receiver.recv(), Message::VerAck));
+        let request = fresh_receiver.recv();
+        match &request {
+            Message::GetBlocksAfter { locator } => {
+                assert_eq!(locator, &vec![genesis_hash])
+            }
+            other => panic!("expected GetBlocksAfter, got {:?}", other),
+        }
+
+        let reply = ahead_sender.send(request).recv();
+        fresh_sender.send(reply);
+        assert!(matches!(
+            fresh_server.recv(),
+            Some(Message::NewBlockHashes(_))
+        ));
+        assert_eq!(fresh_chain.lock().unwrap().tip(), longer_tip);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn orphan_linked_across_workers() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let parent = generate_easiest_block(&blockchain.lock().unwrap().tip());
+        let child = generate_easiest_block(&parent.hash());
+        let (first_sender, first_server) = generate_test_worker_with_blockchain(&blockchain);
+        let (second_sender, second_server) = generate_test_worker_with_blockchain(&blockchain);
+
+        // the child arrives first at one worker, which asks around for its parent
+        first_sender.send(Message::Blocks(vec![child.clone()]));
+        assert!(matches!(first_server.recv(), Some(Message::GetBlocks(_))));
+        assert_eq!(blockchain.lock().unwrap().orphan_count(), 1);
+
+        // the parent arrives at the other worker, which links the buffered child too
+        second_sender.send(Message::Blocks(vec![parent.clone()]));
+        match second_server.recv() {
+            Some(Message::NewBlockHashes(hashes)) => {
+                assert_eq!(hashes, vec![parent.hash(), child.hash()])
+            }
+            _ => panic!(),
+        }
+        assert_eq!(blockchain.lock().unwrap().tip(), child.hash());
+        assert_eq!(blockchain.lock().unwrap().orphan_count(), 0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn invalid_orphan_dropped_with_descendants() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let parent = generate_easiest_block(&blockchain.lock().unwrap().tip());
+        let invalid = generate_easiest_block_with_state_root(&parent.hash(), H256::from([1; 32]));
+        let child = generate_easiest_block(&invalid.hash());
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+
+        test_msg_sender.send(Message::Blocks(vec![invalid.clone()]));
+        test_msg_sender.send(Message::Blocks(vec![child.clone()]));
+        test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.tip(), parent.hash());
+        assert!(!blockchain.contains_block(&invalid.hash()));
+        assert!(!blockchain.contains_block(&child.hash()));
+        assert_eq!(blockchain.orphan_count(), 0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn unanswered_parent_request_retried() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(1, msg_chan, &server, blockchain, mempool);
+        worker.clone().start();
+        let parent = H256::from([7; 32]);
+        let orphan = generate_block_with_difficulty(&parent, [0xff; 32].into());
+        let expected = vec![parent];
+
+        test_msg_sender.send(Message::Blocks(vec![orphan]));
+        assert!(matches!(server_receiver.recv(), Some(Message::GetBlocks(h)) if h == expected));
+
+        // the response was dropped, so the parent is asked for again once the request timed out
+        let mut now = std::time::Instant::now();
+        worker.retry_parent_requests(now);
+        for _ in 1..MAX_PARENT_REQUESTS {
+            now += PARENT_REQUEST_TIMEOUT;
+            worker.retry_parent_requests(now);
+            assert!(matches!(server_receiver.recv(), Some(Message::GetBlocks(h)) if h == expected));
+        }
+
+        // until too many requests went unanswered
+        now += PARENT_REQUEST_TIMEOUT;
+        worker.retry_parent_requests(now);
+        assert!(worker.parent_requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphan_flood_stays_bounded() {
+        // an orphan's difficulty is only checked once its parent arrives, so these are cheap to make
+        let orphan =
+            |i: u8| generate_block_with_difficulty(&H256::from([i; 32]), [0xff; 32].into());
+        let addr = "127.0.0.1".parse().unwrap();
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        blockchain.lock().unwrap().set_orphan_capacity(8);
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        for i in 0..30 {
+            test_msg_sender.send(Message::Blocks(vec![orphan(i)]));
+        }
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert_eq!(blockchain.lock().unwrap().orphan_count(), 8);
+        assert_eq!(server.misbehavior_score(&addr), 0);
+
+        // with room for them, orphans beyond the peer's share are penalized
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        for i in 0..MAX_ORPHANS_PER_PEER as u8 + 3 {
+            test_msg_sender.send(Message::Blocks(vec![orphan(i)]));
+        }
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert_eq!(server.misbehavior_score(&addr), 3 * ORPHAN_FLOOD_PENALTY);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_wrong_state_root() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+        let block = generate_easiest_block_with_state_root(&genesis_hash, H256::from([1; 32]));
+
+        test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert!(!blockchain.lock().unwrap().contains_block(&block.hash()));
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis_hash);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_wrong_merkle_root() {
+        let blockchain = Blockchain::with_easiest_difficulty();
+        let genesis_hash = blockchain.tip();
+        let ico = key_pair::ico();
+        let payment = SignedTransaction::new(
+            Transaction::new(key_pair::address(&ico), "bob".to_string(), 1, 0, 0),
+            &ico,
+        );
+        // the header still commits to no transactions
+        let mut block = generate_easiest_block(&genesis_hash);
+        block.get_content_mut().add_transactions(vec![payment]);
+        block.set_state_root(blockchain.expected_state_root(&block).unwrap());
+
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert!(!blockchain.lock().unwrap().contains_block(&block.hash()));
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn ban_peer_sending_invalid_blocks() {
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let offenses = BAN_THRESHOLD / INVALID_BLOCK_PENALTY;
+
+        for offense in 1..=offenses {
+            // an unmined block fails the proof of work
+            let mut peer_receiver =
+                test_msg_sender.send(Message::Blocks(vec![generate_random_block(&genesis_hash)]));
+            assert!(peer_receiver.next().is_none());
+            let addr = "127.0.0.1".parse().unwrap();
+            assert_eq!(
+                server.misbehavior_score(&addr),
+                offense * INVALID_BLOCK_PENALTY
+            );
+            assert_eq!(server.is_banned(&addr), offense == offenses);
+        }
+        // the banned peer isn't answered any more
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("ping".to_string()));
+        assert!(peer_receiver.next().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn survive_malformed_message() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send_bytes(vec![0xff; 7]);
+        assert!(peer_receiver.next().is_none());
+        // the worker is still there to answer
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("ping".to_string()));
+        assert!(matches!(peer_receiver.recv(), Message::Pong(_)));
+    }
+    /// A block on the tip paying the test miner and including `transactions`, its proof of work
+    /// only holds on a chain at the easiest difficulty
+    fn block_with(blockchain: &Blockchain, transactions: Vec<SignedTransaction>) -> Block {
+        let mut block = Block::with_difficulty(blockchain.tip(), blockchain.params().difficulty);
+        block
+            .get_content_mut()
+            .add_transactions(vec![SignedTransaction::coinbase(
+                "miner".to_string(),
+                BLOCK_REWARD as i64,
+                1,
+            )]);
+        block.get_content_mut().add_transactions(transactions);
+        block.set_merkle_root(MerkleTree::new(block.get_transactions()).root());
+        block.set_state_root(blockchain.expected_state_root(&block).unwrap());
+        block
+    }
+
+    /// A payment from the ICO account, valid against the genesis state
+    fn ico_payment(value: i64) -> SignedTransaction {
+        let ico = key_pair::ico();
+        let receiver = base64::encode(key_pair::random().public_key());
+        SignedTransaction::new(
+            Transaction::new(base64::encode(ico.public_key()), receiver, value, 0, 0),
+            &ico,
+        )
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn answer_get_mempool() {
+        let ico = key_pair::ico();
+        let second = SignedTransaction::new(
+            Transaction::new(
+                base64::encode(ico.public_key()),
+                "receiver".to_string(),
+                7,
+                0,
+                1,
+            ),
+            &ico,
+        );
+        let first = ico_payment(5);
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        Worker::new(1, msg_chan, &server, blockchain, Arc::clone(&mempool)).start();
+
+        // the handshake completing asks for the peer's mempool
+        let mut peer_receiver = test_msg_sender.send(Message::VerAck);
+        assert!(matches!(peer_receiver.recv(), Message::GetMempool));
+
+        // an empty mempool has nothing to announce
+        let mut peer_receiver = test_msg_sender.send(Message::GetMempool);
+        assert!(peer_receiver.next().is_none());
+
+        mempool.lock().unwrap().add_transaction(first.clone());
+        mempool.lock().unwrap().add_transaction(second.clone());
+        let mut peer_receiver = test_msg_sender.send(Message::GetMempool);
+        match peer_receiver.recv() {
+            Message::NewTransactionHashes(hashes) => {
+                assert_eq!(hashes, vec![first.hash(), second.hash()])
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reconstruct_compact_block_from_mempool() {
+        let tx = ico_payment(5);
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        mempool.lock().unwrap().add_transaction(tx.clone());
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        let block = block_with(&blockchain.lock().unwrap(), vec![tx.clone()]);
+
+        let compact = Message::compact_block(&block);
+        match &compact {
+            Message::CompactBlock { tx_hashes, .. } => assert_eq!(tx_hashes, &vec![tx.hash()]),
+            _ => panic!(),
+        }
+        let mut peer_receiver = test_msg_sender.send(compact);
+        assert!(matches!(
+            server_receiver.recv(),
+            Some(Message::NewBlockHashes(_))
+        ));
+        // nothing had to be requested from the peer
+        assert!(peer_receiver.next().is_none());
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn oversized_block_rejected() {
+        let ico = key_pair::ico();
+        let payments: Vec<SignedTransaction> = (0..3)
+            .map(|nonce| {
+                SignedTransaction::new(
+                    Transaction::new(
+                        base64::encode(ico.public_key()),
+                        "bob".to_string(),
+                        1,
+                        0,
+                        nonce,
+                    ),
+                    &ico,
+                )
+            })
+            .collect();
+        let params = ConsensusParams {
+            max_block_size: 2,
+            difficulty: EASIEST_DIFFICULTY.into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+
+        // the coinbase doesn't count towards the limit
+        let oversized = block_with(&blockchain.lock().unwrap(), payments.clone());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![oversized]));
+        assert!(peer_receiver.next().is_none());
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis_hash);
+
+        let full = block_with(&blockchain.lock().unwrap(), payments[..2].to_vec());
+        test_msg_sender.send(Message::Blocks(vec![full.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert_eq!(blockchain.lock().unwrap().tip(), full.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn overweight_block_rejected() {
+        let ico = key_pair::ico();
+        let payment = |receiver: &str, nonce: u64| {
+            let sender = base64::encode(ico.public_key());
+            SignedTransaction::new(
+                Transaction::new(sender, receiver.to_string(), 1, 0, nonce),
+                &ico,
+            )
+        };
+        let light = payment("bob", 0);
+        let heavy = payment(&"b".repeat(1000), 0);
+        let params = ConsensusParams {
+            max_block_weight: 2 * light.serialized_size(),
+            difficulty: EASIEST_DIFFICULTY.into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+
+        // a single transaction can be over the limit
+        let overweight = block_with(&blockchain.lock().unwrap(), vec![heavy]);
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![overweight]));
+        assert!(peer_receiver.next().is_none());
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis_hash);
+
+        let full = block_with(&blockchain.lock().unwrap(), vec![light, payment("bob", 1)]);
+        test_msg_sender.send(Message::Blocks(vec![full.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert_eq!(blockchain.lock().unwrap().tip(), full.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn duplicate_transaction_rejected() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+
+        let payment = ico_payment(5);
+        let mut block = Block::with_difficulty(genesis_hash, EASIEST_DIFFICULTY.into());
+        block.get_content_mut().add_transactions(vec![
+            SignedTransaction::coinbase("miner".to_string(), BLOCK_REWARD as i64, 1),
+            payment.clone(),
+            payment,
+        ]);
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![block]));
+        assert!(peer_receiver.next().is_none());
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis_hash);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn large_block_verified_before_locking() {
+        let ico = key_pair::ico();
+        let receiver = base64::encode(key_pair::random().public_key());
+        let transactions: Vec<SignedTransaction> = (0..300)
+            .map(|nonce| {
+                SignedTransaction::new(
+                    Transaction::new(
+                        base64::encode(ico.public_key()),
+                        receiver.clone(),
+                        1,
+                        0,
+                        nonce,
+                    ),
+                    &ico,
+                )
+            })
+            .collect();
+        let params = ConsensusParams {
+            max_block_size: 300,
+            max_block_weight: usize::MAX,
+            difficulty: EASIEST_DIFFICULTY.into(),
+            ..ConsensusParams::default()
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params, State::new())));
+        let (test_msg_sender, server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+        let block = block_with(&blockchain.lock().unwrap(), transactions.clone());
+        // building the block verified the signatures, start from a cold cache
+        for tx in transactions.iter() {
+            signature_cache::global().invalidate(&tx.full_hash());
+        }
+
+        test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        assert!(matches!(
+            server_receiver.recv(),
+            Some(Message::NewBlockHashes(_))
+        ));
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+        assert_eq!(
+            blockchain
+                .lock()
+                .unwrap()
+                .get_state()
+                .get_balance(&AccountAddress::new(receiver)),
+            300
+        );
+        // each signature was checked once up front, every application under the lock then
+        // found it in the cache
+        for tx in transactions.iter() {
+            assert_eq!(signature_cache::global().hits(&tx.full_hash()), Some(2));
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mixed_inventory_in_one_round_trip() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let tx = ico_payment(5);
+        let block = block_with(&blockchain.lock().unwrap(), vec![tx.clone()]);
+        blockchain.lock().unwrap().insert(&block);
+        mempool.lock().unwrap().add_transaction(tx.clone());
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (holder_sender, msg_chan) = TestMsgSender::new();
+        Worker::new(1, msg_chan, &server, blockchain, mempool).start();
+        let lacking = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&lacking);
+        let inventory = vec![InvItem::Block(block.hash()), InvItem::Tx(tx.hash())];
+
+        // a node lacking both items asks for them in a single message
+        let mut peer_receiver = test_msg_sender.send(Message::Inv(inventory.clone()));
+        let request = match peer_receiver.recv() {
+            Message::GetData(items) => items,
+            _ => panic!(),
+        };
+        assert_eq!(request, inventory);
+
+        // and the node holding them answers with the transaction, then the compact block
+        let mut peer_receiver = holder_sender.send(Message::GetData(request));
+        let transactions = peer_receiver.recv();
+        match &transactions {
+            Message::Transactions(transactions) => assert_eq!(transactions[0].hash(), tx.hash()),
+            _ => panic!(),
+        }
+        let compact = peer_receiver.recv();
+        match &compact {
+            Message::CompactBlock { header, .. } => assert_eq!(header.hash(), block.hash()),
+            _ => panic!(),
+        }
+
+        // which the lacking node rebuilds without asking for anything else
+        test_msg_sender.send(transactions);
+        let mut peer_receiver = test_msg_sender.send(compact);
+        assert!(peer_receiver.next().is_none());
+        assert_eq!(lacking.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn transactions_relayed_once() {
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        Worker::new(1, msg_chan, &server, blockchain, Arc::clone(&mempool)).start();
+        let tx = ico_payment(5);
+
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![tx.hash()]),
+            _ => panic!(),
+        }
+        // still not announced again once it left the mempool and comes back
+        mempool.lock().unwrap().remove_transactions(&[tx.hash()]);
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        let other = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        test_msg_sender.send(Message::Transactions(vec![other.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![other.hash()]),
+            _ => panic!(),
+        }
+        assert!(mempool.lock().unwrap().contains_transaction(&tx.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn transaction_of_a_losing_branch_fetched_again() {
+        let tx = ico_payment(5);
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut losing = generate_random_block(&genesis_hash);
+        losing.get_content_mut().add_transactions(vec![tx.clone()]);
+        blockchain.insert(&losing);
+        let first = generate_random_block(&genesis_hash);
+        blockchain.insert(&first);
+        blockchain.insert(&generate_random_block(&first.hash()));
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+
+        // the reorg left the transaction out of the longest chain, so it is asked for again
+        let mut peer_receiver =
+            test_msg_sender.send(Message::NewTransactionHashes(vec![tx.hash()]));
+        match peer_receiver.recv() {
+            Message::GetTransactions(hashes) => assert_eq!(hashes, vec![tx.hash()]),
+            other => panic!("expected GetTransactions, got {:?}", other),
+        }
+        let mut peer_receiver = test_msg_sender.send(Message::Inv(vec![InvItem::Tx(tx.hash())]));
+        match peer_receiver.recv() {
+            Message::GetData(items) => assert_eq!(items, vec![InvItem::Tx(tx.hash())]),
+            other => panic!("expected GetData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn transaction_rejected_at_first_relayed_once_accepted() {
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        Worker::new(1, msg_chan, &server, blockchain, Arc::clone(&mempool)).start();
+        let alice = key_pair::random();
+        let receiver = base64::encode(key_pair::random().public_key());
+        let tx = SignedTransaction::new(
+            Transaction::new(key_pair::address(&alice), receiver, 5, 0, 0),
+            &alice,
+        );
+
+        // alice has no funds yet
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        assert!(!mempool.lock().unwrap().contains_transaction(&tx.hash()));
+
+        let mut funded = State::new();
+        funded.add_account_with_balance(AccountAddress::new(key_pair::address(&alice)), 10);
+        mempool.lock().unwrap().revalidate(&funded);
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![tx.hash()]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn fetch_missing_transactions_of_compact_block() {
+        let tx = ico_payment(5);
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let (test_msg_sender, server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+        let block = block_with(&blockchain.lock().unwrap(), vec![tx.clone()]);
+
+        let mut peer_receiver = test_msg_sender.send(Message::compact_block(&block));
+        match peer_receiver.recv() {
+            Message::GetTransactions(hashes) => assert_eq!(hashes, vec![tx.hash()]),
+            _ => panic!(),
+        }
+        test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        // the fetched transaction is relayed before the completed block
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![tx.hash()]),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            server_receiver.recv(),
+            Some(Message::NewBlockHashes(_))
+        ));
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_checked_before_buffering() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool);
+        worker.clone().start();
+        let addr = "127.0.0.1".parse().unwrap();
+
+        // neither an unmined header nor one at another difficulty gets its transactions asked for
+        let mut unmined = generate_random_block(&genesis_hash);
+        while unmined.hash().meets_difficulty(&unmined.get_difficulty()) {
+            unmined.set_nonce(unmined.get_nonce().wrapping_add(1));
+        }
+        let easiest = generate_easiest_block(&genesis_hash);
+        for (offense, block) in [unmined, easiest].iter_mut().enumerate() {
+            block
+                .get_content_mut()
+                .add_transactions(vec![ico_payment(5)]);
+            let mut peer_receiver = test_msg_sender.send(Message::compact_block(block));
+            assert!(peer_receiver.next().is_none());
+            assert_eq!(
+                server.misbehavior_score(&addr),
+                (offense as u32 + 1) * INVALID_BLOCK_PENALTY
+            );
+        }
+        assert!(worker.pending_compact_blocks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reordered_compact_block_rejected() {
+        let ico = key_pair::ico();
+        let first = ico_payment(5);
+        let second = SignedTransaction::new(
+            Transaction::new(key_pair::address(&ico), "bob".to_string(), 7, 0, 1),
+            &ico,
+        );
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        mempool.lock().unwrap().add_transaction(first.clone());
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        let block = block_with(&blockchain.lock().unwrap(), vec![first, second.clone()]);
+
+        // the header of the block with its transactions swapped, once the missing one arrives
+        let mut reordered = Message::compact_block(&block);
+        if let Message::CompactBlock { tx_hashes, .. } = &mut reordered {
+            tx_hashes.reverse();
+        }
+        let mut peer_receiver = test_msg_sender.send(reordered);
+        assert!(matches!(peer_receiver.recv(), Message::GetTransactions(_)));
+        test_msg_sender.send(Message::Transactions(vec![second]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+        assert_eq!(blockchain.lock().unwrap().tip(), genesis_hash);
+
+        // so the block in its committed order is still accepted
+        test_msg_sender.send(Message::compact_block(&block));
+        loop {
+            match server_receiver.recv() {
+                Some(Message::NewBlockHashes(hashes)) => {
+                    assert_eq!(hashes, vec![block.hash()]);
+                    break;
+                }
+                Some(Message::NewTransactionHashes(_)) => continue,
+                other => panic!("expected NewBlockHashes, got {:?}", other),
+            }
+        }
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn pending_compact_blocks_bounded() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(1, msg_chan, &server, blockchain, mempool);
+        worker.clone().start();
+
+        let hashes: Vec<H256> = (0..MAX_PENDING_COMPACT_BLOCKS + 1)
+            .map(|_| {
+                let block = block_with_unknown_payment(&genesis_hash);
+                let mut peer_receiver = test_msg_sender.send(Message::compact_block(&block));
+                assert!(matches!(peer_receiver.recv(), Message::GetTransactions(_)));
+                block.hash()
+            })
+            .collect();
+        let pending = worker.pending_compact_blocks.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_COMPACT_BLOCKS);
+        assert!(!pending.contains_key(&hashes[0]));
+        assert!(hashes[1..].iter().all(|hash| pending.contains_key(hash)));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn unanswered_compact_block_falls_back_to_full_block() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(1, msg_chan, &server, blockchain, mempool);
+        worker.clone().start();
+        let block = block_with_unknown_payment(&genesis_hash);
+
+        // the transaction no mempool holds is asked for
+        let mut peer_receiver = test_msg_sender.send(Message::compact_block(&block));
+        match peer_receiver.recv() {
+            Message::GetTransactions(hashes) => {
+                assert_eq!(hashes, vec![block.get_transactions()[0].hash()])
+            }
+            other => panic!("expected GetTransactions, got {:?}", other),
+        }
+
+        // the block waits for it until the timeout, then the full block is asked for
+        let now = std::time::Instant::now();
+        worker.expire_compact_blocks(now);
+        assert!(worker
+            .pending_compact_blocks
+            .lock()
+            .unwrap()
+            .contains_key(&block.hash()));
+        worker.expire_compact_blocks(now + COMPACT_BLOCK_TIMEOUT);
+        assert!(worker.pending_compact_blocks.lock().unwrap().is_empty());
+        match server_receiver.recv() {
+            Some(Message::GetBlocks(hashes)) => assert_eq!(hashes, vec![block.hash()]),
+            other => panic!("expected GetBlocks, got {:?}", other),
+        }
+    }
+
+    /// A block on `parent` at the easiest difficulty paying from the ICO account, the payment
+    /// is in no mempool
+    fn block_with_unknown_payment(parent: &H256) -> Block {
+        let mut block = generate_easiest_block(parent);
+        block
+            .get_content_mut()
+            .add_transactions(vec![ico_payment(5)]);
+        block.set_merkle_root(MerkleTree::new(block.get_transactions()).root());
+        block
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn stop_syncing_when_peer_cannot_serve() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let mut peer_receiver =
+            test_msg_sender.send(Message::version(5, Blockchain::new().genesis_hash(), 1));
+        peer_receiver.recv();
+        peer_receiver.recv();
+        assert!(matches!(
+            peer_receiver.recv(),
+            Message::GetBlocksAfter { .. }
+        ));
+        // the peer has nothing after all, so no further blocks are requested
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        drop(test_msg_sender);
+        assert!(peer_receiver.next().is_none());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn headers_first_sync() {
+        use crate::types::block::generate_block_with_difficulty;
+
+        // blocks at the easiest difficulty carry valid proof of work without mining
+        let easiest: H256 = [0xff; 32].into();
+        let ico = key_pair::ico();
+        let mut serving = Blockchain::new();
+        for nonce in 0..5 {
+            let mut block = generate_block_with_difficulty(&serving.tip(), easiest);
+            block
+                .get_content_mut()
+                .add_transactions(vec![SignedTransaction::new(
+                    Transaction::new(key_pair::address(&ico), "bob".to_string(), 1, 0, nonce),
+                    &ico,
+                )]);
+            serving.insert(&block);
+        }
+        let chain = serving.all_blocks_in_longest_chain();
+        let serving = Arc::new(Mutex::new(serving));
+        let (serving_sender, _serving_receiver) = generate_test_worker_with_blockchain(&serving);
+        let syncing = Arc::new(Mutex::new(Blockchain::new()));
+        let (syncing_sender, _syncing_receiver) = generate_test_worker_with_blockchain(&syncing);
+
+        let locator = syncing.lock().unwrap().block_locator();
+        let headers = match serving_sender.send(Message::GetHeaders { locator }).recv() {
+            Message::Headers(headers) => headers,
+            other => panic!("expected Headers, got {:?}", other),
+        };
+        let hashes: Vec<H256> = headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(hashes, chain[1..].to_vec());
+
+        // the syncing node learns the whole chain from the headers and asks for every body
+        match syncing_sender.send(Message::Headers(headers)).recv() {
+            Message::GetBlocks(requested) => assert_eq!(requested, chain[1..].to_vec()),
+            other => panic!("expected GetBlocks, got {:?}", other),
+        }
+        assert_eq!(syncing.lock().unwrap().best_height(), 0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn headers_must_link() {
+        use crate::types::block::generate_block_with_difficulty;
+
+        let easiest: H256 = [0xff; 32].into();
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        Worker::new(1, msg_chan, &server, Arc::clone(&blockchain), mempool).start();
+        let genesis_hash = blockchain.lock().unwrap().tip();
+
+        // the second header doesn't follow the first
+        let first = generate_block_with_difficulty(&genesis_hash, easiest);
+        let unlinked = generate_block_with_difficulty(&genesis_hash, easiest);
+        let mut peer_receiver = test_msg_sender.send(Message::Headers(vec![
+            first.get_header().clone(),
+            unlinked.get_header().clone(),
+        ]));
+        // the peer is penalized instead of being asked for the blocks
+        assert!(peer_receiver.next().is_none());
+        let addr = "127.0.0.1".parse().unwrap();
+        assert_eq!(server.misbehavior_score(&addr), INVALID_BLOCK_PENALTY);
+    }
+
+    /// Records of every test, kept by `CaptureLogger`
+    static CAPTURED: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Levels of the captured records mentioning `needle`, the logger is installed on first use
+    fn logged_levels(needle: &str) -> Vec<log::Level> {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, message)| message.contains(needle))
+            .map(|(level, _)| *level)
+            .collect()
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn invalid_block_logged_as_warning() {
+        logged_levels("");
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_easiest_difficulty()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (test_msg_sender, _server_receiver) = generate_test_worker_with_blockchain(&blockchain);
+        let block = generate_easiest_block_with_state_root(&genesis_hash, H256::from([1; 32]));
+
+        test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        let mut ping_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        assert!(matches!(ping_receiver.recv(), Message::Pong(_)));
+        let levels = logged_levels(&block.hash().to_string());
+        assert!(levels.contains(&log::Level::Warn), "{:?}", levels);
+        assert!(!levels.contains(&log::Level::Info), "{:?}", levels);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
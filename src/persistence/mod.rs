@@ -0,0 +1,258 @@
+use crate::blockchain::Blockchain;
+use crate::types::mempool::Mempool;
+use log::{error, info};
+use ring::digest;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Layout of a node's data directory: the chain and the mempool each live in their own file, so
+/// that saving one never rewrites the other. The state has no file, loading the chain replays its
+/// blocks, which rebuilds the state they lead to.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    /// Open a data directory, creating it if it does not exist yet
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn chain_path(&self) -> PathBuf {
+        self.root.join("chain.bin")
+    }
+
+    pub fn mempool_path(&self) -> PathBuf {
+        self.root.join("mempool.bin")
+    }
+}
+
+/// Path of the scratch file used while `path` is being rewritten
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Replace the contents of `path` with `bytes` atomically: the data is written to a temp file,
+/// fsynced, then renamed over the target. A crash at any point leaves either the old or the new
+/// file in place, never a half-written one.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Read a file written by `write_atomic`, returning `None` if it does not exist
+pub fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Like `write_atomic`, prefixing the contents with their SHA-256 digest so that `read_checked`
+/// can tell a corrupt file from a valid one
+pub fn write_checked(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut contents = digest::digest(&digest::SHA256, bytes).as_ref().to_vec();
+    contents.extend_from_slice(bytes);
+    write_atomic(path, &contents)
+}
+
+/// Read a file written by `write_checked`, returning `None` if it does not exist and an
+/// `InvalidData` error if its contents don't match their digest
+pub fn read_checked(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut contents = match read_if_exists(path)? {
+        Some(contents) => contents,
+        None => return Ok(None),
+    };
+    let digest_len = digest::SHA256.output_len;
+    if contents.len() < digest_len
+        || digest::digest(&digest::SHA256, &contents[digest_len..]).as_ref()
+            != &contents[..digest_len]
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is corrupt", path.display()),
+        ));
+    }
+    Ok(Some(contents.split_off(digest_len)))
+}
+
+/// Saves the blockchain and mempool into a data directory, either on demand or periodically.
+#[derive(Clone)]
+pub struct Snapshotter {
+    dir: DataDir,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+}
+
+impl Snapshotter {
+    pub fn new(
+        dir: DataDir,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+    ) -> Self {
+        Self {
+            dir,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+        }
+    }
+
+    /// Write the current chain and mempool to disk
+    pub fn flush(&self) -> io::Result<()> {
+        // take the locks one at a time, the chain isn't held up while the mempool is written
+        self.blockchain.lock().unwrap().save_to_path(&self.dir)?;
+        self.mempool.lock().unwrap().save_to_path(&self.dir)?;
+        Ok(())
+    }
+
+    /// Spawn a thread that flushes every `interval`
+    pub fn start(self, interval: time::Duration) {
+        thread::Builder::new()
+            .name("snapshotter".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match self.flush() {
+                    Ok(_) => info!("Saved snapshot to {}", self.dir.root().display()),
+                    Err(e) => error!("Error saving snapshot: {}", e),
+                }
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+pub fn test_data_dir(name: &str) -> DataDir {
+    use rand::Rng;
+    let suffix = rand::thread_rng().gen::<u32>();
+    let root = std::env::temp_dir().join(format!("bitcoin-{}-{}", name, suffix));
+    DataDir::new(root).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::{
+        generate_easiest_block, generate_easiest_block_with_state_root, Block,
+    };
+    use crate::types::hash::Hashable;
+    use crate::types::state::State;
+
+    /// Load a chain saved by one from `Blockchain::with_easiest_difficulty`
+    fn load_easiest(dir: &DataDir) -> io::Result<Blockchain> {
+        let params = Blockchain::with_easiest_difficulty().params().clone();
+        Blockchain::load_from_path_with_params(dir, params, State::new())
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let dir = test_data_dir("atomic");
+        let path = dir.chain_path();
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn interrupted_write_keeps_last_snapshot() {
+        let dir = test_data_dir("crash");
+        let mut blockchain = Blockchain::with_easiest_difficulty();
+        let block = generate_easiest_block(&blockchain.tip());
+        blockchain.insert(&block);
+        blockchain.save_to_path(&dir).unwrap();
+
+        // simulate a crash halfway through the next save: the temp file is left truncated and
+        // the rename never happens
+        let mut longer = load_easiest(&dir).unwrap();
+        longer.insert(&generate_easiest_block(&block.hash()));
+        let bytes = bincode::serialize(&longer.all_blocks_in_longest_chain()).unwrap();
+        fs::write(temp_path(&dir.chain_path()), &bytes[..bytes.len() / 2]).unwrap();
+
+        let loaded = load_easiest(&dir).unwrap();
+        assert_eq!(loaded.tip(), block.hash());
+    }
+
+    #[test]
+    fn corrupt_snapshot_rejected() {
+        let dir = test_data_dir("corrupt");
+        let mut blockchain = Blockchain::with_easiest_difficulty();
+        let first = generate_easiest_block(&blockchain.tip());
+        let second = generate_easiest_block(&first.hash());
+        blockchain.insert(&first);
+        blockchain.insert(&second);
+        blockchain.save_to_path(&dir).unwrap();
+        let saved = fs::read(dir.chain_path()).unwrap();
+        let invalid = |dir: &DataDir| {
+            load_easiest(dir).err().map(|e| e.kind()) == Some(io::ErrorKind::InvalidData)
+        };
+
+        // a flipped bit
+        let mut flipped = saved.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        fs::write(dir.chain_path(), &flipped).unwrap();
+        assert!(invalid(&dir));
+
+        // a truncated file
+        fs::write(dir.chain_path(), &saved[..saved.len() / 2]).unwrap();
+        assert!(invalid(&dir));
+        fs::write(dir.chain_path(), &saved[..10]).unwrap();
+        assert!(invalid(&dir));
+
+        // with a matching digest, a block whose parent is missing and one committing to another
+        // state
+        let genesis = Block::get_genesis_block_with_difficulty(blockchain.params().difficulty);
+        let gap = bincode::serialize(&vec![genesis.clone(), second]).unwrap();
+        write_checked(&dir.chain_path(), &gap).unwrap();
+        assert!(invalid(&dir));
+        let wrong_state = generate_easiest_block_with_state_root(&genesis.hash(), [1; 32].into());
+        let wrong_state = bincode::serialize(&vec![genesis, wrong_state]).unwrap();
+        write_checked(&dir.chain_path(), &wrong_state).unwrap();
+        assert!(invalid(&dir));
+
+        // and on a chain at the default difficulty, a block without proof of work
+        let genesis = Block::get_genesis_block();
+        let mut unmined = Block::new(genesis.hash());
+        unmined.set_state_root(State::new().state_root());
+        while unmined.hash().meets_difficulty(&unmined.get_difficulty()) {
+            unmined.set_nonce(unmined.get_nonce().wrapping_add(1));
+        }
+        let unmined = bincode::serialize(&vec![genesis, unmined]).unwrap();
+        write_checked(&dir.chain_path(), &unmined).unwrap();
+        let loaded = Blockchain::load_from_path(&dir);
+        assert_eq!(
+            loaded.err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+
+        fs::write(dir.chain_path(), &saved).unwrap();
+        assert_eq!(load_easiest(&dir).unwrap().tip(), blockchain.tip());
+    }
+}
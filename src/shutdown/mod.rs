@@ -0,0 +1,35 @@
+use log::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+/// Set once SIGINT or SIGTERM is received, or a shutdown is requested through the API
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often `wait` checks whether a shutdown was requested
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Catch SIGINT and SIGTERM so that `wait` returns instead of the process being killed
+pub fn install_handler() {
+    // the handler runs on a thread of its own rather than in the signal handler
+    if let Err(e) = ctrlc::set_handler(request) {
+        warn!("Error installing the shutdown signal handler: {}", e);
+    }
+}
+
+/// Ask for a shutdown as if a signal was received
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a shutdown signal was received
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Block until a shutdown signal is received
+pub fn wait() {
+    while !requested() {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Serialize, Deserialize};
-use ring::digest;
+use super::hash;
 
 // 20-byte address
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
@@ -49,7 +49,7 @@ impl std::fmt::Debug for Address {
 
 impl Address {
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
-        let input = digest::digest(&digest::SHA256, bytes);
+        let input = hash::digest(bytes);
         let mut raw_hash: [u8; 20] = [0; 20];
         raw_hash[0..20].copy_from_slice(&input.as_ref()[12..32]);
         Address(raw_hash)
@@ -1,8 +1,13 @@
-use crate::types::hash::{Hashable, H256};
+use crate::config::DEFAULT_DIFFICULTY;
+use crate::types::hash::{self, Hashable, H256};
+use crate::types::merkle::MerkleTree;
+#[cfg(any(test, test_utilities))]
+use crate::types::state::State;
 use crate::types::transaction::SignedTransaction;
-use hex_literal::hex;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,6 +25,11 @@ impl Content {
     }
 }
 
+const GENESIS_PARENT: [u8; 32] = [0; 32];
+const GENESIS_NONCE: u32 = 0;
+/// 2021-03-12 00:00:00 UTC, in milliseconds
+const GENESIS_TIMESTAMP: u128 = 1615523200000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
     parent: H256,
@@ -27,12 +37,14 @@ pub struct Header {
     difficulty: H256,
     timestamp: u128,
     merkle_root: H256,
+    state_root: H256, // root of the state after applying the block's transactions
+    // memoized hash, reset by every mutation; skipped so it never feeds into the hash itself
+    #[serde(skip)]
+    hash: OnceLock<H256>,
 }
 
 impl Header {
-    pub fn new(parent: H256, nonce: u32) -> Self {
-        let mut difficulty =
-            hex!("000010ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+    pub fn new(parent: H256, nonce: u32, difficulty: H256) -> Self {
         let mut timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -44,32 +56,70 @@ impl Header {
             difficulty,
             timestamp,
             merkle_root,
+            state_root: H256::from([0; 32]),
+            hash: OnceLock::new(),
         }
     }
 
-    pub fn get_genesis_header() -> Self {
-        let parent = H256::from([0; 32]); // Genesis block has no parent
-        let nonce = 0u32; // An arbitrary fixed nonce for genesis
-        let difficulty =
-            hex!("000010ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
-        // Fixed timestamp for genesis block, for example, the UNIX timestamp of a specific memorable date
-        let timestamp = 1615523200000; // This is a sample timestamp for 2021-03-12 00:00:00
-        let merkle_root = H256::from([0; 32]); // Genesis block's merkle root could be all zeros
-
+    /// A header with every field given, as an external miner completes a block template
+    pub fn from_fields(
+        parent: H256,
+        nonce: u32,
+        difficulty: H256,
+        timestamp: u128,
+        merkle_root: H256,
+        state_root: H256,
+    ) -> Self {
         Header {
             parent,
             nonce,
             difficulty,
             timestamp,
             merkle_root,
+            state_root,
+            hash: OnceLock::new(),
+        }
+    }
+
+    /// The canonical genesis header. Every field but the difficulty, which the chain's blocks
+    /// inherit, is a constant so that all nodes with the same parameters agree on the genesis
+    /// hash.
+    pub fn get_genesis_header(difficulty: H256) -> Self {
+        Header {
+            parent: GENESIS_PARENT.into(), // Genesis block has no parent
+            nonce: GENESIS_NONCE,
+            difficulty,
+            timestamp: GENESIS_TIMESTAMP,
+            merkle_root: H256::from([0; 32]), // Genesis block has no transactions
+            // The genesis allocation is configurable, so it isn't committed to
+            state_root: H256::from([0; 32]),
+            hash: OnceLock::new(),
         }
     }
 }
 
+impl Header {
+    pub fn get_parent(&self) -> H256 {
+        self.parent
+    }
+
+    pub fn get_difficulty(&self) -> H256 {
+        self.difficulty
+    }
+
+    /// Give mutable access to the fields, forgetting the memoized hash
+    fn modify(&mut self) -> &mut Self {
+        self.hash.take();
+        self
+    }
+}
+
 impl Hashable for Header {
     fn hash(&self) -> H256 {
-        let encoded = bincode::serialize(&self).expect("failed to serialize");
-        ring::digest::digest(&ring::digest::SHA256, &encoded).into()
+        *self.hash.get_or_init(|| {
+            let encoded = bincode::serialize(&self).expect("failed to serialize");
+            hash::digest(&encoded)
+        })
     }
 }
 
@@ -87,28 +137,52 @@ impl Hashable for Block {
 
 impl Block {
     pub fn get_genesis_block() -> Self {
-        let genesis_parent: H256 =
-            hex!("00000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
-        let genesis_nonce = 0u32; // or some predetermined value
-        let genesis_header = Header::get_genesis_header();
-        let genesis_content = Content::new();
+        Self::get_genesis_block_with_difficulty(DEFAULT_DIFFICULTY.into())
+    }
+
+    /// The genesis block of a chain whose blocks must meet `difficulty`
+    pub fn get_genesis_block_with_difficulty(difficulty: H256) -> Self {
         Block {
-            header: genesis_header,
-            content: genesis_content,
+            header: Header::get_genesis_header(difficulty),
+            content: Content::new(),
         }
     }
 
     pub fn new(parent: H256) -> Self {
+        Self::with_difficulty(parent, DEFAULT_DIFFICULTY.into())
+    }
+
+    /// An empty block on `parent` whose hash must meet `difficulty`, with a random nonce. Mining
+    /// starts from it, so that miners building on the same parent don't try the same nonces.
+    pub fn with_difficulty(parent: H256, difficulty: H256) -> Self {
         let mut rng = rand::thread_rng();
         let mut nonce = rng.gen::<u32>();
-        let mut header = Header::new(parent, nonce);
+        let mut header = Header::new(parent, nonce, difficulty);
         let mut content = Content::new();
+        // committing to no transactions, until some are added and the root is set again
+        header.merkle_root = MerkleTree::new(&content.transactions).root();
         Block { header, content }
     }
 
+    pub fn get_nonce(&self) -> u32 {
+        self.header.nonce
+    }
+
     // Setter method for changing the nonce
     pub fn set_nonce(&mut self, new_nonce: u32) {
-        self.header.nonce = new_nonce;
+        self.header.modify().nonce = new_nonce;
+    }
+
+    /// Assemble a block from its header and transactions, as relayed in a compact block
+    pub fn from_parts(header: Header, transactions: Vec<SignedTransaction>) -> Self {
+        Block {
+            header,
+            content: Content { transactions },
+        }
+    }
+
+    pub fn get_header(&self) -> &Header {
+        &self.header
     }
 
     pub fn get_parent(&self) -> H256 {
@@ -119,6 +193,29 @@ impl Block {
         self.header.difficulty
     }
 
+    /// Creation time of the block, in milliseconds since the Unix epoch
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn get_state_root(&self) -> H256 {
+        self.header.state_root
+    }
+
+    pub fn get_merkle_root(&self) -> H256 {
+        self.header.merkle_root
+    }
+
+    /// Commit to the transactions of this block, changing its hash
+    pub fn set_merkle_root(&mut self, merkle_root: H256) {
+        self.header.modify().merkle_root = merkle_root;
+    }
+
+    /// Commit to the state after this block, changing its hash
+    pub fn set_state_root(&mut self, state_root: H256) {
+        self.header.modify().state_root = state_root;
+    }
+
     // Method to get a reference to the transactions within the block
     pub fn get_transactions(&self) -> &Vec<SignedTransaction> {
         &self.content.transactions
@@ -134,8 +231,148 @@ impl Block {
     }
 }
 
+/// A one-line summary: the hash, the parent and the number of transactions. The alternate form
+/// `{:#}` lists every header field on its own line, then a summary line per transaction.
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let transactions = self.get_transactions();
+        if !f.alternate() {
+            return write!(
+                f,
+                "block {} on {} with {} transactions",
+                self.hash(),
+                self.get_parent(),
+                transactions.len()
+            );
+        }
+        let header = &self.header;
+        writeln!(f, "block {}", self.hash())?;
+        writeln!(f, "  parent:      {}", header.parent)?;
+        writeln!(f, "  nonce:       {}", header.nonce)?;
+        writeln!(f, "  difficulty:  {}", header.difficulty)?;
+        writeln!(f, "  timestamp:   {}", header.timestamp)?;
+        writeln!(f, "  merkle root: {}", header.merkle_root)?;
+        writeln!(f, "  state root:  {}", header.state_root)?;
+        write!(f, "  transactions: {}", transactions.len())?;
+        for transaction in transactions {
+            write!(f, "\n    {}", transaction)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     let mut block = Block::new(*parent);
     block
 }
+
+/// A block on `parent` with its difficulty target replaced, its nonce is not mined
+#[cfg(any(test, test_utilities))]
+pub fn generate_block_with_difficulty(parent: &H256, difficulty: H256) -> Block {
+    Block::with_difficulty(*parent, difficulty)
+}
+
+/// A difficulty every hash meets, blocks at it are valid without being mined
+#[cfg(any(test, test_utilities))]
+pub const EASIEST_DIFFICULTY: [u8; 32] = [0xff; 32];
+
+/// An empty block on `parent` at `EASIEST_DIFFICULTY`, for chains created by
+/// `Blockchain::with_easiest_difficulty`. Its state root is the one of a chain without any
+/// transactions.
+#[cfg(any(test, test_utilities))]
+pub fn generate_easiest_block(parent: &H256) -> Block {
+    generate_easiest_block_with_state_root(parent, State::new().state_root())
+}
+
+/// An empty block on `parent` at `EASIEST_DIFFICULTY` committing to `state_root`
+#[cfg(any(test, test_utilities))]
+pub fn generate_easiest_block_with_state_root(parent: &H256, state_root: H256) -> Block {
+    let mut block = Block::with_difficulty(*parent, EASIEST_DIFFICULTY.into());
+    block.set_state_root(state_root);
+    block
+}
+
+/// Search for a nonce that satisfies the proof of work, from the one the block has
+#[cfg(any(test, test_utilities))]
+pub fn mine_block(block: &mut Block) {
+    while !block.hash().meets_difficulty(&block.get_difficulty()) {
+        block.set_nonce(block.get_nonce().wrapping_add(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_renders_hex_hashes() {
+        let mut block = generate_random_block(&H256::from([1; 32]));
+        let transaction = SignedTransaction::get_random_signed_transaction();
+        block
+            .get_content_mut()
+            .add_transactions(vec![transaction.clone()]);
+        assert_eq!(
+            block.to_string(),
+            format!(
+                "block {} on {} with 1 transactions",
+                block.hash(),
+                H256::from([1; 32])
+            )
+        );
+
+        let full = format!("{:#}", block);
+        assert!(full.starts_with(&format!("block {}\n", block.hash())));
+        assert!(full.contains(&format!("difficulty:  {}", block.get_difficulty())));
+        // transactions are listed in their one-line form
+        assert!(full.ends_with(&format!("\n    {}", transaction)));
+        assert!(!full.contains('['));
+    }
+
+    #[test]
+    fn genesis_hash_is_stable() {
+        let genesis = Block::get_genesis_block();
+        assert_eq!(genesis.hash(), Block::get_genesis_block().hash());
+        assert_eq!(genesis.get_parent(), H256::from([0; 32]));
+        assert!(genesis.get_transactions().is_empty());
+        assert_eq!(
+            genesis.hash().to_string(),
+            "d5bd81e1af16f2a4fe5c9629606a1665dac3c53d563b485825cacbad47c1140f"
+        );
+    }
+
+    #[test]
+    fn memoized_hash_follows_mutations() {
+        let mut block = Block::new(Block::get_genesis_block().hash());
+        let fresh = |block: &Block| {
+            let encoded = bincode::serialize(block.get_header()).unwrap();
+            hash::digest(&encoded)
+        };
+        let before = block.hash();
+        assert_eq!(before, fresh(&block));
+        assert_eq!(block.hash(), before);
+
+        block.set_nonce(block.get_nonce().wrapping_add(1));
+        assert_ne!(block.hash(), before);
+        assert_eq!(block.hash(), fresh(&block));
+
+        // the memoized hash is neither serialized nor carried over as stale
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded.hash(), block.hash());
+        block.set_state_root(H256::from([1; 32]));
+        assert_eq!(block.hash(), fresh(&block));
+    }
+
+    #[test]
+    fn mining_starts_from_a_random_nonce() {
+        let parent = Block::get_genesis_block().hash();
+        let (first, second) = (Block::new(parent), Block::new(parent));
+        assert_ne!(first.get_nonce(), second.get_nonce());
+
+        // any hash meets the easiest difficulty, so the seed itself is kept
+        let mut block = Block::with_difficulty(parent, [0xff; 32].into());
+        let seed = block.get_nonce();
+        mine_block(&mut block);
+        assert_eq!(block.get_nonce(), seed);
+    }
+}
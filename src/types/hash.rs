@@ -3,19 +3,29 @@ use std::convert::TryInto;
 #[cfg(any(test, test_utilities))]
 use rand::Rng;
 
+/// The algorithm every hash of the chain is computed with: headers, transactions, Merkle trees,
+/// the state root and addresses. Swapping it changes every hash, the genesis hash included, and it
+/// must produce 32 bytes, such as `SHA512_256`.
+pub const DIGEST: &ring::digest::Algorithm = &ring::digest::SHA256;
+
+/// Hash `bytes` with `DIGEST`
+pub fn digest(bytes: &[u8]) -> H256 {
+    ring::digest::digest(DIGEST, bytes).into()
+}
+
 /// An object that can be meaningfully hashed.
 pub trait Hashable {
-    /// Hash the object using SHA256.
+    /// Hash the object with `DIGEST`.
     fn hash(&self) -> H256;
 }
 
-/// A SHA256 hash.
+/// A hash produced by `DIGEST`.
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
 impl Hashable for H256 {
     fn hash(&self) -> H256 {
-        ring::digest::digest(&ring::digest::SHA256, &self.0).into()
+        digest(&self.0)
     }
 }
 
@@ -89,6 +99,14 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl H256 {
+    /// Check the proof of work: both the hash and the target are read as 256-bit big-endian
+    /// integers, and the hash must not exceed the target
+    pub fn meets_difficulty(&self, target: &H256) -> bool {
+        self <= target
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -116,4 +134,47 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn difficulty_boundaries() {
+        let target: H256 =
+            hex!("000010ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let one_less: H256 =
+            hex!("000010fffffffffffffffffffffffffffffffffffffffffffffffffffffffffe").into();
+        let one_more: H256 =
+            hex!("0000110000000000000000000000000000000000000000000000000000000000").into();
+        assert!(target.meets_difficulty(&target));
+        assert!(one_less.meets_difficulty(&target));
+        assert!(!one_more.meets_difficulty(&target));
+    }
+
+    #[test]
+    fn difficulty_reads_first_byte_as_most_significant() {
+        let target: H256 =
+            hex!("0100000000000000000000000000000000000000000000000000000000000000").into();
+        // small in the last byte but large in the first
+        let low_end: H256 =
+            hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let high_end: H256 =
+            hex!("0100000000000000000000000000000000000000000000000000000000000001").into();
+        assert!(low_end.meets_difficulty(&target));
+        assert!(!high_end.meets_difficulty(&target));
+    }
+
+    #[test]
+    fn digest_is_the_configured_algorithm() {
+        // the known SHA256 of "abc", the default digest
+        let abc = digest(b"abc");
+        let expected: H256 =
+            hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").into();
+        assert_eq!(abc, expected);
+        assert_eq!(abc.as_ref(), ring::digest::digest(DIGEST, b"abc").as_ref());
+        assert_eq!(abc.hash(), digest(abc.as_ref()));
+    }
+}
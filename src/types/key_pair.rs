@@ -1,9 +1,110 @@
 use ring::rand;
 use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::fs;
+use std::path::Path;
+
+/// PKCS8 document of the ICO's key pair, embedded at build time
+pub const ICO_PKCS8: &[u8] = include_bytes!("ico_key.pk8");
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
+    from_pkcs8(&generate_pkcs8()).unwrap()
+}
+
+/// Generate a PKCS8 document holding a new random key pair.
+pub fn generate_pkcs8() -> Vec<u8> {
     let rng = rand::SystemRandom::new();
-    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
+    Ed25519KeyPair::generate_pkcs8(&rng)
+        .unwrap()
+        .as_ref()
+        .to_vec()
+}
+
+/// Parse a key pair from a PKCS8 document.
+pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Ed25519KeyPair, String> {
+    Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| format!("Invalid PKCS8 key: {}", e))
+}
+
+/// The account address controlled by a key pair, the base64 encoding of its public key.
+pub fn address(key_pair: &Ed25519KeyPair) -> String {
+    base64::encode(key_pair.public_key())
+}
+
+/// Write a PKCS8 document to a file.
+pub fn export(path: &Path, pkcs8: &[u8]) -> Result<(), String> {
+    fs::write(path, pkcs8).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Read a PKCS8 document from a file, checking it holds a valid key pair.
+pub fn import(path: &Path) -> Result<(Vec<u8>, Ed25519KeyPair), String> {
+    let pkcs8 = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let key_pair = from_pkcs8(&pkcs8).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((pkcs8, key_pair))
+}
+
+/// Load the key pair stored in a file, or generate one and save it there if the file doesn't
+/// exist, so that a node keeps its key across restarts.
+pub fn load_or_generate(path: &Path) -> Result<Ed25519KeyPair, String> {
+    if path.exists() {
+        return import(path).map(|(_, key_pair)| key_pair);
+    }
+    let pkcs8 = generate_pkcs8();
+    export(path, &pkcs8)?;
+    from_pkcs8(&pkcs8)
+}
+
+/// Parse the ICO's key pair from the embedded PKCS8 document.
+pub fn ico_key_pair() -> Result<Ed25519KeyPair, String> {
+    from_pkcs8(ICO_PKCS8).map_err(|e| format!("Embedded ICO key: {}", e))
+}
+
+/// The ICO's key pair, for tests that spend from the initial allocation.
+#[cfg(any(test, test_utilities))]
+pub fn ico() -> Ed25519KeyPair {
+    ico_key_pair().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::test_data_dir;
+
+    #[test]
+    fn export_import_round_trip() {
+        let dir = test_data_dir("key");
+        let path = dir.root().join("key.pk8");
+        let pkcs8 = generate_pkcs8();
+        let key_pair = from_pkcs8(&pkcs8).unwrap();
+        export(&path, &pkcs8).unwrap();
+
+        let (imported, imported_pair) = import(&path).unwrap();
+        assert_eq!(imported, pkcs8);
+        assert_eq!(address(&imported_pair), address(&key_pair));
+        assert_eq!(
+            base64::decode(address(&key_pair)).unwrap(),
+            key_pair.public_key().as_ref()
+        );
+    }
+
+    #[test]
+    fn import_rejects_invalid_pkcs8() {
+        let dir = test_data_dir("key");
+        let path = dir.root().join("garbage.pk8");
+        fs::write(&path, b"not a key").unwrap();
+        let err = import(&path).err().unwrap();
+        assert!(err.contains("Invalid PKCS8 key"), "{}", err);
+        assert!(import(&dir.root().join("missing.pk8")).is_err());
+    }
+
+    #[test]
+    fn load_or_generate_keeps_the_key() {
+        let dir = test_data_dir("key");
+        let path = dir.root().join("node_key.pk8");
+        let first = load_or_generate(&path).unwrap();
+        let saved = fs::read(&path).unwrap();
+        let second = load_or_generate(&path).unwrap();
+        assert_eq!(address(&second), address(&first));
+        assert_eq!(fs::read(&path).unwrap(), saved);
+        assert_ne!(address(&first), address(&ico()));
+    }
 }
@@ -1,83 +1,875 @@
 use crate::blockchain::Blockchain;
+use crate::metrics;
+use crate::persistence::{read_if_exists, write_atomic, DataDir};
 use crate::types::hash::H256;
+use crate::types::state::{AccountAddress, State, StateError};
 use crate::types::transaction::SignedTransaction;
-use std::collections::HashMap;
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::sync::{Arc, Mutex}; // Import the Blockchain type
+use std::thread;
+use std::time::{Duration, Instant};
 
 use super::hash::Hashable;
-use super::transaction::verify;
+use super::signature_cache;
 
+/// How often `start_expiry` looks for transactions to expire
+pub const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pending transactions, checked against the latest state on the way in. A transaction whose
+/// nonce is ahead of its sender's account is queued until the transactions before it are mined.
 pub struct Mempool {
-    transactions: HashMap<H256, SignedTransaction>,
-    // other fields as necessary
+    transactions: HashMap<H256, Entry>, // valid against the state
+    queued: HashMap<H256, Entry>,       // nonce ahead of the sender's account
+    capacity: Option<usize>, // most transactions held, queued ones included, unbounded if None
+    next_seq: u64,
+    state: State, // the tip state, as last given to `revalidate`
+    clock: Box<dyn Fn() -> Instant + Send>, // tells the time entries are added and expired at
+}
+
+/// Where a transaction goes, given the latest state
+#[derive(PartialEq, Eq)]
+enum Admission {
+    Ready,
+    Queued,
+    Rejected,
+}
+
+/// A pending transaction, the order it arrived in and when
+struct Entry {
+    tx: SignedTransaction,
+    seq: u64,
+    added: Instant,
+}
+
+impl Entry {
+    /// Entries with a lower priority are evicted first: lowest fee, then oldest
+    fn priority(&self) -> (u64, u64) {
+        (self.tx.get_fee(), self.seq)
+    }
 }
 
 impl Mempool {
-    /// Create a new mempool
+    /// Create a mempool checking transactions against the default genesis state, for a chain
+    /// built with `Blockchain::new`
     pub fn new() -> Self {
+        Self::from_state(State::new())
+    }
+
+    /// Create a mempool checking transactions against `state`, the tip state of the chain it
+    /// serves
+    pub fn from_state(state: State) -> Self {
         Self {
             transactions: HashMap::new(),
-            // initialize other fields
+            queued: HashMap::new(),
+            capacity: None,
+            next_seq: 0,
+            state,
+            clock: Box::new(Instant::now),
         }
     }
 
-    /// Add a transaction to the mempool if it is valid
-    pub fn add_transaction(&mut self, tx: SignedTransaction) {
+    /// Hold at most `capacity` transactions
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Add a transaction to the mempool if it is valid, returning whether it was added. It must be
+    /// valid against the latest state, except that its nonce may be ahead of the sender's account,
+    /// in which case it is queued. When the mempool is full the lowest priority transaction is
+    /// evicted to make room, unless that would be the new transaction itself.
+    pub fn add_transaction(&mut self, tx: SignedTransaction) -> bool {
         let tx_hash = tx.hash(); // Assume SignedTransaction implements the Hashable trait
-        if self.is_valid(&tx) && !self.transactions.contains_key(&tx_hash) {
-            self.transactions.insert(tx_hash, tx);
+        if self.contains_transaction(&tx_hash) {
+            return false;
+        }
+        let admission = self.admission(&tx);
+        if admission == Admission::Rejected {
+            return false;
+        }
+        let entry = Entry {
+            tx,
+            seq: self.next_seq,
+            added: (self.clock)(),
+        };
+        if let Some(capacity) = self.capacity {
+            if self.len() >= capacity {
+                let lowest = self
+                    .entries()
+                    .min_by_key(|(_, e)| e.priority())
+                    .map(|(hash, e)| (*hash, e.priority()));
+                match lowest {
+                    Some((lowest_hash, lowest_priority)) if lowest_priority < entry.priority() => {
+                        self.evict(&lowest_hash);
+                    }
+                    _ => return false,
+                }
+            }
+        }
+        self.next_seq += 1;
+        if admission == Admission::Ready {
+            self.transactions.insert(tx_hash, entry);
+        } else {
+            self.queued.insert(tx_hash, entry);
+        }
+        metrics::global().transaction_processed();
+        true
+    }
+
+    /// Check a transaction against the latest state: it is ready if the state can apply it now,
+    /// queued if only its nonce is ahead of the sender's account, and rejected otherwise
+    fn admission(&self, tx: &SignedTransaction) -> Admission {
+        match self.state.check_transaction(tx) {
+            Ok(()) => Admission::Ready,
+            // the transactions before it may still arrive, its funds are checked once they did
+            Err(StateError::BadNonce { expected, got }) if got > expected => Admission::Queued,
+            Err(_) => Admission::Rejected,
         }
     }
 
-    /// Checks if a transaction is valid
+    /// Every held transaction, ready and queued
+    fn entries(&self) -> impl Iterator<Item = (&H256, &Entry)> {
+        self.transactions.iter().chain(self.queued.iter())
+    }
+
+    /// Drop a transaction that will not be mined from here
+    fn evict(&mut self, tx_hash: &H256) {
+        let removed = self
+            .transactions
+            .remove(tx_hash)
+            .or_else(|| self.queued.remove(tx_hash));
+        if let Some(entry) = removed {
+            signature_cache::global().invalidate(&entry.tx.full_hash());
+        }
+    }
+
+    /// Checks if a transaction would be admitted, ready or queued, against the latest state
     pub fn is_valid(&self, tx: &SignedTransaction) -> bool {
-        // Implement validity checks here
-        verify(tx.transaction(), tx.public_key(), tx.signature())
+        self.admission(tx) != Admission::Rejected
     }
 
     /// Remove transactions that are included in a block
     pub fn remove_transactions(&mut self, block_transactions: &[H256]) {
         for tx_hash in block_transactions {
-            self.transactions.remove(tx_hash);
+            self.evict(tx_hash);
+        }
+    }
+
+    /// Check the held transactions against a new tip state. Those no longer valid, e.g. because a
+    /// block spent the sender's funds or used up their nonce, are dropped, and queued ones whose
+    /// nonce came up are made ready.
+    pub fn revalidate(&mut self, state: &State) {
+        self.state = state.clone();
+        let stale: Vec<H256> = self
+            .entries()
+            .filter(|(_, entry)| self.admission(&entry.tx) == Admission::Rejected)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for tx_hash in stale.iter() {
+            self.evict(tx_hash);
+        }
+        let ready: Vec<H256> = self
+            .queued
+            .iter()
+            .filter(|(_, entry)| self.admission(&entry.tx) == Admission::Ready)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for tx_hash in ready {
+            let entry = self.queued.remove(&tx_hash).unwrap();
+            self.transactions.insert(tx_hash, entry);
         }
     }
 
+    /// Drop the transactions, ready or queued, that were added more than `max_age` ago
+    pub fn expire(&mut self, max_age: Duration) {
+        let now = (self.clock)();
+        let expired: Vec<H256> = self
+            .entries()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.added) > max_age)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for tx_hash in expired.iter() {
+            self.evict(tx_hash);
+        }
+        if !expired.is_empty() {
+            info!("Expired {} transactions from the mempool", expired.len());
+        }
+    }
+
+    /// Replace the clock telling when transactions are added and expired, to control time in tests
+    #[cfg(any(test, test_utilities))]
+    pub fn set_clock<F>(&mut self, clock: F)
+    where
+        F: Fn() -> Instant + Send + 'static,
+    {
+        self.clock = Box::new(clock);
+    }
+
     /// Method to get transactions for mining a new block
-    /// Here you could implement logic to choose transactions based on fees or other criteria
+    /// Only the transactions `state` can apply in a row are candidates: for each sender, the run
+    /// of consecutive nonces starting at the account's nonce that the balance can pay for. Among
+    /// the next transaction of every sender, the one paying the highest fee is taken first; ties
+    /// are broken in a stable order, by sender, then nonce, then transaction hash, so the same
+    /// mempool always yields the same block.
+    /// At most `max_size` transactions are taken, weighing at most `max_weight` bytes together; a
+    /// transaction that doesn't fit leaves out the rest of its sender's run, while smaller ones
+    /// of other senders may still fill the space
     pub fn get_transactions_for_block(
         &self,
         max_size: usize,
+        max_weight: usize,
+        state: &State,
         blockchain: &Blockchain, // Add a reference to the blockchain
     ) -> Vec<SignedTransaction> {
-        let mut block_transactions = Vec::new();
+        let mut by_sender: HashMap<&String, Vec<(&H256, &SignedTransaction)>> = HashMap::new();
+        for (hash, entry) in self.entries() {
+            // Check if the transaction is already included in the longest chain, one mined only
+            // on a losing branch has to be mined again
+            if blockchain.get_transaction_block(hash).is_none() {
+                by_sender
+                    .entry(entry.tx.get_sender())
+                    .or_default()
+                    .push((hash, &entry.tx));
+            }
+        }
 
-        for tx in self.transactions.values() {
-            if block_transactions.len() >= max_size {
-                break;
+        let mut runs: Vec<VecDeque<(&H256, &SignedTransaction)>> = Vec::new();
+        for (sender, mut candidates) in by_sender {
+            // of the transactions sharing a nonce, the highest fee comes first
+            candidates.sort_by(|(a_hash, a), (b_hash, b)| {
+                a.get_nonce()
+                    .cmp(&b.get_nonce())
+                    .then(b.get_fee().cmp(&a.get_fee()))
+                    .then(a_hash.cmp(b_hash))
+            });
+            let (mut nonce, mut balance) = state
+                .get_account(&AccountAddress::new(sender.clone()))
+                .map(|info| (info.get_nonce(), info.get_balance()))
+                .unwrap_or((0, 0));
+            let mut run = VecDeque::new();
+            for (hash, tx) in candidates {
+                if tx.get_nonce() < nonce {
+                    // already used, by the state or earlier in the run
+                    continue;
+                }
+                let cost = (tx.get_value() as u128).saturating_add(tx.get_fee() as u128);
+                if tx.get_nonce() > nonce || cost > balance {
+                    break;
+                }
+                run.push_back((hash, tx));
+                nonce += 1;
+                balance -= cost;
+            }
+            if !run.is_empty() {
+                runs.push(run);
             }
-            // Check if the transaction is already included in the blockchain.
-            let tx_hash = tx.hash();
-            if !blockchain.contains_transaction(&tx_hash) {
-                block_transactions.push(tx.clone());
+        }
+
+        let mut block_transactions = Vec::new();
+        let mut weight = 0;
+        while block_transactions.len() < max_size {
+            let best = runs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, run)| run.front().map(|(hash, tx)| (i, *hash, *tx)))
+                .min_by(|(_, a_hash, a), (_, b_hash, b)| {
+                    b.get_fee()
+                        .cmp(&a.get_fee())
+                        .then(a.get_sender().cmp(b.get_sender()))
+                        .then(a.get_nonce().cmp(&b.get_nonce()))
+                        .then(a_hash.cmp(b_hash))
+                });
+            match best {
+                Some((i, _, tx)) if weight + tx.serialized_size() > max_weight => {
+                    runs[i].clear();
+                }
+                Some((i, _, tx)) => {
+                    weight += tx.serialized_size();
+                    block_transactions.push(tx.clone());
+                    runs[i].pop_front();
+                }
+                None => break,
             }
         }
 
         block_transactions
     }
 
+    /// The fee at a percentile of the ready transactions, by the nearest-rank method, or None
+    /// if there are none. Queued transactions can't be mined yet and are left out.
+    pub fn fee_percentile(&self, percentile: u32) -> Option<u64> {
+        let mut fees: Vec<u64> = self.transactions.values().map(|e| e.tx.get_fee()).collect();
+        if fees.is_empty() {
+            return None;
+        }
+        fees.sort_unstable();
+        let rank = (percentile.min(100) as usize * fees.len() + 99) / 100;
+        Some(fees[rank.max(1) - 1])
+    }
+
+    /// Smallest fee that would outbid enough ready transactions to make it into the next block of
+    /// at most `max_block_size` transactions, 0 while they all fit. An estimate: the miner also
+    /// takes each sender's transactions in nonce order and fills blocks up to a weight.
+    pub fn next_block_fee(&self, max_block_size: usize) -> u64 {
+        let mut fees: Vec<u64> = self.transactions.values().map(|e| e.tx.get_fee()).collect();
+        if max_block_size == 0 || fees.len() < max_block_size {
+            return 0;
+        }
+        fees.sort_unstable_by(|a, b| b.cmp(a));
+        fees[max_block_size - 1].saturating_add(1)
+    }
+
+    /// Nonce for the next transaction of `address`, whose account is at `state_nonce`: the first
+    /// nonce from there that no held transaction of the sender takes. Transactions queued past a
+    /// gap don't count, the gap has to be filled first.
+    pub fn next_nonce(&self, address: &AccountAddress, state_nonce: u64) -> u64 {
+        let sender = address.to_string();
+        let mut taken: Vec<u64> = self
+            .entries()
+            .map(|(_, entry)| &entry.tx)
+            .filter(|tx| tx.get_sender() == &sender)
+            .map(|tx| tx.get_nonce())
+            .filter(|nonce| *nonce >= state_nonce)
+            .collect();
+        taken.sort_unstable();
+        let mut next = state_nonce;
+        for nonce in taken {
+            if nonce == next {
+                next += 1;
+            } else if nonce > next {
+                break;
+            }
+        }
+        next
+    }
+
+    /// Number of transactions currently held, queued ones included
+    pub fn len(&self) -> usize {
+        self.transactions.len() + self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of transactions waiting for an earlier nonce of their sender
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Hashes of the transactions currently held, in ascending order
+    pub fn transaction_hashes(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = self.entries().map(|(hash, _)| *hash).collect();
+        hashes.sort();
+        hashes
+    }
+
     pub fn contains_transaction(&self, tx_hash: &H256) -> bool {
-        self.transactions.contains_key(tx_hash)
+        self.transactions.contains_key(tx_hash) || self.queued.contains_key(tx_hash)
     }
 
     /// Retrieve a transaction from the mempool by its hash
     pub fn get_transaction(&self, tx_hash: &H256) -> Option<&SignedTransaction> {
-        self.transactions.get(tx_hash)
+        self.transactions
+            .get(tx_hash)
+            .or_else(|| self.queued.get(tx_hash))
+            .map(|entry| &entry.tx)
+    }
+
+    /// Copy the pending transactions, queued ones included, oldest first
+    pub fn export(&self) -> Vec<SignedTransaction> {
+        let mut entries: Vec<&Entry> = self.entries().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.seq);
+        entries.iter().map(|entry| entry.tx.clone()).collect()
+    }
+
+    /// Add transactions as `export` returned them, each checked like any other on the way in.
+    /// Returns how many were added.
+    pub fn import(&mut self, transactions: Vec<SignedTransaction>) -> usize {
+        transactions
+            .into_iter()
+            .filter(|tx| self.add_transaction(tx.clone()))
+            .count()
+    }
+
+    /// Save the pending transactions into a data directory, oldest first
+    pub fn save_to_path(&self, dir: &DataDir) -> io::Result<()> {
+        let transactions = self.export();
+        let bytes = bincode::serialize(&transactions)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&dir.mempool_path(), &bytes)
+    }
+
+    /// Add the transactions written by `save_to_path`, re-checking every one on the way in against
+    /// the state last given to `revalidate`
+    pub fn load_from_path(&mut self, dir: &DataDir) -> io::Result<()> {
+        if let Some(bytes) = read_if_exists(&dir.mempool_path())? {
+            let transactions: Vec<SignedTransaction> = bincode::deserialize(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.import(transactions);
+        }
+        Ok(())
     }
 }
 
 // Shared mempool type definition
 pub type SharedMempool = Arc<Mutex<Mempool>>;
 
+/// Spawn a thread that drops the transactions older than `max_age` from the mempool, checking
+/// every `EXPIRY_INTERVAL`
+pub fn start_expiry(mempool: &SharedMempool, max_age: Duration) {
+    let mempool = Arc::clone(mempool);
+    thread::Builder::new()
+        .name("mempool-expiry".to_string())
+        .spawn(move || loop {
+            thread::sleep(EXPIRY_INTERVAL);
+            mempool.lock().unwrap().expire(max_age);
+        })
+        .unwrap();
+}
+
 // Usage in miner or network worker
 // let mempool: SharedMempool = Arc::new(Mutex::new(Mempool::new()));
 // Now you can pass `mempool` to the miner and network worker
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::Ed25519KeyPair;
+
+    #[test]
+    fn checks_against_the_state_it_is_created_from() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+
+        // a genesis funding another account than the ICO
+        let alice = key_pair::random();
+        let mut genesis = State::new();
+        genesis.add_account_with_balance(AccountAddress::new(key_pair::address(&alice)), 50);
+        let receiver = key_pair::address(&key_pair::random());
+        let tx = SignedTransaction::new(
+            Transaction::new(key_pair::address(&alice), receiver, 5, 0, 0),
+            &alice,
+        );
+        assert!(!Mempool::new().is_valid(&tx));
+        assert!(Mempool::from_state(genesis).add_transaction(tx));
+    }
+
+    #[test]
+    fn forged_sender_rejected() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+
+        // signed by a key of its own, spending from the funded ICO account
+        let ico_address = key_pair::address(&key_pair::ico());
+        let forger = key_pair::random();
+        let receiver = key_pair::address(&forger);
+        let forged = SignedTransaction::new(
+            Transaction::new(ico_address.clone(), receiver.clone(), 5, 0, 0),
+            &forger,
+        );
+        assert!(forged.verify_signed_transaction());
+        let mut mempool = Mempool::new();
+        assert!(!mempool.is_valid(&forged));
+        assert!(!mempool.add_transaction(forged));
+        assert_eq!(mempool.len(), 0);
+
+        // nor is it queued with a nonce ahead
+        let queued =
+            SignedTransaction::new(Transaction::new(ico_address, receiver, 5, 0, 3), &forger);
+        assert!(!mempool.add_transaction(queued));
+        assert_eq!(mempool.len(), 0);
+    }
+
+    #[test]
+    fn export_then_import_reproduces_contents() {
+        let mut mempool = Mempool::new();
+        for nonce in [0, 1, 2, 4] {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            assert!(mempool.add_transaction(tx));
+        }
+        let exported = mempool.export();
+        let hashes: Vec<H256> = exported.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(exported.len(), 4);
+
+        let mut fresh = Mempool::new();
+        assert_eq!(fresh.import(exported.clone()), 4);
+        let reimported: Vec<H256> = fresh.export().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(reimported, hashes);
+        assert_eq!(fresh.transaction_hashes(), mempool.transaction_hashes());
+        assert_eq!(fresh.queued_len(), mempool.queued_len());
+
+        // transactions are checked on the way in, known and invalid ones are skipped
+        let invalid = SignedTransaction::get_random_signed_transaction();
+        assert_eq!(fresh.import(vec![exported[0].clone(), invalid]), 0);
+        assert_eq!(fresh.len(), 4);
+    }
+
+    #[test]
+    fn size_and_hashes_reflect_contents() {
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.len(), 0);
+        let mut expected = Vec::new();
+        for nonce in 0..3 {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            expected.push(tx.hash());
+            mempool.add_transaction(tx);
+        }
+        expected.sort();
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.transaction_hashes(), expected);
+    }
+
+    #[test]
+    fn block_transactions_in_stable_order() {
+        let mut mempool = Mempool::new();
+        for nonce in [3, 0, 2, 1] {
+            mempool.add_transaction(
+                SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap(),
+            );
+        }
+        // senders without an account can't pay, so these are left out
+        for _ in 0..2 {
+            mempool.add_transaction(SignedTransaction::get_random_signed_transaction());
+        }
+        let blockchain = Blockchain::new();
+        let selected =
+            mempool.get_transactions_for_block(10, usize::MAX, blockchain.get_state(), &blockchain);
+        assert_eq!(selected.len(), 4);
+        for pair in selected.windows(2) {
+            let key = |tx: &SignedTransaction| (tx.get_sender().clone(), tx.get_nonce(), tx.hash());
+            assert!(key(&pair[0]) < key(&pair[1]));
+        }
+        for _ in 0..3 {
+            let again: Vec<H256> = mempool
+                .get_transactions_for_block(10, usize::MAX, blockchain.get_state(), &blockchain)
+                .iter()
+                .map(|tx| tx.hash())
+                .collect();
+            let first: Vec<H256> = selected.iter().map(|tx| tx.hash()).collect();
+            assert_eq!(again, first);
+        }
+    }
+
+    #[test]
+    fn highest_fees_selected_first() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let senders: Vec<_> = (0..4).map(|_| key_pair::random()).collect();
+        let mut state = State::new();
+        for sender in senders.iter() {
+            let sender_address = AccountAddress::new(base64::encode(sender.public_key()));
+            state.add_account_with_balance(sender_address, 100);
+        }
+        let mut mempool = Mempool::new();
+        mempool.revalidate(&state);
+        for (sender, fee) in senders.iter().zip([1, 7, 3, 5]) {
+            let sender_address = base64::encode(sender.public_key());
+            let receiver = base64::encode(key_pair::random().public_key());
+            mempool.add_transaction(SignedTransaction::new(
+                Transaction::new(sender_address, receiver, 1, fee, 0),
+                sender,
+            ));
+        }
+        let selected =
+            mempool.get_transactions_for_block(2, usize::MAX, &state, &Blockchain::new());
+        let fees: Vec<u64> = selected.iter().map(|tx| tx.get_fee()).collect();
+        assert_eq!(fees, vec![7, 5]);
+    }
+
+    #[test]
+    fn fee_percentiles_of_ready_transactions() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.fee_percentile(50), None);
+        assert_eq!(mempool.next_block_fee(3), 0);
+
+        let senders: Vec<_> = (0..10).map(|_| key_pair::random()).collect();
+        let mut state = State::new();
+        for sender in senders.iter() {
+            state.add_account_with_balance(AccountAddress::new(key_pair::address(sender)), 100);
+        }
+        mempool.revalidate(&state);
+        let pay = |sender: &Ed25519KeyPair, fee: u64, nonce: u64| {
+            let receiver = key_pair::address(&key_pair::random());
+            let tx = Transaction::new(key_pair::address(sender), receiver, 1, fee, nonce);
+            SignedTransaction::new(tx, sender)
+        };
+        for (sender, fee) in senders.iter().zip(1..=10) {
+            assert!(mempool.add_transaction(pay(sender, fee, 0)));
+        }
+        // queued behind a missing nonce, so it doesn't count
+        assert!(mempool.add_transaction(pay(&senders[0], 100, 2)));
+        assert_eq!(mempool.queued_len(), 1);
+
+        assert_eq!(mempool.fee_percentile(25), Some(3));
+        assert_eq!(mempool.fee_percentile(50), Some(5));
+        assert_eq!(mempool.fee_percentile(90), Some(9));
+        assert_eq!(mempool.fee_percentile(0), Some(1));
+        assert_eq!(mempool.fee_percentile(100), Some(10));
+        // fees 10, 9 and 8 fill a block of three, beating them takes 9
+        assert_eq!(mempool.next_block_fee(3), 9);
+        assert_eq!(mempool.next_block_fee(10), 2);
+        assert_eq!(mempool.next_block_fee(11), 0);
+    }
+
+    #[test]
+    fn next_nonce_follows_pending_transactions() {
+        use crate::types::key_pair;
+
+        let mut mempool = Mempool::new();
+        let ico = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        assert_eq!(mempool.next_nonce(&ico, 0), 0);
+        for nonce in 0..2 {
+            let tx = SignedTransaction::get_random_signed_transaction_from_ico(nonce).unwrap();
+            assert!(mempool.add_transaction(tx));
+        }
+        assert_eq!(mempool.next_nonce(&ico, 0), 2);
+        // once the first is mined the account catches up
+        assert_eq!(mempool.next_nonce(&ico, 1), 2);
+        assert_eq!(mempool.next_nonce(&ico, 5), 5);
+
+        // a transaction queued past a gap leaves the gap to fill
+        let tx = SignedTransaction::get_random_signed_transaction_from_ico(3).unwrap();
+        assert!(mempool.add_transaction(tx));
+        assert_eq!(mempool.next_nonce(&ico, 0), 2);
+        let tx = SignedTransaction::get_random_signed_transaction_from_ico(2).unwrap();
+        assert!(mempool.add_transaction(tx));
+        assert_eq!(mempool.next_nonce(&ico, 0), 4);
+        assert_eq!(
+            mempool.next_nonce(&AccountAddress::new("bob".to_string()), 7),
+            7
+        );
+    }
+
+    #[test]
+    fn only_consecutive_nonces_selected() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let mut mempool = Mempool::new();
+        // nonce 2 is missing, and the later nonces pay more
+        for (nonce, fee) in [(4, 9), (1, 0), (3, 9), (0, 0)] {
+            let receiver = base64::encode(key_pair::random().public_key());
+            mempool.add_transaction(SignedTransaction::new(
+                Transaction::new(ico_address.clone(), receiver, 1, fee, nonce),
+                &ico,
+            ));
+        }
+        let blockchain = Blockchain::new();
+        let selected =
+            mempool.get_transactions_for_block(10, usize::MAX, blockchain.get_state(), &blockchain);
+        let nonces: Vec<u64> = selected.iter().map(|tx| tx.get_nonce()).collect();
+        assert_eq!(nonces, vec![0, 1]);
+
+        // the run starts at the account's nonce
+        let mut state = blockchain.get_state().clone();
+        state.apply_transaction(&selected[0]).unwrap();
+        let selected = mempool.get_transactions_for_block(10, usize::MAX, &state, &blockchain);
+        let nonces: Vec<u64> = selected.iter().map(|tx| tx.get_nonce()).collect();
+        assert_eq!(nonces, vec![1]);
+    }
+
+    #[test]
+    fn block_weight_stays_under_limit() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let senders: Vec<_> = (0..6).map(|_| key_pair::random()).collect();
+        let mut state = State::new();
+        let mut mempool = Mempool::new();
+        for (i, sender) in senders.iter().enumerate() {
+            let sender_address = base64::encode(sender.public_key());
+            state.add_account_with_balance(AccountAddress::new(sender_address.clone()), 100);
+            mempool.revalidate(&state);
+            // receivers of growing length make transactions of growing size
+            let receiver = "r".repeat(1 + 100 * i);
+            mempool.add_transaction(SignedTransaction::new(
+                Transaction::new(sender_address, receiver, 1, 1, 0),
+                sender,
+            ));
+        }
+        let sizes: Vec<usize> = mempool
+            .export()
+            .iter()
+            .map(|tx| tx.serialized_size())
+            .collect();
+        assert!(sizes.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let blockchain = Blockchain::new();
+        for max_weight in [
+            0,
+            sizes[0],
+            sizes[5],
+            sizes[1] + sizes[4],
+            sizes.iter().sum(),
+        ] {
+            let selected = mempool.get_transactions_for_block(10, max_weight, &state, &blockchain);
+            let weight: usize = selected.iter().map(|tx| tx.serialized_size()).sum();
+            assert!(weight <= max_weight, "{} over {}", weight, max_weight);
+            // transactions that don't fit make way for smaller ones
+            let smallest_left = sizes
+                .iter()
+                .find(|size| !selected.iter().any(|tx| tx.serialized_size() == **size));
+            if let Some(size) = smallest_left {
+                assert!(weight + size > max_weight);
+            }
+        }
+        let all: usize = sizes.iter().sum();
+        assert_eq!(
+            mempool
+                .get_transactions_for_block(10, all, &state, &blockchain)
+                .len(),
+            6
+        );
+    }
+
+    #[test]
+    fn capacity_evicts_lowest_priority() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let mut mempool = Mempool::new().with_capacity(3);
+        let mut hashes = Vec::new();
+        for (nonce, fee) in [2, 1, 4, 1, 3].iter().enumerate() {
+            let receiver = base64::encode(key_pair::random().public_key());
+            let tx = SignedTransaction::new(
+                Transaction::new(ico_address.clone(), receiver, 1, *fee, nonce as u64),
+                &ico,
+            );
+            hashes.push(tx.hash());
+            mempool.add_transaction(tx);
+            assert!(mempool.len() <= 3);
+            if nonce == 3 {
+                assert!(!mempool.contains_transaction(&hashes[1]));
+                assert!(mempool.contains_transaction(&hashes[3]));
+            }
+        }
+        // the two fee 1 transactions went first, the older one when the fourth arrived
+        let mut expected = vec![hashes[0], hashes[2], hashes[4]];
+        expected.sort();
+        assert_eq!(mempool.transaction_hashes(), expected);
+    }
+
+    #[test]
+    fn rejects_transactions_the_state_cannot_apply() {
+        use crate::types::key_pair;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let ico = key_pair::ico();
+        let ico_address = base64::encode(ico.public_key());
+        let receiver = base64::encode(key_pair::random().public_key());
+        let payment = |value: i64, nonce: u64| {
+            SignedTransaction::new(
+                Transaction::new(ico_address.clone(), receiver.clone(), value, 0, nonce),
+                &ico,
+            )
+        };
+        let balance = State::new().get_balance(&AccountAddress::new(ico_address.clone()));
+        let mut mempool = Mempool::new();
+        // spends more than the sender has
+        assert!(!mempool.add_transaction(payment(balance as i64 + 1, 0)));
+        // the sender has no account
+        assert!(!mempool.add_transaction(SignedTransaction::get_random_signed_transaction()));
+        assert!(mempool.is_empty());
+
+        // a nonce ahead of the account is queued until the one before it is mined
+        let first = payment(1, 0);
+        let second = payment(1, 1);
+        assert!(mempool.add_transaction(second.clone()));
+        assert_eq!(mempool.queued_len(), 1);
+        assert!(mempool.add_transaction(first.clone()));
+        assert_eq!(mempool.queued_len(), 1);
+
+        let mut state = State::new();
+        state.apply_transaction(&first).unwrap();
+        mempool.remove_transactions(&[first.hash()]);
+        mempool.revalidate(&state);
+        assert_eq!(mempool.queued_len(), 0);
+        assert_eq!(mempool.transaction_hashes(), vec![second.hash()]);
+        // the nonce is used up now
+        assert!(!mempool.add_transaction(payment(2, 0)));
+    }
+
+    #[test]
+    fn expire_drops_old_transactions() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let mut mempool = Mempool::new();
+        {
+            let now = Arc::clone(&now);
+            mempool.set_clock(move || *now.lock().unwrap());
+        }
+        let old = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        mempool.add_transaction(old.clone());
+        *now.lock().unwrap() += Duration::from_secs(50);
+        let new = SignedTransaction::get_random_signed_transaction_from_ico(1).unwrap();
+        mempool.add_transaction(new.clone());
+
+        mempool.expire(Duration::from_secs(60));
+        assert_eq!(mempool.len(), 2);
+        *now.lock().unwrap() += Duration::from_secs(20);
+        mempool.expire(Duration::from_secs(60));
+        assert_eq!(mempool.transaction_hashes(), vec![new.hash()]);
+    }
+
+    #[test]
+    fn transaction_of_a_losing_branch_selected_again() {
+        use crate::types::block::generate_random_block;
+
+        let mined = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        let mut mempool = Mempool::new();
+        mempool.add_transaction(mined.clone());
+
+        // the transaction is mined on a branch that loses to a longer one without it
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut losing = generate_random_block(&genesis_hash);
+        losing
+            .get_content_mut()
+            .add_transactions(vec![mined.clone()]);
+        blockchain.insert(&losing);
+        let first = generate_random_block(&genesis_hash);
+        blockchain.insert(&first);
+        blockchain.insert(&generate_random_block(&first.hash()));
+        assert!(blockchain.contains_transaction(&mined.hash()));
+
+        let selected =
+            mempool.get_transactions_for_block(10, usize::MAX, blockchain.get_state(), &blockchain);
+        let selected: Vec<H256> = selected.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![mined.hash()]);
+    }
+
+    #[test]
+    fn revalidate_drops_stale_transactions() {
+        use crate::types::block::generate_random_block;
+
+        let pending = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        let mut mempool = Mempool::new();
+        mempool.add_transaction(pending.clone());
+
+        // another transaction with the same nonce gets mined first
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.get_content_mut().add_transactions(vec![
+            SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap(),
+        ]);
+        blockchain.insert(&block);
+
+        mempool.revalidate(blockchain.get_state());
+        assert!(!mempool.contains_transaction(&pending.hash()));
+    }
+}
@@ -1,11 +1,15 @@
-use super::hash::{Hashable, H256};
-use ring::digest;
+use super::hash::{self, Hashable, H256};
 
 /// A Merkle tree.
+///
+/// A tree without leaves has no layers and its root is the digest of the empty byte string
+/// (`e3b0c442…b855` with SHA256), so an empty block still commits to a real hash. No proof can be
+/// produced for it and nothing verifies against it.
 #[derive(Debug, Default)]
 pub struct MerkleTree {
     root: H256,
-    layers: Vec<Vec<H256>>,
+    layers: Vec<Vec<H256>>, // every layer below the root, padded to an even length
+    leaf_count: usize,
 }
 
 impl MerkleTree {
@@ -32,7 +36,7 @@ impl MerkleTree {
                 let mut concated = Vec::new();
                 concated.extend_from_slice(left.as_ref());
                 concated.extend_from_slice(right.as_ref());
-                let hash = ring::digest::digest(&ring::digest::SHA256, &concated).into();
+                let hash = hash::digest(&concated);
                 next_layer.push(hash);
             }
             layers.push(current_layer.clone());
@@ -42,22 +46,34 @@ impl MerkleTree {
         let root = if !current_layer.is_empty() {
             current_layer[0].clone()
         } else {
-            H256::default()
+            hash::digest(&[])
         };
 
-        Self { root, layers }
+        Self {
+            root,
+            layers,
+            leaf_count: data.len(),
+        }
     }
 
     pub fn root(&self) -> H256 {
         self.root
     }
 
-    /// Returns the Merkle Proof of data at index i
-    pub fn proof(&self, index: usize) -> Vec<H256> {
+    /// Returns the Merkle Proof of data at index i, the sibling hashes from the leaf up to the
+    /// root. A tree with a single leaf has an empty proof.
+    pub fn proof(&self, index: usize) -> Result<Vec<H256>, String> {
+        if index >= self.leaf_count {
+            return Err(format!(
+                "Leaf index {} is out of range for {} leaves",
+                index, self.leaf_count
+            ));
+        }
         let mut proof = Vec::new();
         let mut index = index;
 
         for layer in &self.layers {
+            // layers are padded by duplicating their last element, so a sibling always exists
             if index % 2 == 0 {
                 proof.push(layer[index + 1].clone());
             } else {
@@ -66,7 +82,7 @@ impl MerkleTree {
             index /= 2;
         }
 
-        proof
+        Ok(proof)
     }
 }
 
@@ -79,30 +95,21 @@ pub fn verify(
     mut index: usize,
     leaf_size: usize,
 ) -> bool {
-    let mut hash = datum.clone();
-    let mut leaf_count = leaf_size;
-    // println!("Proof vector: {:?}", proof);
-    // println!("hash: {:?}", &datum);
-    // println!("root: {:?}", root);
-
-    if leaf_count % 2 != 0 {
-        leaf_count += 1;
+    if index >= leaf_size {
+        return false;
     }
-
-    index += leaf_count - 1;
+    let mut hash = datum.clone();
 
     for &sibling in proof.iter() {
+        // an even position is a left child, whatever the size of its layer
         let concatenated = if index % 2 == 0 {
-            // println!("right, sibling: {:?}", &sibling);
-            [sibling.as_ref(), hash.as_ref()].concat()
-        } else {
-            // println!("left, sibling: {:?}", &sibling);
             [hash.as_ref(), sibling.as_ref()].concat()
+        } else {
+            [sibling.as_ref(), hash.as_ref()].concat()
         };
 
-        hash = H256::from(digest::digest(&digest::SHA256, &concatenated));
-        // println!("proof iter hash: {:?}", &hash);
-        index = (index - 1) / 2;
+        hash = hash::digest(&concatenated);
+        index /= 2;
     }
     &hash == root
 }
@@ -175,7 +182,7 @@ mod tests {
     fn merkle_proof() {
         let input_data: Vec<H256> = gen_merkle_tree_data!();
         let merkle_tree = MerkleTree::new(&input_data);
-        let proof = merkle_tree.proof(0);
+        let proof = merkle_tree.proof(0).unwrap();
         assert_eq!(
             proof,
             vec![hex!("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into()]
@@ -188,7 +195,7 @@ mod tests {
     fn merkle_verifying() {
         let input_data: Vec<H256> = gen_merkle_tree_data!();
         let merkle_tree = MerkleTree::new(&input_data);
-        let proof = merkle_tree.proof(0);
+        let proof = merkle_tree.proof(0).unwrap();
         assert!(verify(
             &merkle_tree.root(),
             &input_data[0].hash(),
@@ -197,6 +204,67 @@ mod tests {
             input_data.len()
         ));
     }
+
+    #[test]
+    fn proofs_verify_for_any_leaf_count() {
+        for leaf_count in [1, 2, 3, 5] {
+            let input_data: Vec<H256> =
+                (0..leaf_count).map(|i| H256::from([i as u8; 32])).collect();
+            let merkle_tree = MerkleTree::new(&input_data);
+            for (index, datum) in input_data.iter().enumerate() {
+                let proof = merkle_tree.proof(index).unwrap();
+                assert!(verify(
+                    &merkle_tree.root(),
+                    &datum.hash(),
+                    &proof,
+                    index,
+                    leaf_count
+                ));
+                // the proof is tied to the position of the leaf
+                if leaf_count > 1 {
+                    let other = (index + 1) % leaf_count;
+                    assert!(!verify(
+                        &merkle_tree.root(),
+                        &input_data[other].hash(),
+                        &proof,
+                        index,
+                        leaf_count
+                    ));
+                }
+            }
+            assert!(merkle_tree.proof(leaf_count).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_tree_root_is_hash_of_nothing() {
+        let input_data: Vec<H256> = vec![];
+        let merkle_tree = MerkleTree::new(&input_data);
+        assert_eq!(
+            merkle_tree.root(),
+            (hex!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")).into()
+        );
+        assert!(merkle_tree.proof(0).is_err());
+        assert!(!verify(&merkle_tree.root(), &merkle_tree.root(), &[], 0, 0));
+    }
+
+    #[test]
+    fn parent_is_digest_of_children() {
+        let input_data: Vec<H256> = vec![H256::from([1; 32]), H256::from([2; 32])];
+        let merkle_tree = MerkleTree::new(&input_data);
+        // leaves are the hashes of the data
+        let (left, right) = (input_data[0].hash(), input_data[1].hash());
+        let expected = hash::digest(&[left.as_ref(), right.as_ref()].concat());
+        assert_eq!(merkle_tree.root(), expected);
+    }
+
+    #[test]
+    fn single_leaf_proof_is_empty() {
+        let input_data: Vec<H256> = vec![H256::from([7; 32])];
+        let merkle_tree = MerkleTree::new(&input_data);
+        assert_eq!(merkle_tree.root(), input_data[0].hash());
+        assert!(merkle_tree.proof(0).unwrap().is_empty());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
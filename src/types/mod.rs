@@ -5,4 +5,5 @@ pub mod merkle;
 pub mod key_pair;
 pub mod transaction;
 pub mod mempool;
+pub mod signature_cache;
 pub mod state;
\ No newline at end of file
@@ -0,0 +1,209 @@
+use crate::types::hash::H256;
+use crate::types::transaction::{verify, SignedTransaction};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// Default number of verified transactions remembered by the global cache
+pub const DEFAULT_CAPACITY: usize = 100000;
+/// Fewest transactions `verify_all` spreads across threads, smaller batches are not worth the
+/// cost of spawning them
+const PARALLEL_THRESHOLD: usize = 32;
+
+/// Remembers transactions whose signature already checked out, so a transaction is verified once
+/// when it enters the mempool rather than again at every later stage. Entries are keyed by the
+/// hash of the whole signed transaction (payload, signature and public key), so a transaction that
+/// differs from a cached one in any byte is verified from scratch.
+pub struct SignatureCache {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    capacity: usize,
+    // number of times each cached entry saved a verification
+    hits: HashMap<H256, u64>,
+    // insertion order, used to drop the oldest entry when full
+    order: VecDeque<H256>,
+    // number of signatures checked with Ed25519, cache hits and malformed transactions aside
+    verifications: u64,
+}
+
+impl SignatureCache {
+    /// Create a cache holding up to `capacity` entries, a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                capacity,
+                hits: HashMap::new(),
+                order: VecDeque::new(),
+                verifications: 0,
+            }),
+        }
+    }
+
+    /// Change the capacity, dropping the oldest entries if the cache is now over it
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.shrink();
+    }
+
+    /// Check the signature of `tx`, skipping the cryptographic check if it is cached. A
+    /// transaction that fails `check_structure` is rejected without one.
+    pub fn verify(&self, tx: &SignedTransaction) -> bool {
+        if tx.check_structure().is_err() {
+            return false;
+        }
+        let tx_hash = tx.full_hash();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(hits) = inner.hits.get_mut(&tx_hash) {
+                *hits += 1;
+                return true;
+            }
+            inner.verifications += 1;
+        }
+        // verify outside the lock, only valid signatures are remembered
+        let valid = verify(tx.transaction(), tx.public_key(), tx.signature());
+        if valid {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.capacity > 0 && !inner.hits.contains_key(&tx_hash) {
+                inner.hits.insert(tx_hash, 0);
+                inner.order.push_back(tx_hash);
+                inner.shrink();
+            }
+        }
+        valid
+    }
+
+    /// Check the signatures of all of `txs` across the available cores, returning whether every one
+    /// is valid. The valid ones are cached, so applying them afterwards does not verify them again.
+    pub fn verify_all(&self, txs: &[SignedTransaction]) -> bool {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        if threads == 1 || txs.len() < PARALLEL_THRESHOLD {
+            return txs.iter().all(|tx| self.verify(tx));
+        }
+        let chunk_size = txs.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let chunks: Vec<_> = txs
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || chunk.iter().all(|tx| self.verify(tx))))
+                .collect();
+            // the scope joins the threads left behind after an invalid chunk
+            chunks.into_iter().all(|chunk| chunk.join().unwrap())
+        })
+    }
+
+    /// Forget a transaction by its `full_hash`, e.g. once it leaves the mempool
+    pub fn invalidate(&self, tx_hash: &H256) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.hits.remove(tx_hash).is_some() {
+            inner.order.retain(|h| h != tx_hash);
+        }
+    }
+
+    /// How many signatures were checked with Ed25519, rather than served from the cache
+    pub fn verifications(&self) -> u64 {
+        self.inner.lock().unwrap().verifications
+    }
+
+    /// How many verifications the entry for `tx_hash`, a `full_hash`, has saved, or `None` if it
+    /// isn't cached
+    pub fn hits(&self, tx_hash: &H256) -> Option<u64> {
+        self.inner.lock().unwrap().hits.get(tx_hash).copied()
+    }
+}
+
+impl Inner {
+    fn shrink(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hits.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The cache shared by the mempool and the state
+pub fn global() -> &'static SignatureCache {
+    static GLOBAL: OnceLock<SignatureCache> = OnceLock::new();
+    GLOBAL.get_or_init(|| SignatureCache::new(DEFAULT_CAPACITY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::mempool::Mempool;
+    use crate::types::state::State;
+
+    #[test]
+    fn verified_once_across_lifecycle() {
+        let tx = SignedTransaction::get_random_signed_transaction_from_ico(0).unwrap();
+        let mut mempool = Mempool::new();
+        mempool.add_transaction(tx.clone());
+        assert_eq!(global().hits(&tx.full_hash()), Some(0));
+        let mut state = State::new();
+        state.apply_transaction(&tx).unwrap();
+        assert_eq!(global().hits(&tx.full_hash()), Some(1));
+    }
+
+    #[test]
+    fn tampered_transaction_is_not_served_from_cache() {
+        let cache = SignatureCache::new(10);
+        let tx = SignedTransaction::get_random_signed_transaction();
+        assert!(cache.verify(&tx));
+        let mut bytes = bincode::serialize(&tx).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered: SignedTransaction = bincode::deserialize(&bytes).unwrap();
+        assert!(!cache.verify(&tampered));
+        assert_eq!(cache.hits(&tampered.full_hash()), None);
+    }
+
+    #[test]
+    fn capacity_bounds_entries() {
+        let cache = SignatureCache::new(2);
+        let txs: Vec<SignedTransaction> = (0..3)
+            .map(|_| SignedTransaction::get_random_signed_transaction())
+            .collect();
+        for tx in txs.iter() {
+            assert!(cache.verify(tx));
+        }
+        assert_eq!(cache.hits(&txs[0].full_hash()), None);
+        assert_eq!(cache.hits(&txs[2].full_hash()), Some(0));
+        cache.set_capacity(0);
+        assert_eq!(cache.hits(&txs[2].full_hash()), None);
+    }
+
+    #[test]
+    fn verify_all_checks_every_transaction() {
+        let cache = SignatureCache::new(1000);
+        let mut txs: Vec<SignedTransaction> = (0..200)
+            .map(|_| SignedTransaction::get_random_signed_transaction())
+            .collect();
+        assert!(cache.verify_all(&txs));
+        assert!(txs.iter().all(|tx| cache.hits(&tx.full_hash()) == Some(0)));
+
+        let mut bytes = bincode::serialize(&txs[150]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        txs[150] = bincode::deserialize(&bytes).unwrap();
+        assert!(!cache.verify_all(&txs));
+        assert_eq!(cache.hits(&txs[150].full_hash()), None);
+    }
+
+    #[test]
+    fn malformed_transaction_rejected_before_verifying() {
+        let cache = SignatureCache::new(10);
+        let tx = SignedTransaction::get_random_signed_transaction();
+        let mut truncated: serde_json::Value = serde_json::to_value(&tx).unwrap();
+        truncated["public_key"].as_array_mut().unwrap().pop();
+        let truncated: SignedTransaction = serde_json::from_value(truncated).unwrap();
+        assert!(truncated.check_structure().is_err());
+        assert!(!cache.verify(&truncated));
+        assert_eq!(cache.verifications(), 0);
+
+        assert!(cache.verify(&tx));
+        assert_eq!(cache.verifications(), 1);
+    }
+}
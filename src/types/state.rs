@@ -1,14 +1,94 @@
-use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::signature::KeyPair;
+use serde::{Deserialize, Serialize};
 
-use crate::types::transaction::SignedTransaction;
+use crate::config::{self, ConsensusParams};
+use crate::types::hash::{self, H256};
+use crate::types::key_pair;
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::{SignedTransaction, PUBLIC_KEY_LEN};
+use std::path::Path;
 use std::{collections::HashMap, vec};
 
 use super::address::Address;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Amount credited to the miner by the coinbase transaction of each block, under the default
+/// consensus parameters
+pub const BLOCK_REWARD: u128 = config::DEFAULT_BLOCK_REWARD;
+
+/// Why a transaction or block could not be applied to a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// A transaction that fails `SignedTransaction::check_structure`, its signature is not checked
+    Malformed(String),
+    InvalidSignature,
+    /// The sender isn't the address of the key that signed the transaction
+    SenderMismatch,
+    InsufficientFunds {
+        balance: u128,
+        needed: u128,
+    },
+    BadNonce {
+        expected: u64,
+        got: u64,
+    },
+    UnknownSender(AccountAddress),
+    BlockNotFound(H256),
+    /// A coinbase anywhere but first in a block, or a transaction applied as one that isn't
+    MisplacedCoinbase,
+    WrongReward(i64),
+    BalanceOverflow(AccountAddress),
+    /// A transfer of less than the dust threshold
+    Dust {
+        value: i64,
+        threshold: u128,
+    },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Malformed(reason) => write!(f, "Malformed transaction: {}", reason),
+            StateError::InvalidSignature => write!(f, "Invalid transaction signature"),
+            StateError::SenderMismatch => {
+                write!(f, "Sender is not the address of the signing key")
+            }
+            StateError::InsufficientFunds { balance, needed } => write!(
+                f,
+                "Insufficient funds: balance {}, needed {}",
+                balance, needed
+            ),
+            StateError::BadNonce { expected, got } => {
+                write!(f, "Incorrect nonce: expected {}, got {}", expected, got)
+            }
+            StateError::UnknownSender(address) => {
+                write!(f, "Sender account {} does not exist", address)
+            }
+            StateError::BlockNotFound(hash) => write!(f, "Block {} not found", hash),
+            StateError::MisplacedCoinbase => {
+                write!(
+                    f,
+                    "Coinbase transaction outside the first position of a block"
+                )
+            }
+            StateError::WrongReward(value) => write!(f, "Coinbase pays the wrong reward {}", value),
+            StateError::BalanceOverflow(address) => {
+                write!(f, "Balance of {} overflows", address)
+            }
+            StateError::Dust { value, threshold } => write!(
+                f,
+                "Value {} is below the dust threshold {}",
+                value, threshold
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct AccountAddress(String); // Replace with your own account address type if necessary
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccountInfo {
     nonce: u64,    // Nonce of the account
     balance: u128, // Balance of the account
@@ -26,13 +106,40 @@ impl AccountInfo {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     accounts: HashMap<AccountAddress, AccountInfo>,
+    block_reward: u128,   // what a coinbase must pay
+    dust_threshold: u128, // smallest value a transfer may move
+    allocated: u128,      // coins given to accounts directly, such as the genesis allocation
+    issued: u128,         // coins created by coinbase transactions
+    burned: u128,         // fees of transactions applied without a fee recipient
 }
 
 use std::fmt;
 
+impl AccountAddress {
+    pub fn new(address: String) -> Self {
+        AccountAddress(address)
+    }
+
+    /// An address given by a user, which must be the base64 encoding of an Ed25519 public key
+    /// for its coins to be spendable
+    pub fn parse(address: &str) -> Result<Self, String> {
+        let key =
+            base64::decode(address).map_err(|e| format!("invalid address {}: {}", address, e))?;
+        if key.len() != PUBLIC_KEY_LEN {
+            return Err(format!(
+                "invalid address {}: expected a {} byte public key, got {} bytes",
+                address,
+                PUBLIC_KEY_LEN,
+                key.len()
+            ));
+        }
+        Ok(AccountAddress(address.to_string()))
+    }
+}
+
 impl fmt::Display for AccountAddress {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -40,22 +147,47 @@ impl fmt::Display for AccountAddress {
 }
 
 impl State {
-    // Constructor to create a new State
+    // Constructor to create a new State, panicking if the embedded ICO key is invalid; the node
+    // itself starts from `with_params`, which reports it
     pub fn new() -> Self {
-        let mut state = Self {
-            accounts: HashMap::new(),
-        };
+        Self::with_params(&ConsensusParams::default()).unwrap()
+    }
+
+    /// The state at genesis under the given parameters, only the ICO account is funded
+    pub fn with_params(params: &ConsensusParams) -> Result<Self, String> {
+        Self::with_ico_key(params, key_pair::ICO_PKCS8)
+    }
+
+    /// Like `with_params`, funding the ICO account of the given PKCS8 key pair
+    fn with_ico_key(params: &ConsensusParams, ico_pkcs8: &[u8]) -> Result<Self, String> {
+        let mut state = Self::empty(params);
 
         // Call the desired function here
-        state.initialize_default_accounts();
+        state.initialize_default_accounts(ico_pkcs8, params.ico_balance)?;
 
-        state
+        Ok(state)
     }
 
-    fn initialize_default_accounts(&mut self) {
+    /// A state without any account
+    fn empty(params: &ConsensusParams) -> Self {
+        Self {
+            accounts: HashMap::new(),
+            block_reward: params.block_reward,
+            dust_threshold: params.dust_threshold,
+            allocated: 0,
+            issued: 0,
+            burned: 0,
+        }
+    }
+
+    fn initialize_default_accounts(
+        &mut self,
+        ico_pkcs8: &[u8],
+        ico_balance: u128,
+    ) -> Result<(), String> {
         // Load the ICO's private key
-        let ico_private_key_bytes = include_bytes!("key_pair.pem"); // Load the ICO's private key file
-        let key_pair = Ed25519KeyPair::from_pkcs8(ico_private_key_bytes).unwrap();
+        let key_pair =
+            key_pair::from_pkcs8(ico_pkcs8).map_err(|e| format!("Embedded ICO key: {}", e))?;
 
         // ICO's public key
         let ico_public_key = key_pair.public_key();
@@ -63,16 +195,90 @@ impl State {
         // Encode the public key in a readable format (e.g., Base64)
         let ico_public_key_string = base64::encode(ico_public_key);
 
-        self.add_account_with_balance(AccountAddress(ico_public_key_string), 200000)
+        self.add_account_with_balance(AccountAddress(ico_public_key_string), ico_balance);
+        Ok(())
+    }
+
+    /// Create the state at genesis from an allocation file, a JSON object mapping addresses to
+    /// their initial balance. The embedded ICO account is only funded if the file lists it.
+    /// Every address must parse, or its coins could never be spent, and the balances together
+    /// must fit in a u128.
+    pub fn from_genesis_alloc(path: &Path, params: &ConsensusParams) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        let allocation: HashMap<String, u128> = serde_json::from_str(&contents)
+            .map_err(|e| format!("Error parsing {}: {}", path.display(), e))?;
+        let mut state = Self::empty(params);
+        let mut total: u128 = 0;
+        for (address, balance) in allocation {
+            let address = AccountAddress::parse(&address)
+                .map_err(|e| format!("Error in {}: {}", path.display(), e))?;
+            total = total
+                .checked_add(balance)
+                .ok_or_else(|| format!("Error in {}: the balances overflow", path.display()))?;
+            state.add_account_with_balance(address, balance);
+        }
+        Ok(state)
     }
 
     pub fn get_accounts(&self) -> &HashMap<AccountAddress, AccountInfo> {
         &self.accounts
     }
 
+    /// Sum of the balances of all accounts
+    pub fn total_supply(&self) -> u128 {
+        self.accounts
+            .values()
+            .fold(0u128, |total, info| total.saturating_add(info.balance))
+    }
+
+    /// Coins created by coinbase transactions so far
+    pub fn issued(&self) -> u128 {
+        self.issued
+    }
+
+    /// Coins destroyed as the fee of transactions applied outside of a block
+    pub fn burned(&self) -> u128 {
+        self.burned
+    }
+
+    /// Whether the total supply is what was allocated to accounts, plus what coinbase
+    /// transactions issued, minus the burned fees. Transfers must neither create nor destroy
+    /// coins, so this failing points at a bug in applying them.
+    pub fn supply_is_conserved(&self) -> bool {
+        let expected = self
+            .allocated
+            .saturating_add(self.issued)
+            .saturating_sub(self.burned);
+        self.total_supply() == expected
+    }
+
+    /// Account for a balance set directly, replacing `old`
+    fn allocate(&mut self, old: u128, new: u128) {
+        self.allocated = self.allocated.saturating_add(new).saturating_sub(old);
+    }
+
+    /// Commitment to every account: the root of a Merkle tree whose leaves are the hashes of
+    /// the (address, nonce, balance) entries sorted by address
+    pub fn state_root(&self) -> H256 {
+        let mut accounts: Vec<(&AccountAddress, &AccountInfo)> = self.accounts.iter().collect();
+        accounts.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+        let leaves: Vec<H256> = accounts
+            .iter()
+            .map(|(address, info)| {
+                let encoded = bincode::serialize(&(&address.0, info.nonce, info.balance))
+                    .expect("failed to serialize");
+                hash::digest(&encoded)
+            })
+            .collect();
+        MerkleTree::new(&leaves).root()
+    }
+
     // Function to add or update an account in the state
     pub fn update_account(&mut self, address: AccountAddress, nonce: u64, balance: u128) {
         let account_info = AccountInfo { nonce, balance };
+        let old = self.get_balance(&address);
+        self.allocate(old, balance);
         self.accounts.insert(address, account_info);
     }
 
@@ -81,72 +287,550 @@ impl State {
         self.accounts.get(address)
     }
 
+    // Function to get an account's balance, accounts that don't exist yet hold nothing
+    pub fn get_balance(&self, address: &AccountAddress) -> u128 {
+        self.accounts
+            .get(address)
+            .map(|info| info.balance)
+            .unwrap_or(0)
+    }
+
     // Function to add a new account with a public key and balance
     pub fn add_account_with_balance(&mut self, address: AccountAddress, balance: u128) {
         let account_info = AccountInfo { nonce: 0, balance };
+        let old = self.get_balance(&address);
+        self.allocate(old, balance);
         self.accounts.insert(address, account_info);
     }
 
-    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<(), String> {
-        // Verify the signature of the transaction
-        if !tx.verify_signed_transaction() {
-            return Err("Invalid transaction signature".to_string());
+    /// Apply the transactions of a block in order. Only the first transaction may be a coinbase,
+    /// and the fees of the others are paid to its receiver. A block applies as a whole: if a
+    /// transaction fails, the state is left unchanged and the failure is returned with the
+    /// transaction's position in the block.
+    pub fn apply_block(
+        &mut self,
+        transactions: &[SignedTransaction],
+    ) -> Result<(), (usize, StateError)> {
+        let fee_recipient = transactions
+            .first()
+            .filter(|tx| tx.is_coinbase())
+            .map(|tx| AccountAddress(tx.get_receiver().clone()));
+        let mut next = self.clone();
+        for (index, tx) in transactions.iter().enumerate() {
+            next.apply_block_transaction(index, tx, fee_recipient.as_ref())
+                .map_err(|e| (index, e))?;
+        }
+        debug_assert!(next.supply_is_conserved(), "block changed the total supply");
+        *self = next;
+        Ok(())
+    }
+
+    /// Apply the transaction at position `index` of a block, only the first one may be a coinbase
+    pub fn apply_block_transaction(
+        &mut self,
+        index: usize,
+        tx: &SignedTransaction,
+        fee_recipient: Option<&AccountAddress>,
+    ) -> Result<(), StateError> {
+        if index == 0 && tx.is_coinbase() {
+            self.apply_coinbase(tx)
+        } else {
+            self.apply_transaction_paying_fee(tx, fee_recipient)
         }
+    }
+
+    /// Credit the block reward to the receiver of a coinbase transaction
+    pub fn apply_coinbase(&mut self, tx: &SignedTransaction) -> Result<(), StateError> {
+        if !tx.is_coinbase() {
+            return Err(StateError::MisplacedCoinbase);
+        }
+        if tx.get_value() < 0 || tx.get_value() as u128 != self.block_reward {
+            return Err(StateError::WrongReward(tx.get_value()));
+        }
+        let receiver_info = self
+            .accounts
+            .entry(AccountAddress(tx.get_receiver().clone()))
+            .or_insert_with(|| AccountInfo {
+                nonce: 0,
+                balance: 0,
+            });
+        receiver_info.balance += self.block_reward;
+        self.issued += self.block_reward;
+        Ok(())
+    }
+
+    /// Apply a transaction outside of a block, its fee is burned
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<(), StateError> {
+        self.apply_transaction_paying_fee(tx, None)
+    }
+
+    /// Apply a transaction, crediting its fee to `fee_recipient` or burning it if there is none
+    pub fn apply_transaction_paying_fee(
+        &mut self,
+        tx: &SignedTransaction,
+        fee_recipient: Option<&AccountAddress>,
+    ) -> Result<(), StateError> {
+        // blocks and the mempool go through the same checks
+        self.check_transaction(tx)?;
 
         let sender_address = AccountAddress(tx.get_sender().clone());
         let receiver_address = AccountAddress(tx.get_receiver().clone());
         let value = tx.get_value() as u128;
-        let sender_nonce = tx.get_nonce();
+        let fee = tx.get_fee() as u128;
 
-        // Check for sufficient funds and correct nonce
-        if let Some(sender_info) = self.accounts.get(&sender_address) {
-            println!(
-                "balance: {}, value: {}, nonce: {}, sender_nonce: {}",
-                sender_info.balance, value, sender_info.nonce, sender_nonce
-            );
-            // if sender_info.balance < value || sender_info.nonce != sender_nonce {
-            if sender_info.balance < value {
-                return Err("Insufficient funds or incorrect nonce".to_string());
-            }
-        } else {
-            return Err("Sender account does not exist".to_string());
-        }
+        // Work on copies of the touched accounts and only write them back once every step has
+        // succeeded, so a failure leaves the state as it was
+        let mut staged: HashMap<AccountAddress, AccountInfo> = HashMap::new();
 
         // Update sender's balance and nonce
-        let sender_info = self.accounts.get_mut(&sender_address).unwrap();
-        sender_info.balance -= value;
+        let sender_info = self.staged_account(&mut staged, sender_address);
+        sender_info.balance -= value + fee;
         sender_info.nonce += 1;
 
         // Update receiver's balance
-        let receiver_info: &mut AccountInfo =
-            self.accounts
-                .entry(receiver_address)
-                .or_insert_with(|| AccountInfo {
-                    nonce: 0,
-                    balance: 0,
-                });
-        receiver_info.balance += value;
+        let receiver_info = self.staged_account(&mut staged, receiver_address.clone());
+        receiver_info.balance = receiver_info
+            .balance
+            .checked_add(value)
+            .ok_or(StateError::BalanceOverflow(receiver_address))?;
 
+        // Pay the fee to the miner
+        if let Some(fee_recipient) = fee_recipient {
+            let recipient_info = self.staged_account(&mut staged, fee_recipient.clone());
+            recipient_info.balance = recipient_info
+                .balance
+                .checked_add(fee)
+                .ok_or_else(|| StateError::BalanceOverflow(fee_recipient.clone()))?;
+        } else {
+            self.burned += fee;
+        }
+
+        // a receiver that didn't exist is only created once it holds something
+        let accounts = &self.accounts;
+        staged.retain(|address, info| info.balance > 0 || accounts.contains_key(address));
+        self.accounts.extend(staged);
         Ok(())
     }
 
+    /// Reject a transfer of less than the dust threshold, or of a negative value
+    fn check_dust(&self, tx: &SignedTransaction) -> Result<(), StateError> {
+        if tx.get_value() < 0 || (tx.get_value() as u128) < self.dust_threshold {
+            return Err(StateError::Dust {
+                value: tx.get_value(),
+                threshold: self.dust_threshold,
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the staged copy of an account, copying it from the state on first use
+    fn staged_account<'a>(
+        &self,
+        staged: &'a mut HashMap<AccountAddress, AccountInfo>,
+        address: AccountAddress,
+    ) -> &'a mut AccountInfo {
+        let current = self.accounts.get(&address).cloned();
+        staged.entry(address).or_insert_with(|| {
+            current.unwrap_or(AccountInfo {
+                nonce: 0,
+                balance: 0,
+            })
+        })
+    }
+
     // Function to check if a transaction is valid given the current state
     pub fn is_transaction_valid(&self, tx: &SignedTransaction) -> bool {
+        self.check_transaction(tx).is_ok()
+    }
+
+    /// Check that a transaction could be applied to this state, without applying it: it must be
+    /// well formed and signed by the key of its sender, which must have an account, the next
+    /// nonce and enough funds for the value and the fee. The value must be at least the dust
+    /// threshold.
+    pub fn check_transaction(&self, tx: &SignedTransaction) -> Result<(), StateError> {
+        if tx.is_coinbase() {
+            return Err(StateError::MisplacedCoinbase);
+        }
+        tx.check_structure().map_err(StateError::Malformed)?;
         // Verify the signature of the transaction
         if !tx.verify_signed_transaction() {
-            return false;
+            return Err(StateError::InvalidSignature);
+        }
+        if *tx.get_sender() != base64::encode(tx.public_key()) {
+            return Err(StateError::SenderMismatch);
         }
+        self.check_dust(tx)?;
 
         let sender_address = AccountAddress(tx.get_sender().clone());
-        let value = tx.get_value() as u128;
-        let sender_nonce = tx.get_nonce();
+        let needed = tx.get_value() as u128 + tx.get_fee() as u128;
+        let sender_info = self
+            .accounts
+            .get(&sender_address)
+            .ok_or_else(|| StateError::UnknownSender(sender_address.clone()))?;
+        if sender_info.nonce != tx.get_nonce() {
+            return Err(StateError::BadNonce {
+                expected: sender_info.nonce,
+                got: tx.get_nonce(),
+            });
+        }
+        if sender_info.balance < needed {
+            return Err(StateError::InsufficientFunds {
+                balance: sender_info.balance,
+                needed,
+            });
+        }
+        Ok(())
+    }
+}
 
-        if let Some(sender_info) = self.accounts.get(&sender_address) {
-            // Check for sufficient balance and correct nonce
-            sender_info.balance >= value && sender_info.nonce == sender_nonce
-        } else {
-            // Sender account does not exist
-            false
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hashable;
+    use crate::types::transaction::Transaction;
+
+    #[test]
+    fn addresses_are_public_keys() {
+        let address = key_pair::address(&key_pair::random());
+        assert_eq!(
+            AccountAddress::parse(&address),
+            Ok(AccountAddress::new(address.clone()))
+        );
+        assert!(AccountAddress::parse("not base64!").is_err());
+        assert!(AccountAddress::parse(&base64::encode([7u8; 20])).is_err());
+        assert!(AccountAddress::parse("").is_err());
+    }
+
+    #[test]
+    fn failed_transaction_leaves_accounts_untouched() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress::new(base64::encode(ico.public_key()));
+        let full = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let mut state = State::new();
+        state.add_account_with_balance(full.clone(), u128::MAX);
+        let before = state.clone();
+
+        // the sender can pay, but crediting the receiver overflows after the debit was staged
+        let tx = SignedTransaction::new(
+            Transaction::new(ico_address.to_string(), full.to_string(), 1, 0, 0),
+            &ico,
+        );
+        assert_eq!(
+            state.apply_transaction(&tx),
+            Err(StateError::BalanceOverflow(full.clone()))
+        );
+        for address in [&ico_address, &full] {
+            let (after, before) = (state.get_account(address), before.get_account(address));
+            assert_eq!(
+                after.map(|info| info.get_balance()),
+                before.map(|info| info.get_balance())
+            );
+            assert_eq!(
+                after.map(|info| info.get_nonce()),
+                before.map(|info| info.get_nonce())
+            );
+        }
+        assert_eq!(state.get_accounts().len(), before.get_accounts().len());
+    }
+
+    #[test]
+    fn state_root_commits_to_balances() {
+        let alice = AccountAddress::new("alice".to_string());
+        let bob = AccountAddress::new("bob".to_string());
+        let mut first = State::new();
+        first.add_account_with_balance(alice.clone(), 1);
+        first.add_account_with_balance(bob.clone(), 2);
+        let mut second = State::new();
+        second.add_account_with_balance(bob.clone(), 2);
+        second.add_account_with_balance(alice.clone(), 1);
+        assert_eq!(first.state_root(), second.state_root());
+
+        second.update_account(alice.clone(), 0, 3);
+        assert_ne!(first.state_root(), second.state_root());
+
+        // a single account is the only leaf, the hash of its encoded entry
+        let mut single = State::empty(&ConsensusParams::default());
+        single.add_account_with_balance(alice, 4);
+        let leaf = hash::digest(&bincode::serialize(&("alice", 0u64, 4u128)).unwrap());
+        assert_eq!(single.state_root(), leaf.hash());
+    }
+
+    #[test]
+    fn invalid_ico_key_is_an_error() {
+        let params = ConsensusParams::default();
+        let err = State::with_ico_key(&params, b"not a key").err().unwrap();
+        assert!(err.contains("ICO key"), "{}", err);
+        let state = State::with_ico_key(&params, key_pair::ICO_PKCS8).unwrap();
+        let ico = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        assert_eq!(state.get_balance(&ico), params.ico_balance);
+    }
+
+    #[test]
+    fn genesis_alloc_funds_listed_accounts() {
+        let dir = crate::persistence::test_data_dir("genesis_alloc");
+        let path = dir.root().join("genesis.json");
+        let mut expected: Vec<(String, u128)> = [100, 250, 1]
+            .iter()
+            .map(|balance| (key_pair::address(&key_pair::random()), *balance))
+            .collect();
+        let allocation: HashMap<&String, u128> = expected.iter().map(|(a, b)| (a, *b)).collect();
+        std::fs::write(&path, serde_json::to_string(&allocation).unwrap()).unwrap();
+
+        let state = State::from_genesis_alloc(&path, &ConsensusParams::default()).unwrap();
+        let mut accounts: Vec<(String, u128)> = state
+            .get_accounts()
+            .iter()
+            .map(|(address, info)| (address.to_string(), info.get_balance()))
+            .collect();
+        accounts.sort();
+        expected.sort();
+        assert_eq!(accounts, expected);
+
+        // a typo would lock the coins away for good
+        std::fs::write(&path, r#"{"alice": 100}"#).unwrap();
+        let e = State::from_genesis_alloc(&path, &ConsensusParams::default()).unwrap_err();
+        assert!(e.contains("invalid address alice"), "{}", e);
+        let (a, b) = (&expected[0].0, &expected[1].0);
+        let overflowing = format!(r#"{{"{}": {}, "{}": 1}}"#, a, u128::MAX, b);
+        std::fs::write(&path, overflowing).unwrap();
+        assert!(State::from_genesis_alloc(&path, &ConsensusParams::default()).is_err());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(State::from_genesis_alloc(&path, &ConsensusParams::default()).is_err());
+    }
+
+    #[test]
+    fn fees_move_from_sender_to_coinbase_receiver() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress(base64::encode(ico.public_key()));
+        let miner = AccountAddress(base64::encode(key_pair::random().public_key()));
+        let alice = AccountAddress(base64::encode(key_pair::random().public_key()));
+        let mut state = State::new();
+        let before = state.get_balance(&ico_address);
+
+        let transactions = vec![
+            SignedTransaction::coinbase(miner.to_string(), BLOCK_REWARD as i64, 1),
+            SignedTransaction::new(
+                Transaction::new(ico_address.to_string(), alice.to_string(), 5, 3, 0),
+                &ico,
+            ),
+        ];
+        assert!(state.apply_block(&transactions).is_ok());
+        assert_eq!(state.get_balance(&ico_address), before - 8);
+        assert_eq!(state.get_balance(&alice), 5);
+        assert_eq!(state.get_balance(&miner), BLOCK_REWARD + 3);
+    }
+
+    #[test]
+    fn failures_report_their_cause() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress::new(base64::encode(ico.public_key()));
+        let stranger = key_pair::random();
+        let stranger_address = AccountAddress::new(base64::encode(stranger.public_key()));
+        let mut state = State::new();
+        let balance = state.get_balance(&ico_address);
+        let payment = |value: i64, nonce: u64| {
+            SignedTransaction::new(
+                Transaction::new(ico_address.to_string(), "bob".to_string(), value, 0, nonce),
+                &ico,
+            )
+        };
+
+        assert_eq!(
+            state.apply_transaction(&payment(1, 1)),
+            Err(StateError::BadNonce {
+                expected: 0,
+                got: 1
+            })
+        );
+        assert_eq!(
+            state.apply_transaction(&payment(balance as i64 + 1, 0)),
+            Err(StateError::InsufficientFunds {
+                balance,
+                needed: balance + 1
+            })
+        );
+        let unknown = SignedTransaction::new(
+            Transaction::new(stranger_address.to_string(), "bob".to_string(), 1, 0, 0),
+            &stranger,
+        );
+        assert_eq!(
+            state.apply_transaction(&unknown),
+            Err(StateError::UnknownSender(stranger_address))
+        );
+        let mut bytes = bincode::serialize(&payment(1, 0)).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let forged: SignedTransaction = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(
+            state.apply_transaction(&forged),
+            Err(StateError::InvalidSignature)
+        );
+
+        let coinbase = SignedTransaction::coinbase("miner".to_string(), BLOCK_REWARD as i64, 1);
+        assert_eq!(
+            state.apply_block_transaction(1, &coinbase, None),
+            Err(StateError::MisplacedCoinbase)
+        );
+        let greedy = SignedTransaction::coinbase("miner".to_string(), 1000, 1);
+        assert_eq!(
+            state.apply_coinbase(&greedy),
+            Err(StateError::WrongReward(1000))
+        );
+        assert_eq!(state.apply_transaction(&payment(1, 0)), Ok(()));
+    }
+
+    #[test]
+    fn supply_follows_issuance() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress::new(base64::encode(ico.public_key()));
+        let miner = AccountAddress::new(base64::encode(key_pair::random().public_key()));
+        let mut state = State::new();
+        let initial = state.total_supply();
+        assert_eq!(initial, crate::config::DEFAULT_ICO_BALANCE);
+        let payment = |value: i64, fee: u64, nonce: u64| {
+            SignedTransaction::new(
+                Transaction::new(
+                    ico_address.to_string(),
+                    "bob".to_string(),
+                    value,
+                    fee,
+                    nonce,
+                ),
+                &ico,
+            )
+        };
+
+        // transfers, and failed ones, move coins around without changing the supply
+        for nonce in 0..3 {
+            state.apply_transaction(&payment(7, 0, nonce)).unwrap();
+        }
+        assert!(state.apply_transaction(&payment(-5, 0, 3)).is_err());
+        assert!(state.apply_transaction(&payment(7, 0, 0)).is_err());
+        assert_eq!(state.total_supply(), initial);
+        assert!(state.supply_is_conserved());
+
+        // each block adds its reward, fees paid to the miner stay in circulation
+        for height in 1..=3u64 {
+            let block = vec![
+                SignedTransaction::coinbase(miner.to_string(), BLOCK_REWARD as i64, height),
+                payment(1, 2, height + 2),
+            ];
+            assert!(state.apply_block(&block).is_ok());
+            assert_eq!(
+                state.total_supply(),
+                initial + height as u128 * BLOCK_REWARD
+            );
         }
+        assert_eq!(state.issued(), 3 * BLOCK_REWARD);
+
+        // a fee without a recipient is burned
+        state.apply_transaction(&payment(1, 4, 6)).unwrap();
+        assert_eq!(state.burned(), 4);
+        assert_eq!(state.total_supply(), initial + 3 * BLOCK_REWARD - 4);
+        assert!(state.supply_is_conserved());
+
+        // a balance changed behind the ledger's back is caught
+        let mut state = State::new();
+        state.accounts.get_mut(&ico_address).unwrap().balance += 1;
+        assert!(!state.supply_is_conserved());
+    }
+
+    #[test]
+    fn coinbase_only_accepted_with_marker_in_first_position() {
+        let miner = AccountAddress::new(key_pair::address(&key_pair::random()));
+        let mut state = State::new();
+        let coinbase = SignedTransaction::coinbase(miner.to_string(), BLOCK_REWARD as i64, 1);
+        assert!(state.apply_block_transaction(1, &coinbase, None).is_err());
+        assert!(state.apply_block_transaction(0, &coinbase, None).is_ok());
+
+        let unmarked = SignedTransaction::new(
+            Transaction::new(
+                "Sender".to_string(),
+                miner.to_string(),
+                BLOCK_REWARD as i64,
+                0,
+                2,
+            ),
+            &key_pair::random(),
+        );
+        assert!(state.apply_block_transaction(0, &unmarked, None).is_err());
+        assert_eq!(state.get_balance(&miner), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn forged_sender_not_applied_in_a_block() {
+        let ico_address = AccountAddress::new(key_pair::address(&key_pair::ico()));
+        let forger = key_pair::random();
+        let thief = AccountAddress::new(key_pair::address(&forger));
+        let forged = SignedTransaction::new(
+            Transaction::new(ico_address.to_string(), thief.to_string(), 5, 0, 0),
+            &forger,
+        );
+        let mut state = State::new();
+        let before = state.get_balance(&ico_address);
+        assert_eq!(
+            state.check_transaction(&forged),
+            Err(StateError::SenderMismatch)
+        );
+        let coinbase = SignedTransaction::coinbase(thief.to_string(), BLOCK_REWARD as i64, 1);
+        // the whole block is rejected, its coinbase included
+        assert_eq!(
+            state.apply_block(&[coinbase, forged]),
+            Err((1, StateError::SenderMismatch))
+        );
+        assert_eq!(state.get_balance(&ico_address), before);
+        assert_eq!(state.get_balance(&thief), 0);
+    }
+
+    #[test]
+    fn dust_transfers_rejected() {
+        let ico = key_pair::ico();
+        let ico_address = AccountAddress::new(key_pair::address(&ico));
+        let bob = AccountAddress::new(key_pair::address(&key_pair::random()));
+        let params = ConsensusParams {
+            dust_threshold: 10,
+            ..ConsensusParams::default()
+        };
+        let mut state = State::with_params(&params).unwrap();
+        let transfer = |value: i64| {
+            SignedTransaction::new(
+                Transaction::new(ico_address.to_string(), bob.to_string(), value, 0, 0),
+                &ico,
+            )
+        };
+
+        let dust = transfer(9);
+        let expected = StateError::Dust {
+            value: 9,
+            threshold: 10,
+        };
+        assert_eq!(state.check_transaction(&dust), Err(expected.clone()));
+        assert_eq!(state.apply_transaction(&dust), Err(expected));
+        assert!(state.get_account(&bob).is_none());
+
+        // a transfer at the threshold creates the receiver's account
+        state.apply_transaction(&transfer(10)).unwrap();
+        let bob_info = state.get_account(&bob).unwrap();
+        assert_eq!(bob_info.get_balance(), 10);
+        assert_eq!(bob_info.get_nonce(), 0);
+
+        // even without a threshold an empty transfer is malformed
+        let carol = AccountAddress::new(key_pair::address(&key_pair::random()));
+        let mut state = State::with_params(&ConsensusParams {
+            dust_threshold: 0,
+            ..ConsensusParams::default()
+        })
+        .unwrap();
+        let empty = SignedTransaction::new(
+            Transaction::new(ico_address.to_string(), carol.to_string(), 0, 0, 0),
+            &ico,
+        );
+        assert!(matches!(
+            state.apply_transaction(&empty),
+            Err(StateError::Malformed(_))
+        ));
+        assert!(state.get_account(&carol).is_none());
+        assert_eq!(state.get_account(&ico_address).unwrap().get_nonce(), 0);
     }
 }
@@ -0,0 +1,150 @@
+use crate::persistence::{read_if_exists, write_atomic};
+use crate::types::key_pair;
+use crate::types::state::AccountAddress;
+use crate::types::transaction::{SignedTransaction, Transaction};
+use ring::signature::Ed25519KeyPair;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// A key held by the wallet. The PKCS8 document is kept so the key can be written back out.
+struct Key {
+    pkcs8: Vec<u8>,
+    key_pair: Ed25519KeyPair,
+}
+
+/// A set of Ed25519 key pairs, indexed by the account address they control. The keystore file
+/// holds the PKCS8 documents unencrypted, so it must be kept private.
+#[derive(Default)]
+pub struct Wallet {
+    keys: HashMap<AccountAddress, Key>,
+}
+
+impl Wallet {
+    /// Create an empty wallet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate a new key pair and return the address it controls
+    pub fn generate_address(&mut self) -> AccountAddress {
+        self.add_key(&key_pair::generate_pkcs8())
+            .expect("a freshly generated key is valid")
+    }
+
+    /// Add a key given as a PKCS8 document, returning the address it controls
+    pub fn add_key(&mut self, pkcs8: &[u8]) -> Result<AccountAddress, String> {
+        let key_pair = key_pair::from_pkcs8(pkcs8)?;
+        let address = AccountAddress::new(key_pair::address(&key_pair));
+        self.keys.insert(
+            address.clone(),
+            Key {
+                pkcs8: pkcs8.to_vec(),
+                key_pair,
+            },
+        );
+        Ok(address)
+    }
+
+    /// Get the addresses this wallet holds keys for, in ascending order
+    pub fn addresses(&self) -> Vec<AccountAddress> {
+        let mut addresses: Vec<AccountAddress> = self.keys.keys().cloned().collect();
+        addresses.sort_by_key(|address| address.to_string());
+        addresses
+    }
+
+    /// Check if the wallet holds the key of an address
+    pub fn contains(&self, address: &AccountAddress) -> bool {
+        self.keys.contains_key(address)
+    }
+
+    /// Sign a transaction with the key of its sender
+    pub fn sign(&self, transaction: Transaction) -> Result<SignedTransaction, String> {
+        let sender = AccountAddress::new(transaction.get_sender().clone());
+        match self.keys.get(&sender) {
+            Some(key) => Ok(SignedTransaction::new(transaction, &key.key_pair)),
+            None => Err(format!("No key for sender {}", sender)),
+        }
+    }
+
+    /// Save every key to a keystore file
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let keys: Vec<&Vec<u8>> = self
+            .addresses()
+            .iter()
+            .map(|address| &self.keys[address].pkcs8)
+            .collect();
+        let bytes =
+            bincode::serialize(&keys).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(path, &bytes)
+    }
+
+    /// Load a wallet from a keystore written by `save_to_path`, or an empty one if there is none
+    pub fn load_from_path(path: &Path) -> io::Result<Self> {
+        let mut wallet = Self::new();
+        let bytes = match read_if_exists(path)? {
+            Some(bytes) => bytes,
+            None => return Ok(wallet),
+        };
+        let keys: Vec<Vec<u8>> = bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for pkcs8 in keys {
+            wallet
+                .add_key(&pkcs8)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(wallet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::test_data_dir;
+    use crate::types::transaction::verify;
+
+    #[test]
+    fn holds_several_keys() {
+        let mut wallet = Wallet::new();
+        let first = wallet.generate_address();
+        let second = wallet.generate_address();
+        assert_ne!(first, second);
+        assert!(wallet.contains(&first) && wallet.contains(&second));
+        assert_eq!(wallet.addresses().len(), 2);
+    }
+
+    #[test]
+    fn signs_with_the_sender_key() {
+        let mut wallet = Wallet::new();
+        let alice = wallet.generate_address();
+        let bob = wallet.generate_address();
+        let transaction = Transaction::new(alice.to_string(), bob.to_string(), 5, 0, 0);
+
+        let signed = wallet.sign(transaction.clone()).unwrap();
+        let alice_key = base64::decode(alice.to_string()).unwrap();
+        let bob_key = base64::decode(bob.to_string()).unwrap();
+        assert_eq!(signed.public_key(), &alice_key);
+        assert!(verify(&transaction, &alice_key, signed.signature()));
+        assert!(!verify(&transaction, &bob_key, signed.signature()));
+
+        let stranger = Transaction::new("stranger".to_string(), bob.to_string(), 5, 0, 0);
+        assert!(wallet.sign(stranger).is_err());
+    }
+
+    #[test]
+    fn keystore_round_trip() {
+        let dir = test_data_dir("wallet");
+        let path = dir.root().join("wallet.bin");
+        let mut wallet = Wallet::new();
+        wallet.generate_address();
+        wallet.generate_address();
+        wallet.save_to_path(&path).unwrap();
+
+        let loaded = Wallet::load_from_path(&path).unwrap();
+        assert_eq!(loaded.addresses(), wallet.addresses());
+        assert!(Wallet::load_from_path(&dir.root().join("missing.bin"))
+            .unwrap()
+            .addresses()
+            .is_empty());
+    }
+}